@@ -19,10 +19,17 @@ cfg_if! {
                 .whitelist_type("mach_header.*")
                 .whitelist_type("load_command.*")
                 .whitelist_type("uuid_command.*")
+                .whitelist_type("dylib_command.*")
                 .whitelist_type("segment_command.*")
+                .whitelist_type("dyld_info_command.*")
+                .whitelist_type("section.*")
+                .whitelist_type("symtab_command")
                 .whitelist_var("MH_MAGIC.*")
                 .whitelist_var("LC_SEGMENT.*")
                 .whitelist_var("LC_UUID.*")
+                .whitelist_var("LC_ID_DYLIB")
+                .whitelist_var("LC_DYLD_INFO.*")
+                .whitelist_var("LC_SYMTAB")
                 .generate()
                 .expect("Should generate macOS FFI bindings OK");
 
@@ -6,7 +6,10 @@ set -eux
 export RUST_BACKTRACE=1
 
 cargo build --examples $PROFILE
-cargo test $PROFILE
+cargo test $PROFILE --all
+cargo test $PROFILE --features rayon
+cargo test $PROFILE --features capi
+cargo test $PROFILE --features tracing
 
 if [[ "$PROFILE" == "--release" ]]; then
     cargo bench
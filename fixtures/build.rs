@@ -0,0 +1,165 @@
+//! Build the fixture shared libraries with the C compiler, and describe them
+//! to `src/lib.rs` in `$OUT_DIR/fixtures.rs`.
+//!
+//! Fixtures can only be loaded into the process they were built for, so
+//! nothing is built when cross compiling, or for platforms `findshlibs`
+//! doesn't support, and `BUILT` is false.
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How to build one fixture.
+struct Spec {
+    /// The name of the constant describing the fixture.
+    constant: &'static str,
+    /// The C source file in `c/`.
+    source: &'static str,
+    /// The subdirectory of `$OUT_DIR` to put the shared library in.
+    dir: String,
+    /// The shared library's file name, without the platform's suffix.
+    stem: &'static str,
+    /// The GNU build ID to give the shared library on Linux.
+    build_id: &'static [u8],
+    /// The version to give the shared library, in its soname on Linux or as
+    /// its current version on macOS.
+    version: Option<(u32, u32, u32)>,
+    /// The name of a section only this fixture has.
+    odd_section: Option<&'static str>,
+}
+
+fn specs(target_os: &str) -> Vec<Spec> {
+    // Long enough to overflow Windows' `MAX_PATH` and most fixed-size
+    // buffers once `$OUT_DIR` is prepended.
+    let long_dir = (0..12).map(|i| format!("long-directory-name-{:02}", i)).collect::<Vec<_>>();
+
+    vec![
+        Spec {
+            constant: "BASIC",
+            source: "basic.c",
+            dir: "basic".into(),
+            stem: "libfindshlibs_basic",
+            build_id: &[
+                0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89,
+                0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67,
+            ],
+            version: Some((1, 2, 3)),
+            odd_section: None,
+        },
+        Spec {
+            constant: "ODD_SECTIONS",
+            source: "odd_sections.c",
+            dir: "odd_sections".into(),
+            stem: "libfindshlibs_odd_sections",
+            build_id: &[0xfe, 0xed, 0xfa, 0xce, 0xca, 0xfe, 0xbe, 0xef, 0x00, 0x11, 0x22, 0x33],
+            version: None,
+            odd_section: Some(if target_os == "macos" {
+                "__fsl_odd"
+            } else {
+                ".findshlibs.odd-name"
+            }),
+        },
+        Spec {
+            constant: "LONG_PATH",
+            source: "basic.c",
+            dir: long_dir.join("/"),
+            stem: "libfindshlibs_long_path",
+            build_id: &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
+            version: None,
+            odd_section: None,
+        },
+        Spec {
+            constant: "NON_ASCII",
+            source: "basic.c",
+            dir: "n\u{f6}n-\u{e4}scii".into(),
+            stem: "libfindshlibs_\u{fc}n\u{ef}c\u{f6}d\u{e9}",
+            build_id: &[
+                0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab, 0xac,
+                0xad, 0xae, 0xaf,
+            ],
+            version: None,
+            odd_section: None,
+        },
+    ]
+}
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=c");
+    println!("cargo:rerun-if-env-changed=CC");
+
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+    let native = env::var("TARGET").ok() == env::var("HOST").ok();
+    let built = native && (target_os == "linux" || target_os == "macos");
+
+    let mut out = String::new();
+    writeln!(out, "/// Whether the fixtures were built for this platform.").unwrap();
+    writeln!(out, "pub const BUILT: bool = {};", built).unwrap();
+
+    let specs = specs(&target_os);
+    for spec in &specs {
+        let path = if built {
+            build(spec, &target_os, &manifest_dir, &out_dir)
+        } else {
+            PathBuf::new()
+        };
+        writeln!(out, "/// The `{}` fixture.", spec.constant.to_lowercase()).unwrap();
+        writeln!(
+            out,
+            "pub const {}: Fixture = Fixture {{ path: {:?}, build_id: &{:?}, version: {:?}, \
+             compilation_directory: {:?}, odd_section: {:?} }};",
+            spec.constant,
+            path.to_str().expect("fixture paths should be UTF-8"),
+            spec.build_id,
+            spec.version,
+            out_dir.to_str().expect("OUT_DIR should be UTF-8"),
+            spec.odd_section
+        )
+        .unwrap();
+    }
+    let constants: Vec<_> = specs.iter().map(|spec| spec.constant).collect();
+    writeln!(out, "/// Every fixture.").unwrap();
+    writeln!(out, "pub const ALL: &[Fixture] = &[{}];", constants.join(", ")).unwrap();
+
+    fs::write(out_dir.join("fixtures.rs"), out).unwrap();
+}
+
+/// Build the fixture described by `spec` and return its path.
+fn build(spec: &Spec, target_os: &str, manifest_dir: &Path, out_dir: &Path) -> PathBuf {
+    let dir = out_dir.join(&spec.dir);
+    fs::create_dir_all(&dir).unwrap();
+    let suffix = if target_os == "macos" { "dylib" } else { "so" };
+    let path = dir.join(format!("{}.{}", spec.stem, suffix));
+
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".into());
+    let mut command = Command::new(compiler);
+    // The compiler records its working directory as `DW_AT_comp_dir`.
+    command.current_dir(out_dir).arg("-g").arg("-O1").arg("-fPIC");
+    command.arg("-o").arg(&path).arg(manifest_dir.join("c").join(spec.source));
+
+    if target_os == "macos" {
+        command.arg("-dynamiclib");
+        command.arg(format!("-Wl,-install_name,{}", path.display()));
+        if let Some((major, minor, patch)) = spec.version {
+            command.arg(format!("-Wl,-current_version,{}.{}.{}", major, minor, patch));
+        }
+    } else {
+        command.arg("-shared");
+        let build_id: String = spec.build_id.iter().map(|byte| format!("{:02x}", byte)).collect();
+        command.arg(format!("-Wl,--build-id=0x{}", build_id));
+        if let Some((major, minor, patch)) = spec.version {
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            command.arg(format!("-Wl,-soname,{}.{}.{}.{}", file_name, major, minor, patch));
+        }
+    }
+
+    let status = command
+        .status()
+        .unwrap_or_else(|err| panic!("failed to run the C compiler: {}", err));
+    assert!(status.success(), "failed to build the {} fixture: {:?}", spec.constant, command);
+    path
+}
@@ -0,0 +1,5 @@
+/* A shared library with one exported function and nothing else of note. */
+
+int findshlibs_fixture_answer(void) {
+    return 42;
+}
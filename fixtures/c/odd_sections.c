@@ -0,0 +1,14 @@
+/* A shared library with data in sections whose names no toolchain uses. */
+
+#ifdef __APPLE__
+#define ODD_SECTION "__DATA,__fsl_odd"
+#else
+#define ODD_SECTION ".findshlibs.odd-name"
+#endif
+
+__attribute__((used, section(ODD_SECTION)))
+const char findshlibs_fixture_odd_data[] = "findshlibs odd section";
+
+int findshlibs_fixture_answer(void) {
+    return findshlibs_fixture_odd_data[0];
+}
@@ -0,0 +1,123 @@
+//! Tiny shared libraries with known properties, for testing `findshlibs`
+//! against more than the host's system libraries.
+//!
+//! The build script compiles each fixture from the C sources in `c/` into
+//! `$OUT_DIR`, with a known build ID and version where the platform allows,
+//! and in places that exercise unusual paths: a very long one, and one that
+//! isn't ASCII. Each [`Fixture`](./struct.Fixture.html) records what
+//! `findshlibs` should report for it.
+//!
+//! ```
+//! extern crate findshlibs_fixtures as fixtures;
+//!
+//! if fixtures::BUILT {
+//!     let loaded = fixtures::BASIC.load();
+//!     assert_eq!(loaded.answer(), 42);
+//! }
+//! ```
+
+extern crate libc;
+
+use std::ffi::CString;
+use std::path::Path;
+
+include!(concat!(env!("OUT_DIR"), "/fixtures.rs"));
+
+/// The name of the function every fixture exports.
+pub const EXPORTED_FUNCTION: &str = "findshlibs_fixture_answer";
+
+/// A shared library built for testing, and what it should look like once
+/// loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fixture {
+    /// The absolute path of the shared library, which it is loaded from.
+    pub path: &'static str,
+    /// The GNU build ID it was linked with. This is only used on Linux.
+    pub build_id: &'static [u8],
+    /// Its major, minor and patch version, if it has one.
+    pub version: Option<(u32, u32, u32)>,
+    /// The directory it was compiled in.
+    pub compilation_directory: &'static str,
+    /// The name of a section only this fixture has.
+    pub odd_section: Option<&'static str>,
+}
+
+impl Fixture {
+    /// Get the path of the shared library.
+    #[inline]
+    pub fn path(&self) -> &'static Path {
+        Path::new(self.path)
+    }
+
+    /// Load the shared library with `dlopen`, panicking if that fails.
+    pub fn load(&self) -> Loaded {
+        if !BUILT {
+            panic!("fixtures aren't built for this platform");
+        }
+        let path = CString::new(self.path).unwrap();
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        assert!(!handle.is_null(), "failed to load {}", self.path);
+        Loaded {
+            fixture: *self,
+            handle,
+        }
+    }
+}
+
+/// A loaded fixture, which is unloaded with `dlclose` when dropped.
+#[derive(Debug)]
+pub struct Loaded {
+    fixture: Fixture,
+    handle: *mut libc::c_void,
+}
+
+impl Loaded {
+    /// Get the fixture that was loaded.
+    #[inline]
+    pub fn fixture(&self) -> &Fixture {
+        &self.fixture
+    }
+
+    /// Get the address of the function this fixture exports.
+    pub fn exported_function(&self) -> *const u8 {
+        let name = CString::new(EXPORTED_FUNCTION).unwrap();
+        let address = unsafe { libc::dlsym(self.handle, name.as_ptr()) };
+        assert!(!address.is_null(), "{} doesn't export {}", self.fixture.path, EXPORTED_FUNCTION);
+        address as *const u8
+    }
+
+    /// Call the function this fixture exports.
+    pub fn answer(&self) -> i32 {
+        let function: extern "C" fn() -> i32 =
+            unsafe { ::std::mem::transmute(self.exported_function()) };
+        function()
+    }
+}
+
+impl Drop for Loaded {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixtures_load() {
+        if !BUILT {
+            return;
+        }
+        for fixture in ALL {
+            assert!(fixture.path().is_absolute());
+            let loaded = fixture.load();
+            let answer = loaded.answer();
+            assert!(answer == 42 || fixture.odd_section.is_some());
+        }
+        assert!(LONG_PATH.path.len() > 260);
+        assert!(!NON_ASCII.path.is_ascii());
+    }
+}
@@ -0,0 +1,57 @@
+/*
+ * C API for findshlibs, available when the crate is built with the `capi`
+ * feature. Keep in sync with src/capi.rs.
+ */
+
+#ifndef FINDSHLIBS_H
+#define FINDSHLIBS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The shared library has no ID. */
+#define FINDSHLIBS_ID_NONE 0
+/* The shared library's ID is a 16-byte Mach-O UUID. */
+#define FINDSHLIBS_ID_UUID 1
+/* The shared library's ID is a GNU build ID of any length. */
+#define FINDSHLIBS_ID_GNU_BUILD_ID 2
+
+/*
+ * A flattened view of a shared library. All pointers are only valid for the
+ * duration of the callback.
+ */
+typedef struct FindshlibsSharedLibrary {
+  /* The nul-terminated name of the shared library, as reported by the loader. */
+  const char *name;
+  /* The lowest actual virtual memory address of any of its segments. */
+  uintptr_t base;
+  /* The number of bytes from `base` to the end of its last segment. */
+  uintptr_t size;
+  /* The shared library's virtual memory bias. */
+  intptr_t bias;
+  /* One of the FINDSHLIBS_ID_* constants. */
+  uint32_t id_kind;
+  /* The ID's raw bytes, or NULL if `id_kind` is FINDSHLIBS_ID_NONE. */
+  const uint8_t *id;
+  /* The number of bytes pointed to by `id`. */
+  uintptr_t id_len;
+} FindshlibsSharedLibrary;
+
+/* Return zero to continue iterating, or non-zero to stop. */
+typedef int (*FindshlibsCallback)(const FindshlibsSharedLibrary *shlib, void *data);
+
+/*
+ * Invoke `callback` with each shared library loaded in this process, passing
+ * `data` through untouched.
+ */
+void findshlibs_each(FindshlibsCallback callback, void *data);
+
+#ifdef __cplusplus
+} /* extern "C" */
+#endif
+
+#endif /* FINDSHLIBS_H */
@@ -0,0 +1,142 @@
+//! A C API for enumerating shared libraries.
+//!
+//! This module is only available with the `capi` feature enabled. It exports
+//! `extern "C"` functions described by the `include/findshlibs.h` header, so
+//! that C and C++ code can reuse this crate's per-OS enumeration. To get a
+//! library that C code can link against, build this crate as a static
+//! library, for example:
+//!
+//! ```text
+//! cargo rustc --release --features capi --crate-type staticlib
+//! ```
+
+use super::{Segment, SharedLibrary, SharedLibraryId, TargetSharedLibrary};
+
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+/// The shared library has no ID.
+pub const FINDSHLIBS_ID_NONE: u32 = 0;
+/// The shared library's ID is a 16-byte Mach-O UUID.
+pub const FINDSHLIBS_ID_UUID: u32 = 1;
+/// The shared library's ID is a GNU build ID of any length.
+pub const FINDSHLIBS_ID_GNU_BUILD_ID: u32 = 2;
+
+/// A flattened view of a shared library, as handed to a
+/// [`FindshlibsCallback`](./type.FindshlibsCallback.html).
+///
+/// All pointers are only valid for the duration of the callback.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FindshlibsSharedLibrary {
+    /// The nul-terminated name of the shared library, as reported by the
+    /// loader.
+    pub name: *const c_char,
+    /// The lowest actual virtual memory address of any of its segments.
+    pub base: usize,
+    /// The number of bytes from `base` to the end of its last segment.
+    pub size: usize,
+    /// The shared library's virtual memory bias.
+    pub bias: isize,
+    /// One of the `FINDSHLIBS_ID_*` constants.
+    pub id_kind: u32,
+    /// The ID's raw bytes, or null if `id_kind` is `FINDSHLIBS_ID_NONE`.
+    pub id: *const u8,
+    /// The number of bytes pointed to by `id`.
+    pub id_len: usize,
+}
+
+/// The callback invoked by `findshlibs_each` for each shared library.
+///
+/// Return zero to continue iterating, or non-zero to stop.
+pub type FindshlibsCallback =
+    unsafe extern "C" fn(shlib: *const FindshlibsSharedLibrary, data: *mut c_void) -> c_int;
+
+/// Invoke `callback` with each shared library loaded in this process, passing
+/// `data` through untouched.
+///
+/// # Safety
+///
+/// `callback` must be safe to call with the given `data`, and must not retain
+/// any of the pointers in the `FindshlibsSharedLibrary` after it returns.
+#[no_mangle]
+pub unsafe extern "C" fn findshlibs_each(callback: Option<FindshlibsCallback>, data: *mut c_void) {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return,
+    };
+
+    TargetSharedLibrary::each(|shlib| {
+        let (base, end) = shlib
+            .segments()
+            .filter(|seg| seg.len() > 0)
+            .map(|seg| {
+                let start = seg.actual_virtual_memory_address(shlib).0 as usize;
+                (start, start.saturating_add(seg.len()))
+            })
+            .fold((usize::MAX, 0), |(lo, hi), (start, end)| (lo.min(start), hi.max(end)));
+        let (base, size) = if base <= end { (base, end - base) } else { (0, 0) };
+
+        let id = shlib.id();
+        let (id_kind, id_bytes) = match id {
+            Some(SharedLibraryId::Uuid(ref bytes)) => (FINDSHLIBS_ID_UUID, &bytes[..]),
+            Some(SharedLibraryId::GnuBuildId(ref bytes)) => (FINDSHLIBS_ID_GNU_BUILD_ID, &bytes[..]),
+            None => (FINDSHLIBS_ID_NONE, &[][..]),
+        };
+
+        let flat = FindshlibsSharedLibrary {
+            name: shlib.name().as_ptr(),
+            base,
+            size,
+            bias: shlib.virtual_memory_bias().0,
+            id_kind,
+            id: if id_bytes.is_empty() { ptr::null() } else { id_bytes.as_ptr() },
+            id_len: id_bytes.len(),
+        };
+
+        if callback(&flat, data) == 0 {
+            ::IterationControl::Continue
+        } else {
+            ::IterationControl::Break
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    unsafe extern "C" fn count(shlib: *const FindshlibsSharedLibrary, data: *mut c_void) -> c_int {
+        let shlib = &*shlib;
+        assert!(!shlib.name.is_null());
+        let _ = CStr::from_ptr(shlib.name);
+        assert_eq!(shlib.id.is_null(), shlib.id_kind == FINDSHLIBS_ID_NONE);
+
+        *(data as *mut usize) += 1;
+        0
+    }
+
+    unsafe extern "C" fn stop(_shlib: *const FindshlibsSharedLibrary, data: *mut c_void) -> c_int {
+        *(data as *mut usize) += 1;
+        1
+    }
+
+    #[test]
+    fn each_counts_libraries() {
+        let mut n = 0usize;
+        unsafe {
+            findshlibs_each(Some(count), &mut n as *mut usize as *mut c_void);
+        }
+        assert_eq!(n > 0, ::TARGET_SUPPORTED);
+    }
+
+    #[test]
+    fn each_can_stop() {
+        let mut n = 0usize;
+        unsafe {
+            findshlibs_each(Some(stop), &mut n as *mut usize as *mut c_void);
+        }
+        assert_eq!(n, ::TARGET_SUPPORTED as usize);
+    }
+}
@@ -0,0 +1,247 @@
+//! Object-safe views of shared libraries and segments.
+//!
+//! `SharedLibrary` and `Segment` have associated types and generic methods,
+//! so they can't be used as trait objects, and code handling them has to name
+//! the platform's concrete types or be generic itself. The
+//! [`DynSharedLibrary`](./trait.DynSharedLibrary.html) and
+//! [`DynSegment`](./trait.DynSegment.html) traits offer a simplified,
+//! non-generic interface instead, implemented for every shared library and
+//! segment, which plugins and FFI layers can pass around as `&dyn
+//! DynSharedLibrary` or `Box<dyn DynSegment>`.
+//!
+//! Both traits share method names with their generic counterparts, so avoid
+//! importing `SharedLibrary` and `DynSharedLibrary` into the same scope.
+//!
+//! ```
+//! use findshlibs::dynamic::{self, DynSharedLibrary};
+//!
+//! fn describe(shlib: &dyn DynSharedLibrary) -> String {
+//!     format!("{} ({} segments)", shlib.name().to_string_lossy(), shlib.segments().len())
+//! }
+//!
+//! dynamic::each(|shlib| println!("{}", describe(shlib)));
+//! ```
+
+use super::snapshot::ModuleInfo;
+use super::{
+    Avma, Bias, DebugInfoAvailability, IterationControl, Segment, SegmentPermissions,
+    SharedLibrary, SharedLibraryId, SharedLibraryVersion, Svma, Symbol, TargetSharedLibrary,
+};
+
+use std::ffi::CStr;
+use std::fmt::Debug;
+
+/// An object-safe view of a segment of a shared library.
+///
+/// Unlike `Segment`, this already knows which shared library it belongs to,
+/// so it can tell its actual virtual memory address by itself.
+#[allow(clippy::len_without_is_empty)]
+pub trait DynSegment: Debug {
+    /// Get this segment's name.
+    fn name(&self) -> &CStr;
+
+    /// Get this segment's stated virtual memory address.
+    fn stated_virtual_memory_address(&self) -> Svma;
+
+    /// Get this segment's actual virtual memory address.
+    fn actual_virtual_memory_address(&self) -> Avma;
+
+    /// Get the length of this segment in memory, in bytes.
+    fn len(&self) -> usize;
+
+    /// Get the offset of this segment's data in the file it was loaded from.
+    fn file_offset(&self) -> u64;
+
+    /// Get the number of bytes of this segment that are backed by the file.
+    fn file_size(&self) -> u64;
+
+    /// Get the memory protection this segment was initially mapped with.
+    fn permissions(&self) -> SegmentPermissions;
+}
+
+/// An object-safe view of a shared library loaded in this process.
+///
+/// Each method does the same as the `SharedLibrary` method of the same name.
+pub trait DynSharedLibrary: Debug {
+    /// Get the name of this shared library.
+    fn name(&self) -> &CStr;
+
+    /// Get the debug-id of this shared library, if any.
+    fn id(&self) -> Option<SharedLibraryId>;
+
+    /// Get the version of this shared library, if known.
+    fn version(&self) -> Option<SharedLibraryVersion>;
+
+    /// Get this shared library's segments.
+    fn segments(&self) -> Vec<Box<dyn DynSegment + '_>>;
+
+    /// Find out where this shared library's debug information can be found.
+    fn debug_info_availability(&self) -> DebugInfoAvailability;
+
+    /// Get the number of bytes of address space this shared library occupies.
+    fn virtual_memory_size(&self) -> usize;
+
+    /// Get the bias of this shared library.
+    fn virtual_memory_bias(&self) -> Bias;
+
+    /// Get the headers of this shared library, if they are mapped.
+    fn header_bytes(&self) -> Option<&[u8]>;
+
+    /// Find the nearest dynamic symbol at or before the given address.
+    fn symbol_at(&self, address: Avma) -> Option<Symbol<'_>>;
+
+    /// Copy the information about this shared library.
+    fn to_module_info(&self) -> ModuleInfo;
+}
+
+/// A segment together with the bias of its shared library.
+#[derive(Debug)]
+struct BoundSegment<G> {
+    segment: G,
+    bias: Bias,
+}
+
+impl<G: Segment> DynSegment for BoundSegment<G> {
+    #[inline]
+    fn name(&self) -> &CStr {
+        self.segment.name()
+    }
+
+    #[inline]
+    fn stated_virtual_memory_address(&self) -> Svma {
+        self.segment.stated_virtual_memory_address()
+    }
+
+    #[inline]
+    fn actual_virtual_memory_address(&self) -> Avma {
+        Avma(self.segment.avma_range(self.bias).start as *const u8)
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.segment.len()
+    }
+
+    #[inline]
+    fn file_offset(&self) -> u64 {
+        self.segment.file_offset()
+    }
+
+    #[inline]
+    fn file_size(&self) -> u64 {
+        self.segment.file_size()
+    }
+
+    #[inline]
+    fn permissions(&self) -> SegmentPermissions {
+        self.segment.permissions()
+    }
+}
+
+impl<S: SharedLibrary> DynSharedLibrary for S {
+    #[inline]
+    fn name(&self) -> &CStr {
+        SharedLibrary::name(self)
+    }
+
+    #[inline]
+    fn id(&self) -> Option<SharedLibraryId> {
+        SharedLibrary::id(self)
+    }
+
+    #[inline]
+    fn version(&self) -> Option<SharedLibraryVersion> {
+        SharedLibrary::version(self)
+    }
+
+    fn segments(&self) -> Vec<Box<dyn DynSegment + '_>> {
+        let bias = SharedLibrary::virtual_memory_bias(self);
+        SharedLibrary::segments(self)
+            .map(|segment| Box::new(BoundSegment { segment, bias }) as Box<dyn DynSegment + '_>)
+            .collect()
+    }
+
+    #[inline]
+    fn debug_info_availability(&self) -> DebugInfoAvailability {
+        SharedLibrary::debug_info_availability(self)
+    }
+
+    #[inline]
+    fn virtual_memory_size(&self) -> usize {
+        SharedLibrary::virtual_memory_size(self)
+    }
+
+    #[inline]
+    fn virtual_memory_bias(&self) -> Bias {
+        SharedLibrary::virtual_memory_bias(self)
+    }
+
+    #[inline]
+    fn header_bytes(&self) -> Option<&[u8]> {
+        SharedLibrary::header_bytes(self)
+    }
+
+    #[inline]
+    fn symbol_at(&self, address: Avma) -> Option<Symbol<'_>> {
+        SharedLibrary::symbol_at(self, address)
+    }
+
+    #[inline]
+    fn to_module_info(&self) -> ModuleInfo {
+        ModuleInfo::new(self)
+    }
+}
+
+/// Find all shared libraries in this process and invoke `f` with an
+/// object-safe view of each one, in the same order as `SharedLibrary::each`.
+pub fn each<F, C>(mut f: F)
+where
+    F: FnMut(&dyn DynSharedLibrary) -> C,
+    C: Into<IterationControl>,
+{
+    TargetSharedLibrary::each(|shlib| f(shlib));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dyn_views_match_the_concrete_types() {
+        let mut expected = Vec::new();
+        TargetSharedLibrary::each(|shlib| {
+            let segments: Vec<_> = SharedLibrary::segments(shlib)
+                .map(|seg| (seg.actual_virtual_memory_address(shlib), seg.len()))
+                .collect();
+            expected.push((SharedLibrary::name(shlib).to_owned(), segments));
+        });
+
+        let mut actual = Vec::new();
+        each(|shlib| {
+            let segments: Vec<_> = shlib
+                .segments()
+                .iter()
+                .map(|seg| (seg.actual_virtual_memory_address(), seg.len()))
+                .collect();
+            actual.push((shlib.name().to_owned(), segments));
+        });
+
+        // Other tests load and unload libraries concurrently, so only the
+        // main executable, which always comes first, is stable.
+        assert_eq!(actual.first(), expected.first());
+        assert_eq!(actual.is_empty(), !::TARGET_SUPPORTED);
+    }
+
+    #[test]
+    fn to_module_info_copies_the_module() {
+        let mut modules = Vec::new();
+        each(|shlib| -> IterationControl {
+            modules.push(shlib.to_module_info());
+            IterationControl::Break
+        });
+        if let Some(main) = modules.first() {
+            assert_eq!(main.load_index(), None);
+            assert!(!main.segments().is_empty());
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Recovering display strings from names that aren't valid UTF-8.
+//!
+//! The loader reports names as the bytes of the path a shared library was
+//! loaded from. They are almost always UTF-8, but files created by legacy
+//! tools, or on file systems mounted with a legacy character set, can have
+//! names in another encoding, which `CStr::to_string_lossy` turns into runs
+//! of replacement characters. A [`NameDecoder`](./trait.NameDecoder.html)
+//! says how to decode those names instead; names that are valid UTF-8 are
+//! always taken as UTF-8. It is honored by `report::Options::name_decoder`.
+//!
+//! ```
+//! use findshlibs::encoding::{display_name, Latin1};
+//! use std::ffi::CStr;
+//!
+//! let name = CStr::from_bytes_with_nul(b"/opt/m\xfcller/libfoo.so\0").unwrap();
+//! assert_eq!(display_name(name, Some(&Latin1)), "/opt/m\u{fc}ller/libfoo.so");
+//! ```
+
+use std::borrow::Cow;
+use std::env;
+use std::ffi::CStr;
+use std::fmt::Debug;
+use std::str;
+
+/// A way of decoding names that aren't valid UTF-8.
+pub trait NameDecoder: Debug + Send + Sync {
+    /// Decode `name`, which isn't valid UTF-8, or return `None` if it isn't
+    /// valid in this encoding either.
+    fn decode(&self, name: &[u8]) -> Option<String>;
+}
+
+/// Get the string to display for `name`: the name itself if it is valid
+/// UTF-8, otherwise the name as decoded by `decoder`, and otherwise the name
+/// with invalid sequences replaced by U+FFFD.
+pub fn display_name<'a>(name: &'a CStr, decoder: Option<&dyn NameDecoder>) -> Cow<'a, str> {
+    let bytes = name.to_bytes();
+    if let Ok(name) = str::from_utf8(bytes) {
+        return Cow::Borrowed(name);
+    }
+    match decoder.and_then(|decoder| decoder.decode(bytes)) {
+        Some(name) => Cow::Owned(name),
+        None => String::from_utf8_lossy(bytes),
+    }
+}
+
+/// ISO 8859-1, in which every byte is the code point of the same value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Latin1;
+
+impl NameDecoder for Latin1 {
+    fn decode(&self, name: &[u8]) -> Option<String> {
+        Some(name.iter().map(|&b| char::from(b)).collect())
+    }
+}
+
+/// Windows code page 1252, the ANSI code page of Western European Windows
+/// installations, which Samba and other tools often use for legacy names.
+///
+/// This is ISO 8859-1 with printable characters in place of most C1
+/// controls. The five bytes the code page leaves undefined are decoded as the
+/// C1 controls of the same value, as web browsers do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Windows1252;
+
+/// The characters of the bytes 0x80 to 0x9f in code page 1252.
+const WINDOWS_1252_C1: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+impl NameDecoder for Windows1252 {
+    fn decode(&self, name: &[u8]) -> Option<String> {
+        Some(
+            name.iter()
+                .map(|&b| match b {
+                    0x80..=0x9f => WINDOWS_1252_C1[(b - 0x80) as usize],
+                    _ => char::from(b),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The encoding of this platform's legacy names.
+///
+/// * On Linux, this is the character set of the locale in the `LC_ALL`,
+///   `LC_CTYPE` or `LANG` environment variable, if it is ISO 8859-1 or code
+///   page 1252. Names under a UTF-8 locale, or one with another character
+///   set, aren't decoded.
+///
+/// * On macOS, names are always UTF-8, so nothing is decoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PlatformEncoding;
+
+impl NameDecoder for PlatformEncoding {
+    fn decode(&self, name: &[u8]) -> Option<String> {
+        if cfg!(target_os = "macos") {
+            return None;
+        }
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(|var| env::var(var).ok())
+            .find(|locale| !locale.is_empty())?;
+        match locale_charset(&locale)?.as_str() {
+            "iso88591" | "latin1" => Latin1.decode(name),
+            "cp1252" | "windows1252" => Windows1252.decode(name),
+            _ => None,
+        }
+    }
+}
+
+/// Get the character set of a locale name like `de_DE.ISO-8859-1@euro`,
+/// lowercased and without punctuation.
+fn locale_charset(locale: &str) -> Option<String> {
+    let charset = locale.split('.').nth(1)?;
+    let charset = charset.split('@').next().unwrap_or(charset);
+    Some(
+        charset
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cstr(bytes: &[u8]) -> &CStr {
+        CStr::from_bytes_with_nul(bytes).unwrap()
+    }
+
+    #[test]
+    fn utf8_names_are_never_decoded() {
+        let name = cstr("/opt/m\u{fc}ller/libfoo.so\0".as_bytes());
+        assert_eq!(
+            display_name(name, Some(&Latin1)),
+            Cow::Borrowed("/opt/m\u{fc}ller/libfoo.so")
+        );
+        assert_eq!(display_name(name, None), "/opt/m\u{fc}ller/libfoo.so");
+    }
+
+    #[test]
+    fn legacy_names_are_decoded() {
+        let name = cstr(b"/srv/\x80uro/\x93quoted\x94/\x81.so\0");
+        assert_eq!(
+            display_name(name, None),
+            "/srv/\u{fffd}uro/\u{fffd}quoted\u{fffd}/\u{fffd}.so"
+        );
+        assert_eq!(
+            display_name(name, Some(&Latin1)),
+            "/srv/\u{80}uro/\u{93}quoted\u{94}/\u{81}.so"
+        );
+        assert_eq!(
+            display_name(name, Some(&Windows1252)),
+            "/srv/\u{20ac}uro/\u{201c}quoted\u{201d}/\u{81}.so"
+        );
+    }
+
+    #[test]
+    fn locale_charsets() {
+        assert_eq!(
+            locale_charset("de_DE.ISO-8859-1@euro").as_deref(),
+            Some("iso88591")
+        );
+        assert_eq!(locale_charset("en_US.UTF-8").as_deref(), Some("utf8"));
+        assert_eq!(locale_charset("C"), None);
+    }
+}
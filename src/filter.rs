@@ -0,0 +1,460 @@
+//! Filtering which shared libraries are enumerated.
+//!
+//! A [`Filter`](./struct.Filter.html) is checked against each shared library
+//! before the callback passed to
+//! [`SharedLibrary::each_matching`](../trait.SharedLibrary.html#method.each_matching)
+//! runs, or before anything is copied out of it by
+//! `snapshot::each_with_options`, so callers that only care about a few
+//! shared libraries don't pay for parsing the others.
+//!
+//! ```
+//! use findshlibs::filter::Filter;
+//! use findshlibs::{SharedLibrary, TargetSharedLibrary};
+//!
+//! let filter = Filter::new().name_glob("libc.so*");
+//! TargetSharedLibrary::each_matching(&filter, |shlib| {
+//!     println!("{}", shlib.name().to_string_lossy());
+//! });
+//! ```
+//!
+//! Names are compared byte for byte unless a
+//! [`NameMatching`](./struct.NameMatching.html) says otherwise, which
+//! patterns meant to work across platforms usually want:
+//!
+//! ```
+//! use findshlibs::filter::{Filter, NameMatching};
+//!
+//! let filter = Filter::new()
+//!     .name_glob("libSSL.*")
+//!     .name_matching(NameMatching::new().case_insensitive(true));
+//! ```
+
+use super::path::PathPolicy;
+use super::unicode;
+use super::{DebugInfoAvailability, Segment, SharedLibrary, SharedLibraryId};
+
+use std::borrow::Cow;
+use std::ops::Range;
+use std::str;
+use std::sync::Arc;
+
+/// A set of conditions a shared library has to meet to be enumerated.
+///
+/// The default filter matches every shared library. Each condition that is
+/// set narrows it down further; a shared library has to meet all of them.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    name: Option<NamePattern>,
+    name_matching: NameMatching,
+    path_policy: Option<Arc<dyn PathPolicy>>,
+    address_range: Option<Range<usize>>,
+    id: Option<SharedLibraryId>,
+    debug_info: bool,
+}
+
+#[derive(Clone, Debug)]
+enum NamePattern {
+    Substring(Vec<u8>),
+    Glob(Vec<u8>),
+}
+
+impl Filter {
+    /// Get a filter that matches every shared library.
+    #[inline]
+    pub fn new() -> Self {
+        Filter::default()
+    }
+
+    /// Only match shared libraries whose name contains `substring`.
+    ///
+    /// On Linux, the main executable is reported with an empty name, so it
+    /// only matches the empty substring.
+    #[inline]
+    pub fn name_contains(mut self, substring: &str) -> Self {
+        self.name = Some(NamePattern::Substring(substring.as_bytes().to_vec()));
+        self
+    }
+
+    /// Only match shared libraries whose name matches the glob `pattern`,
+    /// where `*` matches any sequence of bytes and `?` matches any single
+    /// byte.
+    ///
+    /// A pattern without a `/` is matched against the last component of the
+    /// name, so `libc.so*` matches `/lib/x86_64-linux-gnu/libc.so.6`. Other
+    /// patterns are matched against the whole name.
+    ///
+    /// This replaces any pattern set with `name_contains`.
+    #[inline]
+    pub fn name_glob(mut self, pattern: &str) -> Self {
+        self.name = Some(NamePattern::Glob(pattern.as_bytes().to_vec()));
+        self
+    }
+
+    /// Set how names are compared by `name_contains` and `name_glob`.
+    #[inline]
+    pub fn name_matching(mut self, name_matching: NameMatching) -> Self {
+        self.name_matching = name_matching;
+        self
+    }
+
+    /// Apply `policy` to names before matching them with `name_contains` and
+    /// `name_glob`, such as to match the targets of symlinks.
+    ///
+    /// Without a policy of its own, a filter used by
+    /// `snapshot::EnumerateOptions` uses the options' policy.
+    #[inline]
+    pub fn path_policy<P: PathPolicy + 'static>(mut self, policy: P) -> Self {
+        self.path_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Only match shared libraries with a non-empty segment that overlaps the
+    /// given range of actual virtual memory addresses.
+    #[inline]
+    pub fn intersecting(mut self, range: Range<usize>) -> Self {
+        self.address_range = Some(range);
+        self
+    }
+
+    /// Only match shared libraries whose debug-id is `id`, such as the build
+    /// ID a crash report or symbol server refers to.
+    #[inline]
+    pub fn id(mut self, id: SharedLibraryId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Only match shared libraries whose debug information is embedded or
+    /// can be found elsewhere, according to
+    /// `SharedLibrary::debug_info_availability`.
+    ///
+    /// This is the most expensive condition, so it is checked last.
+    #[inline]
+    pub fn with_debug_info(mut self, with_debug_info: bool) -> Self {
+        self.debug_info = with_debug_info;
+        self
+    }
+
+    /// Check whether the given shared library meets every condition of this
+    /// filter.
+    #[inline]
+    pub fn matches<S: SharedLibrary>(&self, shlib: &S) -> bool {
+        self.matches_with_policy(shlib, None)
+    }
+
+    /// Like `matches`, but apply `policy` to the name if this filter has no
+    /// policy of its own.
+    pub(crate) fn matches_with_policy<S: SharedLibrary>(&self,
+                                                        shlib: &S,
+                                                        policy: Option<&dyn PathPolicy>)
+                                                        -> bool {
+        if let Some(ref pattern) = self.name {
+            let name = match self.path_policy.as_deref().or(policy) {
+                Some(policy) => policy.apply(shlib.name()),
+                None => Cow::Borrowed(shlib.name()),
+            };
+            let name = self.name_matching.normalize(name.to_bytes());
+            let name = &name[..];
+            let matched = match *pattern {
+                NamePattern::Substring(ref substring) => {
+                    contains(name, &self.name_matching.normalize(substring))
+                }
+                NamePattern::Glob(ref glob) if glob.contains(&b'/') => {
+                    glob_matches(&self.name_matching.normalize(glob), name)
+                }
+                NamePattern::Glob(ref glob) => {
+                    let file_name = name.rsplit(|&b| b == b'/').next().unwrap_or(name);
+                    glob_matches(&self.name_matching.normalize(glob), file_name)
+                }
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        if let Some(ref range) = self.address_range {
+            let bias = shlib.virtual_memory_bias();
+            let overlaps = shlib.segments().filter(|seg| seg.len() > 0).any(|seg| {
+                let avma = seg.avma_range(bias);
+                avma.start < range.end && range.start < avma.end
+            });
+            if !overlaps {
+                return false;
+            }
+        }
+
+        if let Some(ref id) = self.id {
+            if shlib.id().as_ref() != Some(id) {
+                return false;
+            }
+        }
+
+        !self.debug_info || shlib.debug_info_availability() != DebugInfoAvailability::None
+    }
+}
+
+/// How names of shared libraries are compared with the names and patterns
+/// they are matched against.
+///
+/// The default compares names byte for byte, which is right for most Linux
+/// file systems. Use [`NameMatching::platform`](#method.platform) for the
+/// common behavior of this platform's file systems.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NameMatching {
+    case_insensitive: bool,
+    unicode_normalized: bool,
+}
+
+impl NameMatching {
+    /// Get a `NameMatching` that compares names byte for byte.
+    #[inline]
+    pub fn new() -> Self {
+        NameMatching::default()
+    }
+
+    /// Get the usual name matching of this platform's file systems: case
+    /// insensitive and normalization insensitive on macOS, case insensitive
+    /// on Windows, and byte for byte elsewhere.
+    pub fn platform() -> Self {
+        NameMatching {
+            case_insensitive: cfg!(any(target_os = "macos", windows)),
+            unicode_normalized: cfg!(target_os = "macos"),
+        }
+    }
+
+    /// Ignore differences in case.
+    ///
+    /// Names that are valid UTF-8 are lowercased with Unicode's full case
+    /// mapping. Other names only have their ASCII letters lowercased.
+    #[inline]
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Ignore differences in Unicode normalization, so that precomposed
+    /// characters (NFC) match their decomposed forms (NFD), as on macOS.
+    ///
+    /// Names are compared in NFD, so a `?` in a glob matches one byte of a
+    /// decomposed character. This only knows the decompositions of Latin,
+    /// Greek, Cyrillic and Hangul characters, and names that aren't valid
+    /// UTF-8 are left as they are.
+    #[inline]
+    pub fn unicode_normalized(mut self, unicode_normalized: bool) -> Self {
+        self.unicode_normalized = unicode_normalized;
+        self
+    }
+
+    /// Get the form of `name` that is compared byte for byte.
+    pub fn normalize<'a>(&self, name: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.case_insensitive && !self.unicode_normalized {
+            return Cow::Borrowed(name);
+        }
+        let name = match str::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) if self.case_insensitive => return Cow::Owned(name.to_ascii_lowercase()),
+            Err(_) => return Cow::Borrowed(name),
+        };
+
+        let mut normalized = String::with_capacity(name.len());
+        // Decompose after lowercasing, which can produce precomposed
+        // characters, such as U+0130 becoming `i` and a combining dot above.
+        let lowercase;
+        let name = if self.case_insensitive {
+            lowercase = name.to_lowercase();
+            &lowercase[..]
+        } else {
+            name
+        };
+        if self.unicode_normalized {
+            unicode::decompose(name, &mut normalized);
+        } else {
+            normalized.push_str(name);
+        }
+        Cow::Owned(normalized.into_bytes())
+    }
+
+    /// Check whether two names are the same under this name matching.
+    #[inline]
+    pub fn names_equal(&self, a: &[u8], b: &[u8]) -> bool {
+        self.normalize(a) == self.normalize(b)
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Match `name` against a glob supporting `*` and `?`.
+fn glob_matches(glob: &[u8], name: &[u8]) -> bool {
+    // Greedy matching with backtracking to the most recent `*`, which is
+    // linear in the common case and never worse than quadratic.
+    let (mut g, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some(&b'*') => {
+                star = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_g, star_n)) => {
+                    g = star_g + 1;
+                    n = star_n + 1;
+                    star = Some((star_g, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{IterationControl, TargetSharedLibrary};
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches(b"libc.so*", b"libc.so.6"));
+        assert!(glob_matches(b"libc.so*", b"libc.so"));
+        assert!(glob_matches(b"lib?.so", b"libm.so"));
+        assert!(glob_matches(b"*", b""));
+        assert!(glob_matches(b"*/libfoo.so", b"/usr/lib/libfoo.so"));
+        assert!(glob_matches(b"*a*b*c", b"xxaxxbxxbxxc"));
+        assert!(!glob_matches(b"libc.so*", b"libcrypto.so.3"));
+        assert!(!glob_matches(b"lib?.so", b"libfoo.so"));
+        assert!(!glob_matches(b"*a*b", b"aaa"));
+        assert!(!glob_matches(b"", b"x"));
+    }
+
+    #[test]
+    fn name_matching() {
+        let exact = NameMatching::new();
+        assert!(exact.names_equal(b"libfoo.so", b"libfoo.so"));
+        assert!(!exact.names_equal(b"libFoo.so", b"libfoo.so"));
+
+        let caseless = NameMatching::new().case_insensitive(true);
+        assert!(caseless.names_equal(b"C:\\Windows\\KERNEL32.DLL", b"c:\\windows\\kernel32.dll"));
+        assert!(caseless.names_equal("\u{c9}T\u{c9}.dylib".as_bytes(), "\u{e9}t\u{e9}.dylib".as_bytes()));
+        assert!(caseless.names_equal(b"LIB\xff.so", b"lib\xff.so"));
+        assert!(!caseless.names_equal("caf\u{e9}".as_bytes(), "cafe\u{301}".as_bytes()));
+
+        let normalized = NameMatching::new().unicode_normalized(true);
+        assert!(normalized.names_equal("caf\u{e9}".as_bytes(), "cafe\u{301}".as_bytes()));
+        assert!(!normalized.names_equal("Caf\u{e9}".as_bytes(), "cafe\u{301}".as_bytes()));
+        assert_eq!(normalized.normalize(b"\xff"), Cow::Borrowed(&b"\xff"[..]));
+
+        let both = normalized.case_insensitive(true);
+        assert!(both.names_equal("CAF\u{c9}".as_bytes(), "cafe\u{301}".as_bytes()));
+    }
+
+    #[test]
+    fn filters_use_name_matching() {
+        let mut name = None;
+        TargetSharedLibrary::each(|shlib| {
+            if !shlib.name().to_bytes().is_empty() {
+                name = Some(shlib.name().to_string_lossy().into_owned());
+                return IterationControl::Break;
+            }
+            IterationControl::Continue
+        });
+        let name = match name {
+            Some(name) => name.to_uppercase(),
+            None => return,
+        };
+
+        let filter = Filter::new().name_contains(&name);
+        let mut n = 0;
+        TargetSharedLibrary::each_matching(&filter, |_| n += 1);
+        let mut caseless = 0;
+        let filter = filter.name_matching(NameMatching::new().case_insensitive(true));
+        TargetSharedLibrary::each_matching(&filter, |_| caseless += 1);
+        assert!(caseless > n);
+    }
+
+    #[test]
+    fn default_filter_matches_everything() {
+        let filter = Filter::new();
+        TargetSharedLibrary::each(|shlib| assert!(filter.matches(shlib)));
+    }
+
+    #[test]
+    fn name_filters() {
+        let mut first = None;
+        TargetSharedLibrary::each(|shlib| {
+            if !shlib.name().to_bytes().is_empty() {
+                first = Some(shlib.name().to_string_lossy().into_owned());
+                return IterationControl::Break;
+            }
+            IterationControl::Continue
+        });
+        let name = match first {
+            Some(name) => name,
+            None => return,
+        };
+        let file_name = name.rsplit('/').next().unwrap();
+
+        let mut n = 0;
+        TargetSharedLibrary::each_matching(&Filter::new().name_contains(&name), |shlib| {
+            assert!(shlib.name().to_string_lossy().contains(&name[..]));
+            n += 1;
+        });
+        assert!(n > 0);
+
+        let mut n = 0;
+        TargetSharedLibrary::each_matching(&Filter::new().name_glob(file_name), |shlib| {
+            assert!(shlib.name().to_string_lossy().ends_with(file_name));
+            n += 1;
+        });
+        assert!(n > 0);
+
+        let filter = Filter::new().name_contains("surely no shared library is called this");
+        TargetSharedLibrary::each_matching::<_, ()>(&filter, |_| panic!("nothing should match"));
+    }
+
+    #[test]
+    fn address_range_filter() {
+        let function: fn() = address_range_filter;
+        let address = function as usize;
+        let mut n = 0;
+        TargetSharedLibrary::each_matching(&Filter::new().intersecting(address..address + 1), |_| {
+            n += 1;
+        });
+        assert_eq!(n, ::TARGET_SUPPORTED as usize);
+
+        let filter = Filter::new().intersecting(0..0);
+        TargetSharedLibrary::each_matching::<_, ()>(&filter, |_| panic!("nothing should match"));
+    }
+
+    #[test]
+    fn id_filter() {
+        let mut id = None;
+        TargetSharedLibrary::each(|shlib| {
+            id = shlib.id();
+            if id.is_some() {
+                return IterationControl::Break;
+            }
+            IterationControl::Continue
+        });
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let mut n = 0;
+        TargetSharedLibrary::each_matching(&Filter::new().id(id.clone()), |shlib| {
+            assert_eq!(shlib.id().as_ref(), Some(&id));
+            n += 1;
+        });
+        assert!(n > 0);
+
+        let filter = Filter::new().id(SharedLibraryId::GnuBuildId(vec![0; 3]));
+        TargetSharedLibrary::each_matching::<_, ()>(&filter, |_| panic!("nothing should match"));
+    }
+}
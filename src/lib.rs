@@ -42,6 +42,13 @@
 //!
 //! Is your OS missing here? Send us a pull request!
 //!
+//! ## Diagnostics
+//!
+//! With the `tracing` feature enabled, enumeration emits spans and events
+//! with [`tracing`](https://docs.rs/tracing) under the `findshlibs` target:
+//! the shared libraries visited, the ones skipped because they couldn't be
+//! parsed or were filtered out, and failed OS calls with their error codes.
+//!
 //! ## Addresses
 //!
 //! Shared libraries' addresses can be confusing. They are loaded somewhere in
@@ -83,15 +90,46 @@ extern crate cfg_if;
 #[macro_use]
 extern crate lazy_static;
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 extern crate libc;
 
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[cfg(test)]
+extern crate proptest;
+
 use std::ffi::CStr;
 use std::fmt::{self, Debug};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::ptr;
+use std::slice;
 
+pub mod dynamic;
+pub mod encoding;
+pub mod filter;
+pub mod lookup;
+pub mod path;
+pub mod poll;
+pub mod regions;
+pub mod report;
+pub mod snapshot;
+pub mod table;
+// Only the Linux and macOS backends have anything to report.
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(dead_code))]
+mod trace;
+mod unicode;
 pub mod unsupported;
 
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "rayon")]
+pub mod par;
+
 cfg_if!(
     if #[cfg(target_os = "linux")] {
 
@@ -196,7 +234,51 @@ simple_newtypes! {
         display = "{:#x}";
 }
 
+/// The memory protection a segment is mapped with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SegmentPermissions {
+    /// The segment is readable.
+    pub read: bool,
+    /// The segment is writable.
+    pub write: bool,
+    /// The segment is executable.
+    pub execute: bool,
+}
+
+impl SegmentPermissions {
+    /// Get the permissions that are in either `self` or `other`.
+    #[inline]
+    pub fn union(self, other: SegmentPermissions) -> SegmentPermissions {
+        SegmentPermissions {
+            read: self.read || other.read,
+            write: self.write || other.write,
+            execute: self.execute || other.execute,
+        }
+    }
+
+    /// Get whether these permissions are both writable and executable.
+    #[inline]
+    pub fn is_wx(self) -> bool {
+        self.write && self.execute
+    }
+}
+
+impl fmt::Display for SegmentPermissions {
+    /// Formats the permissions like `ls` and `/proc/self/maps` do, e.g. `r-x`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
 /// A mapped segment in a shared library.
+#[allow(clippy::len_without_is_empty)]
 pub trait Segment: Sized + Debug {
     /// The associated shared library type for this segment.
     type SharedLibrary: SharedLibrary<Segment = Self>;
@@ -211,8 +293,28 @@ pub trait Segment: Sized + Debug {
     fn stated_virtual_memory_address(&self) -> Svma;
 
     /// Get the length of this segment in memory (in bytes).
+    ///
+    /// This can be larger than `file_size`, in which case the rest of the
+    /// segment, such as `.bss`, is zero-filled.
     fn len(&self) -> usize;
 
+    /// Get the number of bytes of this segment that are backed by the file it
+    /// was loaded from.
+    ///
+    /// This is the ELF program header's `p_filesz`, or the Mach-O segment
+    /// command's `filesize`.
+    fn file_size(&self) -> u64;
+
+    /// Get the offset of this segment's contents within the file it was
+    /// loaded from (in bytes).
+    ///
+    /// This is the ELF program header's `p_offset`, or the Mach-O segment
+    /// command's `fileoff`.
+    fn file_offset(&self) -> u64;
+
+    /// Get the memory protection this segment is initially mapped with.
+    fn permissions(&self) -> SegmentPermissions;
+
     // Provided methods.
 
     /// Get this segment's actual virtual memory address.
@@ -221,16 +323,31 @@ pub trait Segment: Sized + Debug {
     /// documentation for details.
     #[inline]
     fn actual_virtual_memory_address(&self, shlib: &Self::SharedLibrary) -> Avma {
+        apply_bias(self.stated_virtual_memory_address(), shlib.virtual_memory_bias())
+    }
+
+    /// Get the stated virtual memory address one past the end of this segment.
+    ///
+    /// This is based on the segment's size in memory, not its size on disk.
+    #[inline]
+    fn svma_end(&self) -> Svma {
         let svma = self.stated_virtual_memory_address();
-        let bias = shlib.virtual_memory_bias();
-        Avma(unsafe { svma.0.offset(bias.0) })
+        Svma(svma.0.wrapping_add(self.len()))
+    }
+
+    /// Get the half-open range of actual virtual memory addresses this segment
+    /// occupies when loaded with the given bias.
+    #[inline]
+    fn avma_range(&self, bias: Bias) -> Range<usize> {
+        let start = apply_bias(self.stated_virtual_memory_address(), bias).0 as usize;
+        start..start.wrapping_add(self.len())
     }
 
     /// Does this segment contain the given address?
     #[inline]
     fn contains_svma(&self, address: Svma) -> bool {
         let start = self.stated_virtual_memory_address().0 as usize;
-        let end = start + self.len();
+        let end = self.svma_end().0 as usize;
         let address = address.0 as usize;
         start <= address && address < end
     }
@@ -238,18 +355,117 @@ pub trait Segment: Sized + Debug {
     /// Does this segment contain the given address?
     #[inline]
     fn contains_avma(&self, shlib: &Self::SharedLibrary, address: Avma) -> bool {
-        let start = self.actual_virtual_memory_address(shlib).0 as usize;
-        let end = start + self.len();
-        let address = address.0 as usize;
-        start <= address && address < end
+        self.avma_range(shlib.virtual_memory_bias())
+            .contains(&(address.0 as usize))
+    }
+
+    /// Get the offset within the file this segment was loaded from of the
+    /// given stated virtual memory address, if it is in the part of this
+    /// segment that is backed by the file.
+    #[inline]
+    fn svma_to_file_offset(&self, address: Svma) -> Option<u64> {
+        svma_to_file_offset(self.stated_virtual_memory_address(),
+                            self.len(),
+                            self.file_offset(),
+                            self.file_size(),
+                            address)
+    }
+
+    /// Get the stated virtual memory address that the given offset within the
+    /// file this segment was loaded from is mapped at, if it is in the part of
+    /// the file backing this segment.
+    #[inline]
+    fn file_offset_to_svma(&self, offset: u64) -> Option<Svma> {
+        file_offset_to_svma(self.stated_virtual_memory_address(),
+                            self.len(),
+                            self.file_offset(),
+                            self.file_size(),
+                            offset)
+    }
+}
+
+/// Apply a bias to a stated virtual memory address.
+///
+/// Like the loader's own arithmetic, this wraps around instead of
+/// overflowing, so it is defined for any bias, even one that doesn't belong
+/// to the address.
+#[inline]
+pub(crate) fn apply_bias(address: Svma, bias: Bias) -> Avma {
+    Avma(address.0.wrapping_offset(bias.0))
+}
+
+/// Remove a bias from an actual virtual memory address, undoing `apply_bias`.
+#[inline]
+pub(crate) fn remove_bias(address: Avma, bias: Bias) -> Svma {
+    Svma(address.0.wrapping_offset(bias.0.wrapping_neg()))
+}
+
+/// Map a stated virtual memory address to an offset within a file, given a
+/// segment starting at `start` that is `len` bytes long in memory and whose
+/// first `file_size` bytes are loaded from `file_offset`.
+pub(crate) fn svma_to_file_offset(start: Svma,
+                                  len: usize,
+                                  file_offset: u64,
+                                  file_size: u64,
+                                  address: Svma)
+                                  -> Option<u64> {
+    let delta = (address.0 as usize).checked_sub(start.0 as usize)? as u64;
+    if delta < file_size.min(len as u64) {
+        file_offset.checked_add(delta)
+    } else {
+        None
+    }
+}
+
+/// Map an offset within a file to a stated virtual memory address, undoing
+/// `svma_to_file_offset` for the same segment.
+pub(crate) fn file_offset_to_svma(start: Svma,
+                                  len: usize,
+                                  file_offset: u64,
+                                  file_size: u64,
+                                  offset: u64)
+                                  -> Option<Svma> {
+    let delta = offset.checked_sub(file_offset)?;
+    if delta < file_size.min(len as u64) {
+        let address = (start.0 as usize).checked_add(delta as usize)?;
+        Some(Svma(address as *const u8))
+    } else {
+        None
     }
 }
 
+/// Copy as much of `src` as fits into the start of `dst`, and return the
+/// number of bytes copied.
+#[inline]
+pub(crate) fn copy_truncated(src: &[u8], dst: &mut [u8]) -> usize {
+    let n = src.len().min(dst.len());
+    dst[..n].copy_from_slice(&src[..n]);
+    n
+}
+
 /// Represents an ID for a shared library.
-#[derive(PartialEq, Eq, Hash)]
+///
+/// IDs can be used as `HashMap` keys, and are ordered first by their kind and
+/// then by their bytes, so sorting by ID is deterministic across runs.
+///
+/// The `Display` implementation is stable and intended for use in file names
+/// and cache keys:
+///
+/// * `Uuid` is formatted as lowercase hex in the canonical 8-4-4-4-12 grouping,
+///   e.g. `c1e4ed0b-9a49-3bd4-8ddc-d5f4ba2f39cd`.
+///
+/// * `GnuBuildId` is formatted as lowercase hex without separators, in the
+///   same byte order as the note, e.g. `f1c3bcc0279865fe3058404b2831d9e64135386c`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum SharedLibraryId {
     /// A UUID (used on mac)
     Uuid([u8; 16]),
+    /// A GNU build ID (used on Linux)
+    ///
+    /// Build IDs are usually 20-byte SHA-1 hashes, but other toolchains and
+    /// linker options produce other lengths, such as 16-byte MD5 hashes or
+    /// 8-byte fast hashes. All of the bytes are kept.
+    GnuBuildId(Vec<u8>),
 }
 
 impl fmt::Display for SharedLibraryId {
@@ -258,9 +474,14 @@ impl fmt::Display for SharedLibraryId {
             SharedLibraryId::Uuid(ref bytes) => {
                 for (idx, byte) in bytes.iter().enumerate() {
                     if idx == 4 || idx == 6 || idx == 8 || idx == 10 {
-                        try!(write!(f, "-"));
+                        write!(f, "-")?;
                     }
-                    try!(write!(f, "{:02x}", byte));
+                    write!(f, "{:02x}", byte)?;
+                }
+            }
+            SharedLibraryId::GnuBuildId(ref bytes) => {
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
                 }
             }
         }
@@ -274,18 +495,128 @@ impl fmt::Debug for SharedLibraryId {
             SharedLibraryId::Uuid(..) => {
                 write!(f, "Uuid(\"{}\")", self)?;
             }
+            SharedLibraryId::GnuBuildId(..) => {
+                write!(f, "GnuBuildId(\"{}\")", self)?;
+            }
         }
         Ok(())
     }
 }
 
+/// The version of a shared library, as recorded by its own headers.
+///
+/// On macOS this is the `current_version` from the `LC_ID_DYLIB` load command.
+/// On Linux it is derived from the numeric suffix of the `DT_SONAME`, so a
+/// SONAME of `libssl.so.1.1` yields version `1.1.0`. Components that aren't
+/// recorded are zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SharedLibraryVersion {
+    /// The major version.
+    pub major: u32,
+    /// The minor version.
+    pub minor: u32,
+    /// The patch version.
+    pub patch: u32,
+}
+
+impl fmt::Display for SharedLibraryVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Where a shared library's debug information can be found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DebugInfoAvailability {
+    /// The shared library's file contains debug information.
+    ///
+    /// On Linux, this means it has a `.debug_info` or `.zdebug_info` section.
+    /// On macOS, this means it has a `__DWARF` segment.
+    Embedded,
+    /// The shared library records where to find separate debug information.
+    ///
+    /// On Linux, this means it has a `.gnu_debuglink` or `.gnu_debugaltlink`
+    /// section, or a build ID to look up. On macOS, this means it has a UUID
+    /// to look up a dSYM with.
+    External,
+    /// There is no sign of any debug information.
+    None,
+}
+
+/// The kind of volume a shared library's file is stored on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VolumeKind {
+    /// A fixed disk of this machine.
+    Local,
+    /// A network file system, which can change or disappear underneath the
+    /// process.
+    Network,
+    /// Removable media, such as a USB stick or an SD card.
+    Removable,
+}
+
+/// The locks that enumerating shared libraries may take.
+///
+/// A crash or signal handler that takes a lock held by the thread it
+/// interrupted, or by a thread that was suspended, deadlocks. Handlers can
+/// check the hazards of the enumeration they are about to use, see
+/// `SharedLibrary::lock_hazards` and
+/// `snapshot::EnumerateOptions::lock_hazards`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LockHazards {
+    /// The platform loader's lock, which is also held while shared libraries
+    /// are loaded and unloaded.
+    pub loader_lock: bool,
+    /// One of this crate's locks, such as the macOS `DYLD_LOCK`.
+    pub crate_lock: bool,
+    /// The allocator's locks, which allocating memory may take.
+    pub allocator_lock: bool,
+}
+
+impl LockHazards {
+    /// Find out whether no lock is taken at all.
+    #[inline]
+    pub fn is_lock_free(&self) -> bool {
+        !self.loader_lock && !self.crate_lock && !self.allocator_lock
+    }
+}
+
+/// What was cut off to respect a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TruncationKind {
+    /// Whole modules were left out, such as by
+    /// `snapshot::EnumerateOptions::max_modules`.
+    Modules,
+    /// Names were shortened, such as by `report::Options::max_name_len`.
+    Names,
+    /// Lines were cut off, such as by `report::Options::max_line_len`.
+    Lines,
+}
+
+/// An indicator that a result is incomplete because of a limit, so that it
+/// isn't mistaken for the whole picture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Truncated {
+    /// What was cut off.
+    pub kind: TruncationKind,
+    /// How much was lost: the number of modules for
+    /// `TruncationKind::Modules`, and the number of bytes otherwise.
+    pub lost: usize,
+}
+
 /// A trait representing a shared library that is loaded in this process.
 pub trait SharedLibrary: Sized + Debug {
     /// The associated segment type for this shared library.
     type Segment: Segment<SharedLibrary = Self>;
 
     /// An iterator over a shared library's segments.
-    type SegmentIter: Debug + Iterator<Item = Self::Segment>;
+    ///
+    /// The number of segments is always known up front, and segments can be
+    /// iterated in reverse.
+    type SegmentIter: Debug
+        + ExactSizeIterator<Item = Self::Segment>
+        + DoubleEndedIterator<Item = Self::Segment>;
 
     /// Get the name of this shared library.
     fn name(&self) -> &CStr;
@@ -293,28 +624,651 @@ pub trait SharedLibrary: Sized + Debug {
     /// Get the debug-id of this shared library if available.
     fn id(&self) -> Option<SharedLibraryId>;
 
+    /// Get the version of this shared library if available.
+    ///
+    /// See [`SharedLibraryVersion`](./struct.SharedLibraryVersion.html) for
+    /// where the version comes from on each platform.
+    fn version(&self) -> Option<SharedLibraryVersion>;
+
     /// Iterate over this shared library's segments.
     fn segments(&self) -> Self::SegmentIter;
 
+    /// Find out whether debug information for this shared library is
+    /// available, without parsing it.
+    ///
+    /// On Linux, debug sections aren't loaded into memory, so this reads the
+    /// section headers from the shared library's file on disk. If the file
+    /// can't be read, only the in-memory build ID is considered. On macOS,
+    /// this only inspects the load commands.
+    fn debug_info_availability(&self) -> DebugInfoAvailability;
+
+    /// Get the directory this shared library was compiled in, as recorded in
+    /// its debug information, which is the prefix that relative source paths
+    /// in it have to be resolved against or remapped from.
+    ///
+    /// On Linux, this is the `DW_AT_comp_dir` of the first compilation unit,
+    /// read from the file on disk or from the separate debug file found by
+    /// build ID or `.gnu_debuglink` in the places GDB looks. Compressed debug
+    /// sections aren't supported. On macOS, this is the directory of the
+    /// first source file in the debug map the linker leaves in the symbol
+    /// table, which stripped images don't have.
+    fn compilation_directory(&self) -> Option<PathBuf>;
+
+    /// Find out what kind of volume this shared library was loaded from, or
+    /// `None` if its file can't be found anymore.
+    ///
+    /// On Linux, this looks at the type of the file system with `statfs`,
+    /// and at whether the block device holding it is marked removable in
+    /// `/sys`. On macOS, this looks at the mount flags from `statfs`; only
+    /// recent kernels mark removable media.
+    fn volume_kind(&self) -> Option<VolumeKind>;
+
+    /// Get the sections contained in each of this shared library's segments,
+    /// as one list per segment in the order of `segments`, each sorted by
+    /// address.
+    ///
+    /// On Linux, section headers aren't loaded into memory, so this reads
+    /// them from the shared library's file on disk, and returns `None` if it
+    /// can't be read. Only sections that occupy memory at run time are
+    /// listed, and a section is listed in every segment containing it, such
+    /// as both `PT_LOAD` and `PT_GNU_EH_FRAME` for `.eh_frame_hdr`. On macOS,
+    /// the sections follow each segment's load command.
+    fn section_map(&self) -> Option<Vec<Vec<SectionInfo>>>;
+
+    /// Find out whether this is the main executable of the process, rather
+    /// than a shared library it loaded.
+    ///
+    /// On Linux, this compares the program headers with the ones the kernel
+    /// passed to the loader in `AT_PHDR`. On macOS, this checks whether the
+    /// Mach-O file type is `MH_EXECUTE`.
+    fn is_main_executable(&self) -> bool;
+
+    /// Get the number of bytes of address space this shared library occupies,
+    /// from the start of its lowest loaded segment to the end of its highest,
+    /// including any gaps between segments.
+    ///
+    /// * On Linux, this is the span of the `PT_LOAD` segments.
+    ///
+    /// * On macOS, this is the span of the segments other than `__PAGEZERO`,
+    ///   which only reserves address space.
+    fn virtual_memory_size(&self) -> usize;
+
     /// Get the bias of this shared library.
     ///
     /// See the module documentation for details.
     fn virtual_memory_bias(&self) -> Bias;
 
+    /// Get the ASLR slide of this shared library.
+    ///
+    /// This is the difference between the base address the shared library was
+    /// linked to be loaded at and the base address it was actually loaded at.
+    /// It is zero for images that were loaded at their preferred address, such
+    /// as non-PIE executables.
+    ///
+    /// * On Linux, this is the `dlpi_addr` that `dl_iterate_phdr` reports.
+    ///
+    /// * On macOS, this is the slide that `_dyld_get_image_vmaddr_slide`
+    ///   reports.
+    ///
+    /// While this currently has the same value as `virtual_memory_bias` on
+    /// every supported platform, it is always defined in terms of the image's
+    /// preferred base address, whereas the bias is defined in terms of SVMAs.
+    fn load_slide(&self) -> isize;
+
+    /// Find the nearest exported or dynamic symbol at or before the given
+    /// AVMA, if the address is within this shared library.
+    ///
+    /// This only consults the symbol tables the loader keeps in memory, so
+    /// static functions and stripped symbols are not found, and an address
+    /// inside one of them is attributed to the previous dynamic symbol.
+    ///
+    /// This takes the loader's lock and must not be called from a signal
+    /// handler.
+    ///
+    /// On Linux, `each` holds the loader's lock for the list of shared
+    /// libraries while `dladdr` takes its lock for loading them, so calling
+    /// this from inside `each` can deadlock with another thread calling
+    /// `dlopen`, which takes them in the opposite order. Look symbols up with
+    /// `snapshot::ModuleInfo::symbol_at` or `lookup::ModuleMap::symbol_at`
+    /// instead, which run after iteration.
+    fn symbol_at(&self, address: Avma) -> Option<Symbol<'_>>;
+
+    /// Get the headers of this shared library, exactly as the loader mapped
+    /// them, so they can be handed to a full object file parser.
+    ///
+    /// * On Linux, this is the ELF file header and the program header table,
+    ///   read from the `PT_LOAD` segment that maps the start of the file.
+    ///
+    /// * On macOS, this is the Mach-O header and its load commands.
+    ///
+    /// Returns `None` if the headers aren't mapped.
+    fn header_bytes(&self) -> Option<&[u8]>;
+
+    /// Count the relocations the loader had to apply to this shared library,
+    /// to estimate how much it costs to load.
+    ///
+    /// * On Linux, this reads the sizes of the relocation tables from the
+    ///   dynamic section. Relative relocations are counted from
+    ///   `DT_RELACOUNT`, `DT_RELCOUNT` and any `DT_RELR` table.
+    ///
+    /// * On macOS, this interprets the rebase and bind opcodes of the
+    ///   `LC_DYLD_INFO` load command.
+    ///
+    /// Returns `None` if there is no relocation information to read, such as
+    /// for a static executable on Linux, or an image using chained fixups on
+    /// macOS, whose fixup chains dyld overwrites as it applies them.
+    fn relocation_counts(&self) -> Option<RelocationCounts>;
+
+    /// Get the addresses of the static initializers the loader runs when it
+    /// loads this shared library, such as C++ constructors of globals.
+    ///
+    /// * On Linux, these are the entries of `DT_PREINIT_ARRAY` (only found in
+    ///   executables), followed by `DT_INIT` and the entries of
+    ///   `DT_INIT_ARRAY`, which is the order they run in.
+    ///
+    /// * On macOS, these are the entries of the `S_MOD_INIT_FUNC_POINTERS`
+    ///   and `S_INIT_FUNC_OFFSETS` sections, in the order they appear.
+    fn init_routines(&self) -> Vec<Avma>;
+
+    /// Get the addresses of the finalizers the loader runs when it unloads
+    /// this shared library, or when the process exits.
+    ///
+    /// * On Linux, these are the entries of `DT_FINI_ARRAY` in reverse,
+    ///   followed by `DT_FINI`, which is the order they run in.
+    ///
+    /// * On macOS, these are the entries of the `S_MOD_TERM_FUNC_POINTERS`
+    ///   sections, in the order they appear. Most toolchains register
+    ///   destructors with `__cxa_atexit` instead.
+    fn fini_routines(&self) -> Vec<Avma>;
+
+    /// Get the start addresses of the functions in this shared library that
+    /// have unwind information, in ascending order, without needing any
+    /// symbols.
+    ///
+    /// * On Linux, these are the initial locations of the FDEs in the sorted
+    ///   lookup table of the `PT_GNU_EH_FRAME` segment (`.eh_frame_hdr`).
+    ///
+    /// * On macOS, these are the function offsets of the compact unwind
+    ///   entries in the `__TEXT,__unwind_info` section.
+    ///
+    /// Functions without unwind information, such as some hand-written
+    /// assembly, are missing, and adjacent functions with identical compact
+    /// unwind encodings may be merged into one on macOS, so these are only
+    /// approximate function boundaries. An empty list is returned if there is
+    /// no unwind information in a supported format.
+    fn function_starts(&self) -> Vec<Avma>;
+
     /// Given an AVMA within this shared library, convert it back to an SVMA by
     /// removing this shared library's bias.
     #[inline]
     fn avma_to_svma(&self, address: Avma) -> Svma {
-        let bias = self.virtual_memory_bias();
-        let reverse_bias = -bias.0;
-        Svma(unsafe { address.0.offset(reverse_bias) })
+        remove_bias(address, self.virtual_memory_bias())
     }
 
+    /// Get a counter that changes whenever a shared library is loaded into or
+    /// unloaded from this process.
+    ///
+    /// This is much cheaper than enumerating shared libraries, so it can be
+    /// used to decide whether a cached list of shared libraries is stale: if
+    /// two calls return the same value, nothing was loaded or unloaded in
+    /// between.
+    ///
+    /// * On Linux, this is the sum of the loader's `dlpi_adds` and `dlpi_subs`
+    ///   counters. With C libraries that don't provide them, the number of
+    ///   loaded shared libraries is returned instead, which misses an unload
+    ///   followed by a load.
+    ///
+    /// * On macOS, this counts the images that dyld has reported as added or
+    ///   removed since the first call.
+    fn loaded_modules_generation() -> u64;
+
+    /// Get the locks that `each` may take.
+    ///
+    /// * On Linux, `dl_iterate_phdr` takes the loader's lock, and no lock of
+    ///   this crate is taken. Taking another loader lock while it is held, as
+    ///   `symbol_at` does with `dladdr`, can deadlock with a concurrent
+    ///   `dlopen`.
+    ///
+    /// * On macOS, the `DYLD_LOCK` is taken, and dyld takes its own lock in
+    ///   the functions that look up images.
+    fn lock_hazards() -> LockHazards;
+
+    /// Get the locks that `each_unsafe_signal_safe` may take.
+    ///
+    /// This never includes one of this crate's locks, but both Linux's
+    /// `dl_iterate_phdr` and macOS's dyld take the loader's lock. Where that
+    /// is not acceptable, `regions::mapped_modules` reconstructs modules from
+    /// the memory map without going through the loader.
+    fn signal_safe_lock_hazards() -> LockHazards;
+
     /// Find all shared libraries in this process and invoke `f` with each one.
+    ///
+    /// Shared libraries are visited in the order the loader keeps them in,
+    /// which is load order on every supported platform: the main executable
+    /// comes first, followed by its dependencies as they were loaded, and
+    /// then anything loaded later with `dlopen`.
+    ///
+    /// * On Linux, this is the order of the dynamic linker's link map, as
+    ///   reported by `dl_iterate_phdr`. The vDSO is usually listed right
+    ///   after the main executable.
+    ///
+    /// * On macOS, this is the order of dyld's image indices.
+    ///
+    /// Use `snapshot::EnumerateOptions::order` to get the shared libraries
+    /// sorted by address instead.
     fn each<F, C>(f: F)
     where
         F: FnMut(&Self) -> C,
         C: Into<IterationControl>;
+
+    /// Find all shared libraries in this process and invoke `f` with each one,
+    /// in a way that is suitable for use inside a crash or signal handler.
+    ///
+    /// Unlike `each`, this never allocates and never takes any of this crate's
+    /// locks. Most methods on `SharedLibrary` and `Segment` read directly from
+    /// the loader's data structures and are fine to call from `f`, but these
+    /// are not:
+    ///
+    /// * `id()` on Linux copies the build ID into a `Vec`. Use
+    ///   `copy_id_into` or `linux::SharedLibrary::build_id` instead.
+    ///
+    /// * `debug_info_availability()` on Linux, `compilation_directory()`,
+    ///   `section_map()` and `volume_kind()` open, read or `stat` the file on
+    ///   disk, and allocate.
+    ///
+    /// * `init_routines()`, `fini_routines()`, `function_starts()` and
+    ///   `frame_pointer_hint()` collect addresses into a `Vec`.
+    ///
+    /// * `relocation_counts()` reads the relocation tables, which are
+    ///   usually not paged in after startup, so it can block on disk I/O.
+    ///
+    /// * `symbol_at()` calls `dladdr`, which takes the loader's lock.
+    ///
+    /// Use `copy_name_into`, `copy_id_into` and `copy_segments_into` to keep
+    /// what is needed after `f` returns in fixed-size buffers of the caller's.
+    ///
+    /// * On Linux, this still goes through `dl_iterate_phdr`, which takes the
+    ///   loader's (recursive) lock. It cannot deadlock unless another thread
+    ///   holding that lock has been stopped.
+    ///
+    /// * On macOS, the `DYLD_LOCK` is not taken, so shared libraries may be
+    ///   loaded or unloaded concurrently.
+    ///
+    /// # Safety
+    ///
+    /// Panics are not caught: if `f` panics, the process aborts. On macOS the
+    /// caller must ensure that no shared library is unloaded while iterating,
+    /// for example because every other thread has been suspended.
+    unsafe fn each_unsafe_signal_safe<F, C>(f: F)
+    where
+        F: FnMut(&Self) -> C,
+        C: Into<IterationControl>;
+
+    /// Copy as much of this shared library's name as fits into `buf`, without
+    /// a trailing nul, and return the number of bytes copied.
+    ///
+    /// This never allocates, so it is fine to call from
+    /// `each_unsafe_signal_safe`. The name was cut off if fewer bytes than
+    /// `name().to_bytes().len()` were copied.
+    #[inline]
+    fn copy_name_into(&self, buf: &mut [u8]) -> usize {
+        copy_truncated(self.name().to_bytes(), buf)
+    }
+
+    /// Copy as much of the bytes of this shared library's debug-id as fits
+    /// into `buf`, and return the number of bytes copied, which is 0 if it
+    /// has none.
+    ///
+    /// This never allocates, so it is fine to call from
+    /// `each_unsafe_signal_safe`.
+    #[inline]
+    fn copy_id_into(&self, buf: &mut [u8]) -> usize {
+        match self.id() {
+            Some(SharedLibraryId::Uuid(bytes)) => copy_truncated(&bytes, buf),
+            Some(SharedLibraryId::GnuBuildId(bytes)) => copy_truncated(&bytes, buf),
+            None => 0,
+        }
+    }
+
+    /// Copy the actual virtual memory address and length of as many of this
+    /// shared library's segments as fit into `buf`, in the order `segments`
+    /// yields them, and return the number of segments copied.
+    ///
+    /// This never allocates, so it is fine to call from
+    /// `each_unsafe_signal_safe`. Segments were left out if fewer than
+    /// `segments().len()` were copied.
+    #[inline]
+    fn copy_segments_into(&self, buf: &mut [(Avma, usize)]) -> usize {
+        let mut n = 0;
+        for (slot, seg) in buf.iter_mut().zip(self.segments()) {
+            *slot = (seg.actual_virtual_memory_address(self), seg.len());
+            n += 1;
+        }
+        n
+    }
+
+    /// Find all shared libraries in this process that match `filter`, and
+    /// invoke `f` with each one.
+    ///
+    /// Shared libraries are visited in the same order as with `each`.
+    #[inline]
+    fn each_matching<F, C>(filter: &filter::Filter, mut f: F)
+    where
+        F: FnMut(&Self) -> C,
+        C: Into<IterationControl>,
+    {
+        Self::each(|shlib| {
+            if filter.matches(shlib) {
+                f(shlib).into()
+            } else {
+                IterationControl::Continue
+            }
+        });
+    }
+
+    /// Find all shared libraries in this process and invoke `f` with each one
+    /// until it returns `Some`, then stop iterating and return that value.
+    ///
+    /// Returns `None` if `f` returned `None` for every shared library.
+    #[inline]
+    fn find_map_each<F, T>(mut f: F) -> Option<T>
+    where
+        F: FnMut(&Self) -> Option<T>,
+    {
+        let mut found = None;
+        Self::each(|shlib| match f(shlib) {
+            Some(x) => {
+                found = Some(x);
+                IterationControl::Break
+            }
+            None => IterationControl::Continue,
+        });
+        found
+    }
+
+    /// Get a snapshot of the main executable.
+    ///
+    /// Both the Linux and macOS loaders report the main executable first, so
+    /// this stops after the first shared library instead of visiting every
+    /// one. Returns `None` on unsupported platforms.
+    #[inline]
+    fn main_executable() -> Option<snapshot::ModuleInfo> {
+        Self::find_map_each(|shlib| Some(snapshot::ModuleInfo::new(shlib)))
+    }
+
+    /// Find out whether any of this shared library's non-empty segments is
+    /// mapped both writable and executable, in violation of W^X.
+    ///
+    /// This checks the permissions the segments were initially mapped with;
+    /// changes made later with `mprotect` aren't seen.
+    #[inline]
+    fn has_wx_segments(&self) -> bool {
+        self.segments().any(|seg| seg.len() > 0 && seg.permissions().is_wx())
+    }
+
+    /// Guess whether this shared library was built with frame pointers, by
+    /// looking for the instructions setting up a frame pointer at the start
+    /// of a sample of the functions found by `function_starts`.
+    ///
+    /// Only x86, x86-64 and AArch64 prologues are recognized; on other
+    /// architectures nothing is sampled.
+    fn frame_pointer_hint(&self) -> FramePointerHint {
+        let starts = self.function_starts();
+        let step = starts.len().div_ceil(FRAME_POINTER_SAMPLES).max(1);
+        let bias = self.virtual_memory_bias();
+
+        let mut hint = FramePointerHint::default();
+        for &start in starts.iter().step_by(step) {
+            let end = self.segments().find_map(|seg| {
+                let permissions = seg.permissions();
+                let range = seg.avma_range(bias);
+                if permissions.read && permissions.execute && range.contains(&(start.0 as usize)) {
+                    Some(range.end)
+                } else {
+                    None
+                }
+            });
+            let len = match end {
+                Some(end) => (end - start.0 as usize).min(MAX_PROLOGUE_LEN),
+                None => continue,
+            };
+
+            // The segment is mapped readable for as long as the shared library
+            // is loaded.
+            let code = unsafe { slice::from_raw_parts(start.0, len) };
+            if let Some(with_frame_pointer) = has_frame_pointer_prologue(code) {
+                hint.sampled += 1;
+                hint.with_frame_pointer += with_frame_pointer as usize;
+            }
+        }
+        hint
+    }
+
+}
+
+/// The symbol NativeAOT images export to let debuggers find the runtime's
+/// data structures.
+pub(crate) const NATIVE_AOT_DEBUG_HEADER: &[u8] = b"DotNetRuntimeDebugHeader\0";
+
+/// A kind of .NET image compiled ahead of time to native code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ManagedImageKind {
+    /// A PE image of managed code with precompiled native code for its
+    /// methods, which the runtime may still replace with JIT-compiled code.
+    /// Its methods are described by the image's ReadyToRun header.
+    ReadyToRun,
+    /// A native executable or shared library produced by NativeAOT, which
+    /// contains the runtime and has no JIT. Its managed methods only appear
+    /// in the native symbol table.
+    NativeAot,
+}
+
+/// A symbol found by [`SharedLibrary::symbol_at`](./trait.SharedLibrary.html#tymethod.symbol_at).
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol<'a> {
+    name: &'a CStr,
+    address: Avma,
+    offset: usize,
+}
+
+impl<'a> Symbol<'a> {
+    /// Get the symbol's name, which is not demangled.
+    #[inline]
+    pub fn name(&self) -> &'a CStr {
+        self.name
+    }
+
+    /// Get the actual virtual memory address of the symbol.
+    #[inline]
+    pub fn address(&self) -> Avma {
+        self.address
+    }
+
+    /// Get the offset of the looked-up address from the symbol's address.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// A section of a shared library, as found by
+/// [`SharedLibrary::section_map`](./trait.SharedLibrary.html#tymethod.section_map).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SectionInfo {
+    /// The section's name, such as `.text` or `__text`.
+    pub name: Vec<u8>,
+    /// The section's stated virtual memory address.
+    pub svma: Svma,
+    /// The section's size in memory, in bytes.
+    pub len: usize,
+    /// The offset of the section from the start of the segment containing
+    /// it.
+    pub segment_offset: usize,
+    /// The offset of the section's contents in the shared library's file, or
+    /// `None` if it has none, like `.bss` or a zero-fill section.
+    pub file_offset: Option<u64>,
+}
+
+/// The number of relocations of each kind in a shared library, as found by
+/// [`SharedLibrary::relocation_counts`](./trait.SharedLibrary.html#tymethod.relocation_counts).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RelocationCounts {
+    /// Relocations that only add the bias to an address, which are cheap to
+    /// apply: ELF relative relocations, or Mach-O rebases.
+    pub relative: usize,
+    /// Relocations that have to look up a symbol when the shared library is
+    /// loaded: other ELF dynamic relocations, or Mach-O binds and weak binds.
+    pub symbolic: usize,
+    /// Relocations that look up a symbol the first time it is called: ELF
+    /// PLT relocations, or Mach-O lazy binds. With eager binding, these are
+    /// applied at load time as well.
+    pub lazy: usize,
+}
+
+impl RelocationCounts {
+    /// Get the total number of relocations.
+    #[inline]
+    pub fn total(&self) -> usize {
+        self.relative + self.symbolic + self.lazy
+    }
+}
+
+/// How many functions `SharedLibrary::frame_pointer_hint` looks at, at most.
+const FRAME_POINTER_SAMPLES: usize = 256;
+
+/// How many bytes at the start of a function may hold its prologue.
+const MAX_PROLOGUE_LEN: usize = 16;
+
+/// How many of a shared library's functions appear to set up a frame pointer,
+/// as guessed by
+/// [`SharedLibrary::frame_pointer_hint`](./trait.SharedLibrary.html#method.frame_pointer_hint).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FramePointerHint {
+    /// The number of functions whose prologue was looked at.
+    pub sampled: usize,
+    /// The number of those that set up a frame pointer.
+    pub with_frame_pointer: usize,
+}
+
+impl FramePointerHint {
+    /// Get the fraction of the sampled functions that set up a frame
+    /// pointer, between zero and one.
+    #[inline]
+    pub fn fraction(&self) -> f64 {
+        if self.sampled == 0 {
+            0.0
+        } else {
+            self.with_frame_pointer as f64 / self.sampled as f64
+        }
+    }
+
+    /// Guess whether frame pointers can be relied on for unwinding through
+    /// this shared library, or `None` if no functions were sampled.
+    ///
+    /// Leaf functions don't need a frame, even when built with frame
+    /// pointers, so this only requires half of the functions to have one.
+    #[inline]
+    pub fn is_likely(&self) -> Option<bool> {
+        if self.sampled == 0 {
+            None
+        } else {
+            Some(self.fraction() >= 0.5)
+        }
+    }
+}
+
+/// Check whether `code` starts with instructions that set up a frame pointer,
+/// or return `None` if this architecture isn't supported.
+#[allow(unused_variables)]
+fn has_frame_pointer_prologue(code: &[u8]) -> Option<bool> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        // Skip `endbr32` or `endbr64`.
+        let code = match *code {
+            [0xf3, 0x0f, 0x1e, 0xfa, ref rest @ ..] | [0xf3, 0x0f, 0x1e, 0xfb, ref rest @ ..] => rest,
+            _ => code,
+        };
+        // `push %ebp; mov %esp, %ebp` or `push %rbp; mov %rsp, %rbp`, in
+        // either of their encodings.
+        let code = match *code {
+            [0x55, 0x48, ref rest @ ..] if cfg!(target_arch = "x86_64") => rest,
+            [0x55, ref rest @ ..] if cfg!(target_arch = "x86") => rest,
+            _ => return Some(false),
+        };
+        Some(code.starts_with(&[0x89, 0xe5]) || code.starts_with(&[0x8b, 0xec]))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        // Look for `mov x29, sp` (an alias of `add x29, sp, #0`) or `add x29,
+        // sp, #imm` among the first few instructions, e.g. after `paciasp`,
+        // `bti c` and the `stp x29, x30, [sp, #-n]!` saving the frame record.
+        Some(code.chunks_exact(4).any(|insn| {
+            let insn = u32::from_le_bytes([insn[0], insn[1], insn[2], insn[3]]);
+            insn & 0xffc0_03ff == 0x9100_03fd
+        }))
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        None
+    }
+}
+
+/// Look up the dynamic symbol nearest to `address` with `dladdr`.
+///
+/// The name points into the string table of the shared library containing
+/// `address`, so it lives as long as that shared library stays loaded.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn dladdr_symbol<'a>(address: Avma) -> Option<Symbol<'a>> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(address.0 as *const libc::c_void, &mut info) } == 0 {
+        return None;
+    }
+    if info.dli_sname.is_null() || info.dli_saddr.is_null() {
+        return None;
+    }
+    let symbol = info.dli_saddr as usize;
+    Some(Symbol {
+        name: unsafe { CStr::from_ptr(info.dli_sname) },
+        address: Avma(symbol as *const u8),
+        offset: (address.0 as usize).wrapping_sub(symbol),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn dladdr_symbol<'a>(_address: Avma) -> Option<Symbol<'a>> {
+    None
+}
+
+/// Look up a symbol exported by the shared library with the given name,
+/// which is empty for the main executable on Linux, with `dlsym`.
+///
+/// The shared library is only looked up if it is already loaded, and
+/// `dlsym` also searches its dependencies, so check that the symbol is
+/// actually inside it.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn exported_symbol(library: &CStr, symbol: &[u8]) -> Option<Avma> {
+    let symbol = CStr::from_bytes_with_nul(symbol).ok()?;
+    let name = if library.to_bytes().is_empty() { ptr::null() } else { library.as_ptr() };
+    unsafe {
+        let handle = libc::dlopen(name, libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+        if handle.is_null() {
+            return None;
+        }
+        let address = libc::dlsym(handle, symbol.as_ptr());
+        libc::dlclose(handle);
+        if address.is_null() {
+            None
+        } else {
+            Some(Avma(address as *const u8))
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn exported_symbol(_library: &CStr, _symbol: &[u8]) -> Option<Avma> {
+    None
 }
 
 /// Control whether iteration over shared libraries should continue or stop.
@@ -324,6 +1278,14 @@ pub enum IterationControl {
     Break,
     /// Continue iteration.
     Continue,
+    /// Continue iteration, without doing any more work for the current shared
+    /// library.
+    ///
+    /// The backends don't parse anything until it is asked for, so for `each`
+    /// this is the same as `Continue`. Callers that do work of their own for
+    /// each shared library after the callback returns, like
+    /// `snapshot::each_selected`, skip it for this one.
+    Skip,
 }
 
 impl From<()> for IterationControl {
@@ -336,13 +1298,15 @@ impl From<()> for IterationControl {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use std::ffi::CString;
 
     #[test]
     fn panic_in_each() {
         use std::panic;
 
         match panic::catch_unwind(|| {
-            TargetSharedLibrary::each(|_| panic!("uh oh"));
+            TargetSharedLibrary::each::<_, ()>(|_| panic!("uh oh"));
         }) {
             Ok(()) => panic!("Expected a panic, but didn't get one"),
             Err(any) => {
@@ -354,4 +1318,326 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn shared_library_id_display() {
+        let id = SharedLibraryId::Uuid([
+            0xc1, 0xe4, 0xed, 0x0b, 0x9a, 0x49, 0x3b, 0xd4, 0x8d, 0xdc, 0xd5, 0xf4, 0xba, 0x2f,
+            0x39, 0xcd,
+        ]);
+        assert_eq!(id.to_string(), "c1e4ed0b-9a49-3bd4-8ddc-d5f4ba2f39cd");
+        assert_eq!(
+            format!("{:?}", id),
+            "Uuid(\"c1e4ed0b-9a49-3bd4-8ddc-d5f4ba2f39cd\")"
+        );
+    }
+
+    #[test]
+    fn gnu_build_id_display() {
+        let id = SharedLibraryId::GnuBuildId(vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0x01, 0x02, 0x03]);
+        assert_eq!(id.to_string(), "deadbeef00010203");
+        assert_eq!(format!("{:?}", id), "GnuBuildId(\"deadbeef00010203\")");
+
+        let long = SharedLibraryId::GnuBuildId(vec![0x11; 32]);
+        assert_eq!(long.to_string().len(), 64);
+    }
+
+    #[test]
+    fn shared_library_id_ord_and_hash() {
+        use std::collections::HashMap;
+
+        let low = SharedLibraryId::Uuid([0; 16]);
+        let high = SharedLibraryId::Uuid([0xff; 16]);
+        let mut ids = vec![high.clone(), low.clone()];
+        ids.sort();
+        assert_eq!(ids, vec![low.clone(), high.clone()]);
+
+        let mut map = HashMap::new();
+        map.insert(low.clone(), "low");
+        map.insert(high, "high");
+        assert_eq!(map.get(&low), Some(&"low"));
+    }
+
+    #[test]
+    fn load_slide_matches_bias() {
+        TargetSharedLibrary::each(|shlib| {
+            assert_eq!(shlib.load_slide(), shlib.virtual_memory_bias().0);
+        });
+    }
+
+    #[test]
+    fn segment_ranges() {
+        TargetSharedLibrary::each(|shlib| {
+            for seg in shlib.segments() {
+                let range = seg.avma_range(shlib.virtual_memory_bias());
+                assert_eq!(range.start, seg.actual_virtual_memory_address(shlib).0 as usize);
+                assert_eq!(range.end - range.start, seg.len());
+                assert_eq!(
+                    seg.svma_end().0 as usize - seg.stated_virtual_memory_address().0 as usize,
+                    seg.len()
+                );
+                if seg.len() > 0 {
+                    let last = Avma((range.end - 1) as *const u8);
+                    assert!(seg.contains_avma(shlib, last));
+                    assert!(!seg.contains_avma(shlib, Avma(range.end as *const u8)));
+                    assert!(!seg.contains_svma(seg.svma_end()));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn each_unsafe_signal_safe_matches_each() {
+        let mut expected = Vec::new();
+        TargetSharedLibrary::each(|shlib| {
+            expected.push(shlib.virtual_memory_bias());
+        });
+
+        let mut actual = Vec::new();
+        unsafe {
+            TargetSharedLibrary::each_unsafe_signal_safe(|shlib| {
+                actual.push(shlib.virtual_memory_bias());
+            });
+        }
+        // Other tests load and unload libraries concurrently, so only the
+        // main executable, which always comes first, is stable.
+        assert_eq!(actual.first(), expected.first());
+        assert_eq!(actual.is_empty(), !TARGET_SUPPORTED);
+    }
+
+    #[test]
+    fn copies_into_fixed_buffers() {
+        unsafe {
+            TargetSharedLibrary::each_unsafe_signal_safe(|shlib| {
+                let name = shlib.name().to_bytes();
+                let mut buf = [0; 4096];
+                assert_eq!(shlib.copy_name_into(&mut buf), name.len());
+                assert_eq!(&buf[..name.len()], name);
+                let mut short = [0; 3];
+                assert_eq!(shlib.copy_name_into(&mut short), name.len().min(3));
+                assert_eq!(&short[..name.len().min(3)], &name[..name.len().min(3)]);
+
+                let mut buf = [0; 64];
+                let n = shlib.copy_id_into(&mut buf);
+                match shlib.id() {
+                    Some(SharedLibraryId::Uuid(bytes)) => assert_eq!(&buf[..n], &bytes[..]),
+                    Some(SharedLibraryId::GnuBuildId(bytes)) => assert_eq!(&buf[..n], &bytes[..]),
+                    None => assert_eq!(n, 0),
+                }
+
+                let mut segments = [(Avma(ptr::null()), 0); 2];
+                let n = shlib.copy_segments_into(&mut segments);
+                assert_eq!(n, shlib.segments().len().min(2));
+                for (&(avma, len), seg) in segments[..n].iter().zip(shlib.segments()) {
+                    assert_eq!(avma, seg.actual_virtual_memory_address(shlib));
+                    assert_eq!(len, seg.len());
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn signal_safe_enumeration_takes_no_crate_locks() {
+        let each = TargetSharedLibrary::lock_hazards();
+        let signal_safe = TargetSharedLibrary::signal_safe_lock_hazards();
+        assert!(!signal_safe.crate_lock);
+        assert!(each.loader_lock || !signal_safe.loader_lock);
+        assert!(LockHazards::default().is_lock_free());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn symbol_at_finds_libc_function() {
+        let getpid: unsafe extern "C" fn() -> libc::pid_t = libc::getpid;
+        let address = Avma(getpid as usize as *const u8);
+
+        // `dladdr` must not be called from inside `each`, so look the symbol
+        // up in a map of the modules instead.
+        let map = lookup::ModuleMap::new();
+        let symbol = map.symbol_at(address).expect("should find getpid");
+        assert!(!symbol.name().to_bytes().is_empty());
+        assert_eq!(symbol.address().0 as usize + symbol.offset(), address.0 as usize);
+        assert_eq!(map.lookup(symbol.address()), map.lookup(address));
+
+        let local = 0u8;
+        let stack = Avma(&local as *const u8);
+        assert!(map.symbol_at(stack).is_none());
+    }
+
+    #[test]
+    fn main_executable_comes_first() {
+        let main = TargetSharedLibrary::main_executable();
+        assert_eq!(main.is_some(), TARGET_SUPPORTED);
+
+        if let Some(main) = main {
+            TargetSharedLibrary::each(|shlib| {
+                assert_eq!(main.name(), shlib.name());
+                assert_eq!(main.virtual_memory_bias(), shlib.virtual_memory_bias());
+                IterationControl::Break
+            });
+        }
+    }
+
+    #[test]
+    fn native_libraries_are_not_managed() {
+        // `dlopen` must not be called from inside `each`, so look the
+        // symbols up in a snapshot's modules instead.
+        let snapshot = snapshot::ProcessMap::new();
+        for module in snapshot.modules() {
+            assert_eq!(exported_symbol(module.name(), NATIVE_AOT_DEBUG_HEADER), None);
+            assert_eq!(module.managed_image_kind(), None);
+        }
+
+        let libc = snapshot
+            .modules()
+            .iter()
+            .find(|module| module.name().to_string_lossy().contains("libc.so"));
+        if let Some(libc) = libc {
+            assert!(exported_symbol(libc.name(), b"malloc\0").is_some());
+        }
+        assert_eq!(exported_symbol(CStr::from_bytes_with_nul(b"libnotloaded.so\0").unwrap(),
+                                   b"malloc\0"),
+                   None);
+    }
+
+    #[test]
+    fn has_wx_segments_matches_snapshot() {
+        TargetSharedLibrary::each(|shlib| {
+            let module = snapshot::ModuleInfo::new(shlib);
+            assert_eq!(shlib.has_wx_segments(), module.has_wx_segments());
+        });
+    }
+
+    #[test]
+    fn frame_pointer_hints_are_bounded() {
+        TargetSharedLibrary::each(|shlib| {
+            let hint = shlib.frame_pointer_hint();
+            assert!(hint.sampled <= FRAME_POINTER_SAMPLES);
+            assert!(hint.with_frame_pointer <= hint.sampled);
+            assert_eq!(hint.is_likely().is_some(), hint.sampled > 0);
+        });
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn x86_64_frame_pointer_prologues() {
+        // push %rbp; mov %rsp, %rbp
+        assert_eq!(has_frame_pointer_prologue(&[0x55, 0x48, 0x89, 0xe5, 0x90]), Some(true));
+        // endbr64; push %rbp; mov %rsp, %rbp
+        let prologue = [0xf3, 0x0f, 0x1e, 0xfa, 0x55, 0x48, 0x89, 0xe5];
+        assert_eq!(has_frame_pointer_prologue(&prologue), Some(true));
+        // push %rbx; sub $0x10, %rsp
+        let prologue = [0x53, 0x48, 0x83, 0xec, 0x10];
+        assert_eq!(has_frame_pointer_prologue(&prologue), Some(false));
+        assert_eq!(has_frame_pointer_prologue(&[0x55]), Some(false));
+    }
+
+    #[test]
+    fn find_map_each_stops_at_first_match() {
+        let mut visited = 0;
+        let found = TargetSharedLibrary::find_map_each(|shlib| {
+            visited += 1;
+            if visited == 2 {
+                Some(shlib.name().to_owned())
+            } else {
+                None
+            }
+        });
+
+        if TARGET_SUPPORTED {
+            assert!(found.is_some());
+            assert_eq!(visited, 2);
+        } else {
+            assert!(found.is_none());
+        }
+    }
+
+    #[test]
+    fn segment_helpers_match_backend_segments() {
+        TargetSharedLibrary::each(|shlib| {
+            let bias = shlib.virtual_memory_bias();
+            for seg in shlib.segments().filter(|seg| seg.file_size() > 0 && seg.len() > 0) {
+                let svma = seg.stated_virtual_memory_address();
+                let avma = seg.actual_virtual_memory_address(shlib);
+                assert_eq!(avma, apply_bias(svma, bias));
+                assert_eq!(shlib.avma_to_svma(avma), svma);
+                assert!(seg.contains_avma(shlib, avma));
+                assert_eq!(seg.svma_to_file_offset(svma), Some(seg.file_offset()));
+                assert_eq!(seg.file_offset_to_svma(seg.file_offset()), Some(svma));
+            }
+        });
+    }
+
+    /// A synthetic segment that fits in the address space, with a bias that
+    /// keeps it there, and an address somewhere in or around it.
+    fn layouts() -> impl Strategy<Value = (usize, usize, u64, u64, isize, usize)> {
+        (0..usize::MAX / 4, 0..usize::MAX / 4, 0..u64::MAX / 2)
+            .prop_flat_map(|(start, len, file_offset)| {
+                let file_size = 0..=len as u64;
+                let bias = -(start as isize)..(usize::MAX / 4) as isize;
+                let delta = 0..len.max(1) * 2;
+                (Just(start), Just(len), Just(file_offset), file_size, bias, delta)
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn bias_roundtrips(address: usize, bias: isize) {
+            let svma = Svma(address as *const u8);
+            prop_assert_eq!(remove_bias(apply_bias(svma, Bias(bias)), Bias(bias)), svma);
+            let avma = Avma(address as *const u8);
+            prop_assert_eq!(apply_bias(remove_bias(avma, Bias(bias)), Bias(bias)), avma);
+        }
+
+        #[test]
+        fn containment_survives_bias(layout in layouts()) {
+            let (start, len, file_offset, file_size, bias, delta) = layout;
+            let seg = snapshot::SegmentInfo::new(CString::default(),
+                                                 Svma(start as *const u8),
+                                                 len,
+                                                 file_offset,
+                                                 file_size,
+                                                 SegmentPermissions::default());
+            let module = snapshot::ModuleInfo::from_parts(CString::default(),
+                                                          vec![seg.clone()],
+                                                          snapshot::Source::MapsParse)
+                .with_identity(None, Bias(bias));
+
+            let address = Svma((start + delta) as *const u8);
+            let avma = module.actual_virtual_memory_address(&seg).0 as usize;
+            prop_assert_eq!(avma, (start as isize + bias) as usize);
+            let avma_range = avma..avma + len;
+            let biased = apply_bias(address, Bias(bias)).0 as usize;
+            prop_assert_eq!(avma_range.contains(&biased), delta < len);
+            prop_assert_eq!(remove_bias(Avma(biased as *const u8), Bias(bias)), address);
+        }
+
+        #[test]
+        fn file_offsets_roundtrip(layout in layouts()) {
+            let (start, len, file_offset, file_size, _, delta) = layout;
+            let seg = snapshot::SegmentInfo::new(CString::default(),
+                                                 Svma(start as *const u8),
+                                                 len,
+                                                 file_offset,
+                                                 file_size,
+                                                 SegmentPermissions::default());
+
+            let address = Svma((start + delta) as *const u8);
+            let offset = seg.svma_to_file_offset(address);
+            if (delta as u64) < file_size {
+                prop_assert_eq!(offset, Some(file_offset + delta as u64));
+                prop_assert_eq!(seg.file_offset_to_svma(file_offset + delta as u64), Some(address));
+            } else {
+                prop_assert_eq!(offset, None);
+                prop_assert_eq!(seg.file_offset_to_svma(file_offset + delta as u64), None);
+            }
+
+            if start > 0 {
+                prop_assert_eq!(seg.svma_to_file_offset(Svma((start - 1) as *const u8)), None);
+            }
+            if file_offset > 0 {
+                prop_assert_eq!(seg.file_offset_to_svma(file_offset - 1), None);
+            }
+        }
+    }
 }
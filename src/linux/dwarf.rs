@@ -0,0 +1,479 @@
+//! Just enough DWARF parsing to read attributes of the first compilation
+//! unit.
+
+use std::cmp;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+const DW_AT_COMP_DIR: u64 = 0x1b;
+
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_BLOCK2: u64 = 0x03;
+const DW_FORM_BLOCK4: u64 = 0x04;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_FLAG: u64 = 0x0c;
+const DW_FORM_SDATA: u64 = 0x0d;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_REF_ADDR: u64 = 0x10;
+const DW_FORM_REF1: u64 = 0x11;
+const DW_FORM_REF2: u64 = 0x12;
+const DW_FORM_REF4: u64 = 0x13;
+const DW_FORM_REF8: u64 = 0x14;
+const DW_FORM_REF_UDATA: u64 = 0x15;
+const DW_FORM_INDIRECT: u64 = 0x16;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+const DW_FORM_EXPRLOC: u64 = 0x18;
+const DW_FORM_FLAG_PRESENT: u64 = 0x19;
+const DW_FORM_STRX: u64 = 0x1a;
+const DW_FORM_ADDRX: u64 = 0x1b;
+const DW_FORM_REF_SUP4: u64 = 0x1c;
+const DW_FORM_STRP_SUP: u64 = 0x1d;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_REF_SIG8: u64 = 0x20;
+const DW_FORM_IMPLICIT_CONST: u64 = 0x21;
+const DW_FORM_LOCLISTX: u64 = 0x22;
+const DW_FORM_RNGLISTX: u64 = 0x23;
+const DW_FORM_REF_SUP8: u64 = 0x24;
+const DW_FORM_STRX1: u64 = 0x25;
+const DW_FORM_STRX2: u64 = 0x26;
+const DW_FORM_STRX3: u64 = 0x27;
+const DW_FORM_STRX4: u64 = 0x28;
+const DW_FORM_ADDRX1: u64 = 0x29;
+const DW_FORM_ADDRX2: u64 = 0x2a;
+const DW_FORM_ADDRX3: u64 = 0x2b;
+const DW_FORM_ADDRX4: u64 = 0x2c;
+const DW_FORM_GNU_REF_ALT: u64 = 0x1f20;
+const DW_FORM_GNU_STRP_ALT: u64 = 0x1f21;
+
+const DW_UT_COMPILE: u8 = 0x01;
+const DW_UT_SKELETON: u8 = 0x04;
+
+/// The DWARF sections that attributes of a compilation unit are read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Info,
+    Abbrev,
+    Str,
+    LineStr,
+}
+
+/// Somewhere to read DWARF sections from.
+///
+/// Only the parts that are needed are read, rather than whole sections,
+/// which can be hundreds of megabytes in large binaries.
+pub trait Sections {
+    /// Read the bytes at `offset` in `section` into `buf`, and return how
+    /// many were read, which is fewer than `buf.len()` only at the end of the
+    /// section. Returns `None` if the section is missing or can't be read.
+    fn read(&self, section: Section, offset: u64, buf: &mut [u8]) -> Option<usize>;
+}
+
+/// How many bytes to read from a section at a time.
+const CHUNK: usize = 256;
+
+/// The longest string to read, which is generous for a path.
+const MAX_STRING: usize = 4096;
+
+/// A cursor over a DWARF section in the target's byte order, which reads the
+/// section a chunk at a time as it goes.
+struct Reader<'a, S: 'a + ?Sized> {
+    sections: &'a S,
+    section: Section,
+    /// The section offset of the first byte in `buf`.
+    start: u64,
+    buf: Vec<u8>,
+    /// The section offset of the next byte to read.
+    offset: u64,
+}
+
+impl<'a, S: Sections + ?Sized> Reader<'a, S> {
+    fn new(sections: &'a S, section: Section, offset: u64) -> Self {
+        Reader {
+            sections,
+            section,
+            start: offset,
+            buf: Vec::new(),
+            offset,
+        }
+    }
+
+    /// Make sure the next `len` bytes are in `buf`, and return the index of
+    /// the first of them.
+    fn fill(&mut self, len: usize) -> Option<usize> {
+        if self.offset > self.start + self.buf.len() as u64 {
+            // Values were skipped past the end of what has been read.
+            self.start = self.offset;
+            self.buf.clear();
+        }
+        let index = (self.offset - self.start) as usize;
+        let missing = (index + len).saturating_sub(self.buf.len());
+        if missing > 0 {
+            let have = self.buf.len();
+            let want = cmp::max(missing, CHUNK);
+            self.buf.resize(have + want, 0);
+            let read = self
+                .sections
+                .read(self.section, self.start + have as u64, &mut self.buf[have..])?;
+            self.buf.truncate(have + read);
+            if read < missing {
+                return None;
+            }
+        }
+        Some(index)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let index = self.fill(len)?;
+        self.offset += len as u64;
+        Some(&self.buf[index..index + len])
+    }
+
+    fn skip(&mut self, len: u64) -> Option<()> {
+        self.offset = self.offset.checked_add(len)?;
+        Some(())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|bytes| u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_ne_bytes(value))
+    }
+
+    /// Read a section offset, which is 4 or 8 bytes long depending on the
+    /// unit's format.
+    fn offset(&mut self, offset_size: usize) -> Option<u64> {
+        if offset_size == 8 {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn cstr(&mut self) -> Option<Vec<u8>> {
+        let mut len = 0;
+        loop {
+            let index = self.fill(len + 1)?;
+            if let Some(nul) = self.buf[index + len..].iter().position(|&b| b == 0) {
+                let string = self.buf[index..index + len + nul].to_vec();
+                self.offset += (len + nul + 1) as u64;
+                return Some(string);
+            }
+            len = self.buf.len() - index;
+            if len >= MAX_STRING {
+                return None;
+            }
+        }
+    }
+}
+
+/// Read the NUL-terminated string at `offset` in a string section.
+fn string_at<S: Sections + ?Sized>(sections: &S, section: Section, offset: u64) -> Option<Vec<u8>> {
+    Reader::new(sections, section, offset).cstr()
+}
+
+/// Find the attribute specifications of the abbreviation with the given
+/// code, in the table at `offset`.
+fn find_abbreviation<S: Sections + ?Sized>(
+    sections: &S,
+    offset: u64,
+    code: u64,
+) -> Option<Vec<(u64, u64)>> {
+    let mut reader = Reader::new(sections, Section::Abbrev, offset);
+    loop {
+        let this_code = reader.uleb128()?;
+        if this_code == 0 {
+            return None;
+        }
+        let _tag = reader.uleb128()?;
+        let _has_children = reader.u8()?;
+
+        let mut specs = Vec::new();
+        loop {
+            let (name, form) = (reader.uleb128()?, reader.uleb128()?);
+            if form == DW_FORM_IMPLICIT_CONST {
+                // The value is a signed LEB128 stored here rather than in
+                // the entry; we only need to know that it is there.
+                reader.uleb128()?;
+            }
+            if (name, form) == (0, 0) {
+                break;
+            }
+            specs.push((name, form));
+        }
+        if this_code == code {
+            return Some(specs);
+        }
+    }
+}
+
+/// Skip over an attribute value of the given form.
+fn skip_value<S: Sections + ?Sized>(
+    reader: &mut Reader<S>,
+    form: u64,
+    offset_size: usize,
+    address_size: u8,
+) -> Option<()> {
+    match form {
+        DW_FORM_FLAG_PRESENT | DW_FORM_IMPLICIT_CONST => Some(()),
+        DW_FORM_DATA1 | DW_FORM_REF1 | DW_FORM_FLAG | DW_FORM_STRX1 | DW_FORM_ADDRX1 => {
+            reader.skip(1)
+        }
+        DW_FORM_DATA2 | DW_FORM_REF2 | DW_FORM_STRX2 | DW_FORM_ADDRX2 => reader.skip(2),
+        DW_FORM_STRX3 | DW_FORM_ADDRX3 => reader.skip(3),
+        DW_FORM_DATA4 | DW_FORM_REF4 | DW_FORM_REF_SUP4 | DW_FORM_STRX4 | DW_FORM_ADDRX4 => {
+            reader.skip(4)
+        }
+        DW_FORM_DATA8 | DW_FORM_REF8 | DW_FORM_REF_SIG8 | DW_FORM_REF_SUP8 => reader.skip(8),
+        DW_FORM_DATA16 => reader.skip(16),
+        DW_FORM_ADDR => reader.skip(u64::from(address_size)),
+        DW_FORM_STRP | DW_FORM_LINE_STRP | DW_FORM_REF_ADDR | DW_FORM_SEC_OFFSET
+        | DW_FORM_STRP_SUP | DW_FORM_GNU_REF_ALT | DW_FORM_GNU_STRP_ALT => {
+            reader.skip(offset_size as u64)
+        }
+        DW_FORM_SDATA | DW_FORM_UDATA | DW_FORM_REF_UDATA | DW_FORM_STRX | DW_FORM_ADDRX
+        | DW_FORM_LOCLISTX | DW_FORM_RNGLISTX => reader.uleb128().map(|_| ()),
+        DW_FORM_STRING => reader.cstr().map(|_| ()),
+        DW_FORM_BLOCK1 => {
+            let len = reader.u8()?;
+            reader.skip(u64::from(len))
+        }
+        DW_FORM_BLOCK2 => {
+            let len = reader.u16()?;
+            reader.skip(u64::from(len))
+        }
+        DW_FORM_BLOCK4 => {
+            let len = reader.u32()?;
+            reader.skip(u64::from(len))
+        }
+        DW_FORM_BLOCK | DW_FORM_EXPRLOC => {
+            let len = reader.uleb128()?;
+            reader.skip(len)
+        }
+        DW_FORM_INDIRECT => {
+            let form = reader.uleb128()?;
+            skip_value(reader, form, offset_size, address_size)
+        }
+        _ => None,
+    }
+}
+
+/// Read the `DW_AT_comp_dir` attribute of the first compilation unit.
+///
+/// Strings that are only reachable through `.debug_str_offsets` or a
+/// supplementary object file aren't supported.
+pub fn compilation_directory<S: Sections + ?Sized>(sections: &S) -> Option<PathBuf> {
+    let mut reader = Reader::new(sections, Section::Info, 0);
+    let offset_size = match reader.u32()? {
+        0xffff_ffff => {
+            reader.u64()?;
+            8
+        }
+        _ => 4,
+    };
+
+    let version = reader.u16()?;
+    let (abbrev_offset, address_size) = match version {
+        2..=4 => (reader.offset(offset_size)?, reader.u8()?),
+        5 => {
+            let unit_type = reader.u8()?;
+            let address_size = reader.u8()?;
+            let abbrev_offset = reader.offset(offset_size)?;
+            match unit_type {
+                DW_UT_COMPILE => {}
+                DW_UT_SKELETON => {
+                    reader.u64()?;
+                }
+                _ => return None,
+            }
+            (abbrev_offset, address_size)
+        }
+        _ => return None,
+    };
+
+    let code = reader.uleb128()?;
+    for (name, mut form) in find_abbreviation(sections, abbrev_offset, code)? {
+        if form == DW_FORM_INDIRECT {
+            form = reader.uleb128()?;
+        }
+        if name != DW_AT_COMP_DIR {
+            skip_value(&mut reader, form, offset_size, address_size)?;
+            continue;
+        }
+
+        let dir = match form {
+            DW_FORM_STRING => reader.cstr()?,
+            DW_FORM_STRP => string_at(sections, Section::Str, reader.offset(offset_size)?)?,
+            DW_FORM_LINE_STRP => {
+                string_at(sections, Section::LineStr, reader.offset(offset_size)?)?
+            }
+            _ => return None,
+        };
+        return Some(PathBuf::from(OsStr::from_bytes(&dir)));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sections held in memory, which count how much of them is read.
+    #[derive(Debug, Default)]
+    struct Buffers {
+        info: Vec<u8>,
+        abbrev: Vec<u8>,
+        str: Vec<u8>,
+        line_str: Vec<u8>,
+        read: ::std::cell::Cell<usize>,
+    }
+
+    impl Sections for Buffers {
+        fn read(&self, section: Section, offset: u64, buf: &mut [u8]) -> Option<usize> {
+            let data = match section {
+                Section::Info => &self.info,
+                Section::Abbrev => &self.abbrev,
+                Section::Str => &self.str,
+                Section::LineStr => &self.line_str,
+            };
+            let data = data.get(offset as usize..).unwrap_or(&[]);
+            let len = cmp::min(data.len(), buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            self.read.set(self.read.get() + len);
+            Some(len)
+        }
+    }
+
+    fn abbreviations() -> Vec<u8> {
+        vec![
+            1, 0x11, 1, // Abbreviation 1: DW_TAG_compile_unit, has children.
+            0x25, 0x0e, // DW_AT_producer, DW_FORM_strp
+            0x13, 0x05, // DW_AT_language, DW_FORM_data2
+            0x03, 0x08, // DW_AT_name, DW_FORM_string
+            0x1b, 0x1f, // DW_AT_comp_dir, DW_FORM_line_strp
+            0, 0,
+            0,
+        ]
+    }
+
+    #[test]
+    fn dwarf5_line_strp() {
+        let mut info = Vec::new();
+        info.extend_from_slice(&0u32.to_ne_bytes()); // Unit length, unchecked.
+        info.extend_from_slice(&5u16.to_ne_bytes());
+        info.extend_from_slice(&[DW_UT_COMPILE, 8]);
+        info.extend_from_slice(&0u32.to_ne_bytes()); // Abbreviation offset.
+        info.push(1);
+        info.extend_from_slice(&0u32.to_ne_bytes()); // Producer.
+        info.extend_from_slice(&0x1cu16.to_ne_bytes()); // Rust.
+        info.extend_from_slice(b"src/lib.rs\0");
+        info.extend_from_slice(&4u32.to_ne_bytes()); // Compilation directory.
+
+        let sections = Buffers {
+            info,
+            abbrev: abbreviations(),
+            str: b"rustc\0".to_vec(),
+            line_str: b"abc\0/home/user/project\0".to_vec(),
+            ..Buffers::default()
+        };
+        assert_eq!(compilation_directory(&sections),
+                   Some(PathBuf::from("/home/user/project")));
+    }
+
+    #[test]
+    fn dwarf4_inline_string() {
+        let abbrev = vec![
+            0, // An empty table before ours.
+            1, 0x11, 0,
+            0x10, 0x17, // DW_AT_stmt_list, DW_FORM_sec_offset
+            0x1b, 0x08, // DW_AT_comp_dir, DW_FORM_string
+            0, 0,
+            0,
+        ];
+        let mut info = Vec::new();
+        info.extend_from_slice(&0u32.to_ne_bytes());
+        info.extend_from_slice(&4u16.to_ne_bytes());
+        info.extend_from_slice(&1u32.to_ne_bytes());
+        info.push(8);
+        info.push(1);
+        info.extend_from_slice(&0u32.to_ne_bytes());
+        info.extend_from_slice(b"/build\0");
+
+        let sections = Buffers { info, abbrev, ..Buffers::default() };
+        assert_eq!(compilation_directory(&sections), Some(PathBuf::from("/build")));
+    }
+
+    #[test]
+    fn only_the_needed_parts_are_read() {
+        let abbrev = vec![
+            1, 0x11, 1,
+            0x0a, 0x09, // DW_AT_location, DW_FORM_block
+            0x1b, 0x0e, // DW_AT_comp_dir, DW_FORM_strp
+            0, 0,
+            0,
+        ];
+        let mut info = Vec::new();
+        info.extend_from_slice(&0u32.to_ne_bytes());
+        info.extend_from_slice(&4u16.to_ne_bytes());
+        info.extend_from_slice(&0u32.to_ne_bytes());
+        info.push(8);
+        info.push(1);
+        info.extend_from_slice(&[0x88, 0x27]); // A 5000 byte block.
+        info.extend_from_slice(&[0xff; 5000]);
+        info.extend_from_slice(&0x10_0000u32.to_ne_bytes());
+        info.resize(0x20_0000, 0);
+
+        let mut str = vec![b'x'; 0x20_0000];
+        str[0x10_0000..0x10_0007].copy_from_slice(b"/build\0");
+
+        let sections = Buffers { info, abbrev, str, ..Buffers::default() };
+        assert_eq!(compilation_directory(&sections), Some(PathBuf::from("/build")));
+        assert!(sections.read.get() <= 4 * CHUNK, "read {} bytes", sections.read.get());
+    }
+
+    #[test]
+    fn unsupported_and_truncated_units() {
+        let mut info = Vec::new();
+        info.extend_from_slice(&0u32.to_ne_bytes());
+        info.extend_from_slice(&6u16.to_ne_bytes());
+        let sections = Buffers { info, abbrev: abbreviations(), ..Buffers::default() };
+        assert_eq!(compilation_directory(&sections), None);
+        assert_eq!(compilation_directory(&Buffers::default()), None);
+    }
+}
@@ -1,19 +1,32 @@
 //! Linux-specific implementation of the `SharedLibrary` trait.
 
-use super::{Bias, IterationControl, Svma, SharedLibraryId};
+use super::{copy_truncated, dladdr_symbol, Avma, Bias, DebugInfoAvailability, IterationControl,
+            LockHazards, RelocationCounts, SegmentPermissions, Svma, SharedLibraryId, SharedLibraryVersion,
+            SectionInfo, Symbol, VolumeKind};
 use super::Segment as SegmentTrait;
 use super::SharedLibrary as SharedLibraryTrait;
+use super::trace;
 
 use std::any::Any;
-use std::ffi::CStr;
+use std::cmp;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString, OsStr};
 use std::fmt;
-use std::isize;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::panic;
+use std::path::{Path, PathBuf};
 use std::slice;
+use std::str;
 
 use libc;
 
+mod dwarf;
+
 cfg_if! {
     if #[cfg(target_pointer_width = "32")] {
         type Phdr = libc::Elf32_Phdr;
@@ -24,6 +37,431 @@ cfg_if! {
     }
 }
 
+/// An entry in an ELF `.dynamic` section.
+///
+/// `Elf32_Dyn` and `Elf64_Dyn` are both a pointer-sized tag followed by a
+/// pointer-sized value.
+#[repr(C)]
+struct Dyn {
+    d_tag: isize,
+    d_val: usize,
+}
+
+/// An ELF file header.
+///
+/// `Elf32_Ehdr` and `Elf64_Ehdr` only differ in the size of their address and
+/// offset fields, which are pointer-sized.
+#[repr(C)]
+#[allow(dead_code)]
+struct Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: usize,
+    e_phoff: usize,
+    e_shoff: usize,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+/// An ELF section header.
+///
+/// Like `Ehdr`, `Elf32_Shdr` and `Elf64_Shdr` only differ in the size of
+/// their address, offset and size fields.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+struct Shdr {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: usize,
+    sh_addr: usize,
+    sh_offset: usize,
+    sh_size: usize,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: usize,
+    sh_entsize: usize,
+}
+
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: usize = 0x2;
+const SHF_COMPRESSED: usize = 0x800;
+
+const DT_NULL: isize = 0;
+const DT_PLTRELSZ: isize = 2;
+const DT_STRTAB: isize = 5;
+const DT_RELA: isize = 7;
+const DT_RELASZ: isize = 8;
+const DT_RELAENT: isize = 9;
+const DT_INIT: isize = 12;
+const DT_FINI: isize = 13;
+const DT_SONAME: isize = 14;
+const DT_REL: isize = 17;
+const DT_RELSZ: isize = 18;
+const DT_RELENT: isize = 19;
+const DT_PLTREL: isize = 20;
+const DT_JMPREL: isize = 23;
+const DT_INIT_ARRAY: isize = 25;
+const DT_FINI_ARRAY: isize = 26;
+const DT_INIT_ARRAYSZ: isize = 27;
+const DT_FINI_ARRAYSZ: isize = 28;
+const DT_PREINIT_ARRAY: isize = 32;
+const DT_PREINIT_ARRAYSZ: isize = 33;
+const DT_RELRSZ: isize = 35;
+const DT_RELR: isize = 36;
+const DT_RELACOUNT: isize = 0x6fff_fff9;
+const DT_RELCOUNT: isize = 0x6fff_fffa;
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_DATAREL: u8 = 0x30;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// The `statfs` magic numbers of network file systems.
+const NETWORK_FILESYSTEMS: [u32; 9] = [
+    0x0000_6969, // NFS
+    0x0000_517b, // SMB
+    0xff53_4d42, // CIFS
+    0xfe53_4d42, // SMB2
+    0x7375_7245, // Coda
+    0x5346_414f, // AFS
+    0x6b41_4653, // kAFS
+    0x0102_1997, // 9P
+    0x00c3_6400, // Ceph
+];
+
+const NT_GNU_BUILD_ID: u32 = 3;
+const NT_GO_BUILD_ID: u32 = 4;
+
+/// The type of the GNU note holding program properties, such as the x86
+/// control-flow protection a binary was built with.
+pub const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
+/// The type of annobin's build attribute notes that apply to the code from
+/// an address onwards.
+pub const NT_GNU_BUILD_ATTRIBUTE_OPEN: u32 = 0x100;
+/// The type of annobin's build attribute notes that apply to one function.
+pub const NT_GNU_BUILD_ATTRIBUTE_FUNC: u32 = 0x101;
+
+/// A note from one of a shared library's `PT_NOTE` segments.
+#[derive(Clone, Copy, Debug)]
+pub struct Note<'a> {
+    name: &'a [u8],
+    note_type: u32,
+    desc: &'a [u8],
+}
+
+impl<'a> Note<'a> {
+    /// Get the name of this note's owner, such as `GNU`, without the trailing
+    /// nul bytes. Go pads its `Go` name to four bytes with nuls.
+    #[inline]
+    pub fn name(&self) -> &'a [u8] {
+        self.name
+    }
+
+    /// Get this note's type, whose meaning depends on the owner.
+    #[inline]
+    pub fn note_type(&self) -> u32 {
+        self.note_type
+    }
+
+    /// Get this note's descriptor: its payload, exactly as it is in memory.
+    #[inline]
+    pub fn desc(&self) -> &'a [u8] {
+        self.desc
+    }
+
+    /// Iterate over the program properties in this note, if it is a GNU
+    /// `NT_GNU_PROPERTY_TYPE_0` note, and over nothing otherwise.
+    pub fn gnu_properties(&self) -> GnuPropertyIter<'a> {
+        let is_properties = self.name == b"GNU" && self.note_type == NT_GNU_PROPERTY_TYPE_0;
+        GnuPropertyIter {
+            data: if is_properties { self.desc } else { &[] },
+        }
+    }
+
+    /// Parse this note as one of the build attributes annobin records, such
+    /// as the compiler flags a binary was built with, if it is one.
+    pub fn build_attribute(&self) -> Option<BuildAttribute<'a>> {
+        if self.note_type != NT_GNU_BUILD_ATTRIBUTE_OPEN
+            && self.note_type != NT_GNU_BUILD_ATTRIBUTE_FUNC
+        {
+            return None;
+        }
+        parse_build_attribute(self.name)
+    }
+}
+
+/// A program property from a GNU `NT_GNU_PROPERTY_TYPE_0` note.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GnuProperty<'a> {
+    property_type: u32,
+    data: &'a [u8],
+}
+
+impl<'a> GnuProperty<'a> {
+    /// Get the property's type, such as `GNU_PROPERTY_X86_FEATURE_1_AND`
+    /// (`0xc0000002`), whose meaning depends on the architecture for types
+    /// from `0xc0000000` on.
+    #[inline]
+    pub fn property_type(&self) -> u32 {
+        self.property_type
+    }
+
+    /// Get the property's data, exactly as it is in memory.
+    #[inline]
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// An iterator over the program properties of a note.
+#[derive(Clone, Debug)]
+pub struct GnuPropertyIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for GnuPropertyIter<'a> {
+    type Item = GnuProperty<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Each property is padded to the size of an address.
+        let align = mem::size_of::<usize>();
+        let data = self.data;
+        if data.len() < 8 {
+            self.data = &[];
+            return None;
+        }
+        let word = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&data[i * 4..i * 4 + 4]);
+            u32::from_ne_bytes(bytes)
+        };
+        let (property_type, size) = (word(0), word(1) as usize);
+        let end = match 8usize.checked_add(size) {
+            Some(end) if end <= data.len() => end,
+            _ => {
+                // Malformed; give up on the rest of the note.
+                self.data = &[];
+                return None;
+            }
+        };
+
+        let next = end.checked_add(align - 1).map_or(data.len(), |next| next & !(align - 1));
+        self.data = &data[next.min(data.len())..];
+        Some(GnuProperty {
+            property_type,
+            data: &data[8..end],
+        })
+    }
+}
+
+/// A build attribute annobin recorded in a note.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildAttribute<'a> {
+    kind: BuildAttributeKind<'a>,
+    value: BuildAttributeValue<'a>,
+}
+
+impl<'a> BuildAttribute<'a> {
+    /// Get which attribute this is.
+    #[inline]
+    pub fn kind(&self) -> BuildAttributeKind<'a> {
+        self.kind
+    }
+
+    /// Get the attribute's value.
+    #[inline]
+    pub fn value(&self) -> BuildAttributeValue<'a> {
+        self.value
+    }
+}
+
+/// Which build attribute a note records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildAttributeKind<'a> {
+    /// The version of the note format and of the tool that produced it.
+    Version,
+    /// The `-fstack-protector` level.
+    StackProtector,
+    /// Whether the binary was linked with RELRO.
+    Relro,
+    /// The maximum stack usage of the code.
+    StackSize,
+    /// The compiler and its version.
+    Tool,
+    /// The ABI, such as the `-m` options that change it.
+    Abi,
+    /// The `-fpic` or `-fpie` level.
+    Pic,
+    /// Whether `-fshort-enums` was used.
+    ShortEnum,
+    /// Any other attribute, by name, such as `GOW` for the optimization and
+    /// warning options and `FORTIFY` for `_FORTIFY_SOURCE`.
+    Other(&'a [u8]),
+}
+
+/// The value of a build attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildAttributeValue<'a> {
+    /// A number.
+    Number(u64),
+    /// A string, without its nul terminator.
+    String(&'a [u8]),
+    /// A flag.
+    Bool(bool),
+}
+
+/// Parse the name of an annobin note, such as `GA$\x05gcc 11.2.1`: `GA`, a
+/// character telling the type of the value, the attribute either as a byte
+/// below 0x20 or as a nul-terminated name, and the value.
+fn parse_build_attribute(name: &[u8]) -> Option<BuildAttribute<'_>> {
+    let rest = name.strip_prefix(b"GA")?;
+    let (&value_type, rest) = rest.split_first()?;
+    let (&id, _) = rest.split_first()?;
+    let (kind, value) = if id < 0x20 {
+        let kind = match id {
+            1 => BuildAttributeKind::Version,
+            2 => BuildAttributeKind::StackProtector,
+            3 => BuildAttributeKind::Relro,
+            4 => BuildAttributeKind::StackSize,
+            5 => BuildAttributeKind::Tool,
+            6 => BuildAttributeKind::Abi,
+            7 => BuildAttributeKind::Pic,
+            8 => BuildAttributeKind::ShortEnum,
+            _ => return None,
+        };
+        (kind, &rest[1..])
+    } else {
+        let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        (BuildAttributeKind::Other(&rest[..end]), rest.get(end + 1..).unwrap_or(&[]))
+    };
+
+    let value = match value_type {
+        // Numbers are little endian, and as short as they can be.
+        b'*' if value.len() <= 8 => BuildAttributeValue::Number(
+            value.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b)),
+        ),
+        b'$' => {
+            let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+            BuildAttributeValue::String(&value[..end])
+        }
+        b'+' => BuildAttributeValue::Bool(true),
+        b'!' => BuildAttributeValue::Bool(false),
+        _ => return None,
+    };
+    Some(BuildAttribute { kind, value })
+}
+
+/// Notes read from a shared library's file, as returned by
+/// `SharedLibrary::build_attribute_notes`.
+#[derive(Clone, Debug)]
+pub struct FileNotes {
+    data: Vec<u8>,
+}
+
+impl FileNotes {
+    /// Iterate over the notes.
+    #[inline]
+    pub fn iter(&self) -> NoteIter<'_> {
+        NoteIter {
+            addr: 0,
+            headers: [].iter(),
+            data: &self.data,
+            align: 4,
+        }
+    }
+}
+
+/// An iterator over the notes in a shared library's `PT_NOTE` segments.
+#[derive(Debug)]
+pub struct NoteIter<'a> {
+    addr: usize,
+    headers: slice::Iter<'a, Phdr>,
+    data: &'a [u8],
+    align: usize,
+}
+
+impl<'a> Iterator for NoteIter<'a> {
+    type Item = Note<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(note) = self.next_in_segment() {
+                return Some(note);
+            }
+
+            let phdr = self.headers.find(|phdr| phdr.p_type == libc::PT_NOTE)?;
+            let start = self.addr.wrapping_add(phdr.p_vaddr as usize) as *const u8;
+            self.data = unsafe { slice::from_raw_parts(start, phdr.p_memsz as usize) };
+            // Notes are 4-byte aligned, except in segments whose alignment
+            // says otherwise (such as `.note.gnu.property` on 64-bit).
+            self.align = if phdr.p_align == 8 { 8 } else { 4 };
+        }
+    }
+}
+
+impl<'a> NoteIter<'a> {
+    fn next_in_segment(&mut self) -> Option<Note<'a>> {
+        const HEADER_SIZE: usize = 12;
+
+        let data = self.data;
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let word = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&data[i * 4..i * 4 + 4]);
+            u32::from_ne_bytes(bytes) as usize
+        };
+        let (name_size, desc_size, note_type) = (word(0), word(1), word(2) as u32);
+
+        // The sizes come from the note header, so a malformed note can make
+        // these overflow on 32-bit targets.
+        let align = |n: usize| n.checked_add(self.align - 1).map(|n| n & !(self.align - 1));
+        let name_start = HEADER_SIZE;
+        let name_end = name_start.checked_add(name_size);
+        let desc_start = name_end.and_then(align);
+        let desc_end = desc_start.and_then(|start| start.checked_add(desc_size));
+        let (name_end, desc_start, desc_end) = match (name_end, desc_start, desc_end) {
+            (Some(name_end), Some(desc_start), Some(desc_end)) if desc_end <= data.len() => {
+                (name_end, desc_start, desc_end)
+            }
+            _ => {
+                // Malformed; give up on the rest of this segment.
+                self.data = &[];
+                return None;
+            }
+        };
+
+        let mut name = &data[name_start..name_end];
+        while name.last() == Some(&0) {
+            name = &name[..name.len() - 1];
+        }
+        let desc = &data[desc_start..desc_end];
+
+        let next = align(desc_end).map_or(data.len(), |next| next.min(data.len()));
+        self.data = &data[next..];
+        Some(Note { name, note_type, desc })
+    }
+}
+
 /// A mapped segment in an ELF file.
 #[derive(Debug)]
 pub struct Segment<'a> {
@@ -68,6 +506,30 @@ impl<'a> SegmentTrait for Segment<'a> {
             (*self.phdr).p_memsz as _
         }
     }
+
+    #[inline]
+    fn file_size(&self) -> u64 {
+        unsafe {
+            (*self.phdr).p_filesz as _
+        }
+    }
+
+    #[inline]
+    fn file_offset(&self) -> u64 {
+        unsafe {
+            (*self.phdr).p_offset as _
+        }
+    }
+
+    #[inline]
+    fn permissions(&self) -> SegmentPermissions {
+        let flags = unsafe { (*self.phdr).p_flags };
+        SegmentPermissions {
+            read: flags & PF_R != 0,
+            write: flags & PF_W != 0,
+            execute: flags & PF_X != 0,
+        }
+    }
 }
 
 /// An iterator of mapped segments in a shared library.
@@ -81,17 +543,34 @@ impl<'a> Iterator for SegmentIter<'a> {
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
         self.inner.next().map(|phdr| Segment {
-            phdr: phdr,
+            phdr,
             shlib: PhantomData
         })
     }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
 }
 
+impl<'a> DoubleEndedIterator for SegmentIter<'a> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|phdr| Segment {
+            phdr,
+            shlib: PhantomData
+        })
+    }
+}
+
+impl<'a> ExactSizeIterator for SegmentIter<'a> {}
+
 impl<'a> fmt::Debug for SegmentIter<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let ref phdr = self.inner.as_slice()[0];
+        let phdr = self.inner.as_slice().first().map(DebugPhdr);
 
-        f.debug_struct("SegmentIter").field("phdr", &DebugPhdr(phdr)).finish()
+        f.debug_struct("SegmentIter").field("phdr", &phdr).finish()
     }
 }
 
@@ -106,7 +585,7 @@ pub struct SharedLibrary<'a> {
 
 struct IterState<F> {
     f: F,
-    panic: Option<Box<Any + Send>>,
+    panic: Option<Box<dyn Any + Send>>,
 }
 
 const CONTINUE: libc::c_int = 0;
@@ -115,13 +594,184 @@ const BREAK: libc::c_int = 1;
 impl<'a> SharedLibrary<'a> {
     unsafe fn new(info: &'a libc::dl_phdr_info, size: usize) -> Self {
         SharedLibrary {
-            size: size,
+            size,
             addr: info.dlpi_addr as usize as *const _,
             name: CStr::from_ptr(info.dlpi_name),
             headers: slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize),
         }
     }
 
+    /// Get this shared library's `DT_SONAME`, if it has one.
+    ///
+    /// The main executable typically doesn't.
+    pub fn soname(&self) -> Option<&'a CStr> {
+        let offset = self.dynamic_value(DT_SONAME)?;
+        let strtab = self.dynamic_address(self.dynamic_value(DT_STRTAB)?);
+        Some(unsafe { CStr::from_ptr(strtab.add(offset) as *const _) })
+    }
+
+    /// Iterate over the notes in this shared library's `PT_NOTE` segments.
+    ///
+    /// Besides build IDs, these include the program properties of
+    /// `.note.gnu.property`, see `Note::gnu_properties`, and any of annobin's
+    /// build attribute notes that were mapped, see `Note::build_attribute`.
+    /// Those usually aren't, see `build_attribute_notes`.
+    #[inline]
+    pub fn notes(&self) -> NoteIter<'a> {
+        NoteIter {
+            addr: self.addr as usize,
+            headers: self.headers.iter(),
+            data: &[],
+            align: 4,
+        }
+    }
+
+    /// Get the payload of this shared library's GNU build ID note, exactly as
+    /// it is in memory, if it has one.
+    pub fn build_id(&self) -> Option<&'a [u8]> {
+        self.notes()
+            .find(|note| note.name() == b"GNU" && note.note_type() == NT_GNU_BUILD_ID)
+            .map(|note| note.desc())
+    }
+
+    /// Read annobin's build attribute notes from the `.gnu.build.attributes`
+    /// section of this shared library's file on disk, where they usually are
+    /// since the section isn't loaded into memory.
+    ///
+    /// Returns `None` if the file can't be read or has no such section.
+    pub fn build_attribute_notes(&self) -> Option<FileNotes> {
+        let mut elf = match ElfFile::open(self.file_path()?) {
+            Ok(elf) => elf,
+            Err(err) => {
+                trace::os_error("read", &err);
+                return None;
+            }
+        };
+        match elf.section(b".gnu.build.attributes") {
+            Ok(data) => data.map(|data| FileNotes { data }),
+            Err(err) => {
+                trace::os_error("read", &err);
+                None
+            }
+        }
+    }
+
+    /// Get the Go build ID of this shared library, if it was built by the Go
+    /// toolchain.
+    ///
+    /// This is a secondary identifier from the `.note.go.buildid` note; Go
+    /// binaries usually carry a GNU build ID as well.
+    #[inline]
+    pub fn go_build_id(&self) -> Option<&'a str> {
+        find_go_build_id(self.notes())
+    }
+
+    /// Get the path of the file this shared library was loaded from, or
+    /// `None` if it wasn't loaded from a file.
+    fn file_path(&self) -> Option<&Path> {
+        let name = self.name.to_bytes();
+        if name.is_empty() {
+            // The main executable is reported with an empty name.
+            Some(Path::new("/proc/self/exe"))
+        } else if name.contains(&b'/') {
+            Some(Path::new(OsStr::from_bytes(name)))
+        } else {
+            // The vDSO is reported by its soname, which mustn't be mistaken
+            // for a path relative to the current directory.
+            None
+        }
+    }
+
+    /// Get the places where separate debug information for this shared
+    /// library could be, in the order GDB looks for it: by build ID, then next
+    /// to the file and under `/usr/lib/debug` by `.gnu_debuglink` name.
+    fn debug_file_candidates(&self, elf: &mut ElfFile) -> Vec<PathBuf> {
+        let mut candidates = Vec::new();
+        if let Some(id) = self.build_id().filter(|id| id.len() > 1) {
+            let hex: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+            candidates.push(Path::new("/usr/lib/debug/.build-id")
+                .join(&hex[..2])
+                .join(format!("{}.debug", &hex[2..])));
+        }
+
+        let link = match elf.section(b".gnu_debuglink") {
+            Ok(Some(link)) => link,
+            _ => return candidates,
+        };
+        let name = Path::new(OsStr::from_bytes(link.split(|&b| b == 0).next().unwrap_or(&[])));
+        let dir = self
+            .file_path()
+            .and_then(|path| fs::canonicalize(path).ok())
+            .and_then(|path| path.parent().map(Path::to_path_buf));
+        if let (false, Some(dir)) = (name.as_os_str().is_empty(), dir) {
+            candidates.push(dir.join(name));
+            candidates.push(dir.join(".debug").join(name));
+            candidates.push(Path::new("/usr/lib/debug")
+                .join(dir.strip_prefix("/").unwrap_or(&dir))
+                .join(name));
+        }
+        candidates
+    }
+
+    /// Get the entries of the `PT_DYNAMIC` segment, up to the terminating
+    /// `DT_NULL`.
+    fn dynamic_section(&self) -> &'a [Dyn] {
+        let phdr = match self.headers.iter().find(|phdr| phdr.p_type == libc::PT_DYNAMIC) {
+            Some(phdr) => phdr,
+            None => return &[],
+        };
+
+        let start = (self.addr as usize).wrapping_add(phdr.p_vaddr as usize) as *const Dyn;
+        let max_len = phdr.p_memsz as usize / mem::size_of::<Dyn>();
+        let entries = unsafe { slice::from_raw_parts(start, max_len) };
+        let len = entries.iter().position(|d| d.d_tag == DT_NULL).unwrap_or(max_len);
+        &entries[..len]
+    }
+
+    fn dynamic_value(&self, tag: isize) -> Option<usize> {
+        self.dynamic_section().iter().find(|d| d.d_tag == tag).map(|d| d.d_val)
+    }
+
+    /// Get the function pointers in the array of initializers or finalizers
+    /// whose address and size are given by the `tag` and `size_tag` entries of
+    /// the dynamic section.
+    fn routine_array(&self, tag: isize, size_tag: isize) -> Vec<Avma> {
+        let ptr = match self.dynamic_value(tag) {
+            Some(ptr) => ptr,
+            None => return Vec::new(),
+        };
+        let start = self.dynamic_address(ptr) as *const usize;
+        let len = self.dynamic_value(size_tag).unwrap_or(0) / mem::size_of::<usize>();
+        // The loader skips the 0 and -1 entries some toolchains pad them with.
+        unsafe { slice::from_raw_parts(start, len) }
+            .iter()
+            .filter(|&&routine| routine != 0 && routine != usize::MAX)
+            .map(|&routine| Avma(routine as *const u8))
+            .collect()
+    }
+
+    /// Turn an address found in the dynamic section into an actual address.
+    ///
+    /// glibc relocates the `d_ptr` values of writable dynamic sections in
+    /// place, but leaves read-only ones (such as the vDSO's) alone, and other
+    /// loaders may not relocate them at all. Treat the address as already
+    /// relocated if it lands inside one of our mapped segments.
+    fn dynamic_address(&self, ptr: usize) -> *const u8 {
+        let bias = self.addr as usize;
+        let is_mapped = self.headers.iter()
+            .filter(|phdr| phdr.p_type == libc::PT_LOAD)
+            .any(|phdr| {
+                let start = bias.wrapping_add(phdr.p_vaddr as usize);
+                start <= ptr && ptr - start < phdr.p_memsz as usize
+            });
+
+        if is_mapped {
+            ptr as *const u8
+        } else {
+            ptr.wrapping_add(bias) as *const u8
+        }
+    }
+
     unsafe extern "C" fn callback<F, C>(info: *mut libc::dl_phdr_info,
                                         size: usize,
                                         state: *mut libc::c_void)
@@ -134,10 +784,11 @@ impl<'a> SharedLibrary<'a> {
         match panic::catch_unwind(panic::AssertUnwindSafe(|| {
             let info = info.as_ref().unwrap();
             let shlib = SharedLibrary::new(info, size);
+            trace::visited(&shlib);
 
             (state.f)(&shlib).into()
         })) {
-            Ok(IterationControl::Continue) => CONTINUE,
+            Ok(IterationControl::Continue) | Ok(IterationControl::Skip) => CONTINUE,
             Ok(IterationControl::Break) => BREAK,
             Err(panicked) => {
                 state.panic = Some(panicked);
@@ -145,6 +796,23 @@ impl<'a> SharedLibrary<'a> {
             }
         }
     }
+
+    unsafe extern "C" fn signal_safe_callback<F, C>(info: *mut libc::dl_phdr_info,
+                                                    size: usize,
+                                                    f: *mut libc::c_void)
+                                                    -> libc::c_int
+        where F: FnMut(&Self) -> C,
+              C: Into<IterationControl>
+    {
+        let f = &mut *(f as *mut F);
+        let info = info.as_ref().unwrap();
+        let shlib = SharedLibrary::new(info, size);
+
+        match f(&shlib).into() {
+            IterationControl::Continue | IterationControl::Skip => CONTINUE,
+            IterationControl::Break => BREAK,
+        }
+    }
 }
 
 impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
@@ -158,7 +826,120 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
 
     #[inline]
     fn id(&self) -> Option<SharedLibraryId> {
-        None
+        self.build_id().map(|id| SharedLibraryId::GnuBuildId(id.to_vec()))
+    }
+
+    #[inline]
+    fn copy_id_into(&self, buf: &mut [u8]) -> usize {
+        // Unlike `id`, borrow the build ID rather than copying it into a `Vec`.
+        self.build_id().map_or(0, |id| copy_truncated(id, buf))
+    }
+
+    fn version(&self) -> Option<SharedLibraryVersion> {
+        self.soname().and_then(|soname| version_from_soname(soname.to_bytes()))
+    }
+
+    fn debug_info_availability(&self) -> DebugInfoAvailability {
+        let names = self.file_path().map_or_else(Vec::new, |path| {
+            section_names(path).unwrap_or_else(|err| {
+                if err.kind() == io::ErrorKind::InvalidData {
+                    trace::unparsable(self.name, "section headers");
+                } else {
+                    trace::os_error("read", &err);
+                }
+                Vec::new()
+            })
+        });
+        let has = |wanted: &[&[u8]]| names.iter().any(|name| wanted.contains(&&name[..]));
+        if has(&[b".debug_info", b".zdebug_info"]) {
+            DebugInfoAvailability::Embedded
+        } else if has(&[b".gnu_debuglink", b".gnu_debugaltlink"]) || self.build_id().is_some() {
+            DebugInfoAvailability::External
+        } else {
+            DebugInfoAvailability::None
+        }
+    }
+
+    fn compilation_directory(&self) -> Option<PathBuf> {
+        let mut elf = match ElfFile::open(self.file_path()?) {
+            Ok(elf) => elf,
+            Err(err) => {
+                trace::os_error("read", &err);
+                return None;
+            }
+        };
+        if let Some(dir) = elf.compilation_directory() {
+            return Some(dir);
+        }
+
+        self.debug_file_candidates(&mut elf)
+            .iter()
+            .filter_map(|path| ElfFile::open(path).ok())
+            .find_map(|debug_file| debug_file.compilation_directory())
+    }
+
+    fn volume_kind(&self) -> Option<VolumeKind> {
+        let file_path = self.file_path()?;
+        let path = CString::new(file_path.as_os_str().as_bytes()).ok()?;
+        let mut stat: libc::statfs = unsafe { mem::zeroed() };
+        if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+            trace::os_error("statfs", &io::Error::last_os_error());
+            return None;
+        }
+        if NETWORK_FILESYSTEMS.contains(&(stat.f_type as u32)) {
+            return Some(VolumeKind::Network);
+        }
+
+        let device = fs::metadata(file_path).ok()?.dev();
+        if is_removable_device(device) {
+            Some(VolumeKind::Removable)
+        } else {
+            Some(VolumeKind::Local)
+        }
+    }
+
+    fn is_main_executable(&self) -> bool {
+        let phdr = unsafe { libc::getauxval(libc::AT_PHDR) } as usize;
+        phdr != 0 && phdr == self.headers.as_ptr() as usize
+    }
+
+    fn section_map(&self) -> Option<Vec<Vec<SectionInfo>>> {
+        let elf = match ElfFile::open(self.file_path()?) {
+            Ok(elf) => elf,
+            Err(err) => {
+                trace::os_error("read", &err);
+                return None;
+            }
+        };
+        let mut sections: Vec<_> = elf
+            .sections
+            .iter()
+            .filter(|(_, shdr)| shdr.sh_flags & SHF_ALLOC != 0 && shdr.sh_addr != 0)
+            .collect();
+        sections.sort_by_key(|(_, shdr)| shdr.sh_addr);
+
+        Some(self
+            .segments()
+            .map(|seg| {
+                let start = seg.stated_virtual_memory_address().0 as usize;
+                let end = start + seg.len();
+                sections
+                    .iter()
+                    .filter(|(_, shdr)| start <= shdr.sh_addr && shdr.sh_addr + shdr.sh_size <= end)
+                    .map(|&(name, shdr)| SectionInfo {
+                        name: name.clone(),
+                        svma: Svma(shdr.sh_addr as *const u8),
+                        len: shdr.sh_size,
+                        segment_offset: shdr.sh_addr - start,
+                        file_offset: if shdr.sh_type == SHT_NOBITS {
+                            None
+                        } else {
+                            Some(shdr.sh_offset as u64)
+                        },
+                    })
+                    .collect()
+            })
+            .collect())
     }
 
     #[inline]
@@ -166,19 +947,183 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
         SegmentIter { inner: self.headers.iter() }
     }
 
+    fn virtual_memory_size(&self) -> usize {
+        let (start, end) = self.headers
+            .iter()
+            .filter(|phdr| phdr.p_type == libc::PT_LOAD && phdr.p_memsz > 0)
+            .fold((usize::MAX, 0), |(start, end), phdr| {
+                let vaddr = phdr.p_vaddr as usize;
+                (start.min(vaddr), end.max(vaddr + phdr.p_memsz as usize))
+            });
+        end.saturating_sub(start)
+    }
+
     #[inline]
     fn virtual_memory_bias(&self) -> Bias {
         assert!((self.addr as usize) < (isize::MAX as usize));
         Bias(self.addr as usize as isize)
     }
 
+    #[inline]
+    fn load_slide(&self) -> isize {
+        self.addr as usize as isize
+    }
+
+    fn header_bytes(&self) -> Option<&[u8]> {
+        let phdr = self.headers
+            .iter()
+            .find(|phdr| phdr.p_type == libc::PT_LOAD && phdr.p_offset == 0)?;
+        let mapped = phdr.p_filesz as usize;
+        if mapped < mem::size_of::<Ehdr>() {
+            return None;
+        }
+
+        let start = (self.addr as usize).wrapping_add(phdr.p_vaddr as usize) as *const u8;
+        let ehdr = unsafe { &*(start as *const Ehdr) };
+        if &ehdr.e_ident[..4] != b"\x7fELF" {
+            return None;
+        }
+        let phdrs_end = ehdr.e_phoff
+            .saturating_add(ehdr.e_phnum as usize * ehdr.e_phentsize as usize);
+        let len = (ehdr.e_ehsize as usize).max(phdrs_end).min(mapped);
+        Some(unsafe { slice::from_raw_parts(start, len) })
+    }
+
+    fn relocation_counts(&self) -> Option<RelocationCounts> {
+        if self.dynamic_section().is_empty() {
+            return None;
+        }
+
+        let value = |tag| self.dynamic_value(tag).unwrap_or(0);
+        let rela_ent = self.dynamic_value(DT_RELAENT).unwrap_or(3 * mem::size_of::<usize>());
+        let rel_ent = self.dynamic_value(DT_RELENT).unwrap_or(2 * mem::size_of::<usize>());
+        let plt_ent = if value(DT_PLTREL) == DT_RELA as usize { rela_ent } else { rel_ent };
+
+        // Some linkers include the PLT relocations in the range of the others,
+        // which the loader is prepared to skip. The addresses are compared as
+        // found in the dynamic section, as they are all relocated or not.
+        let plt_size = value(DT_PLTRELSZ);
+        let jmprel = value(DT_JMPREL);
+        let without_plt = |start: usize, size: usize| {
+            if plt_size > 0 && start <= jmprel && jmprel - start < size {
+                size.saturating_sub(plt_size)
+            } else {
+                size
+            }
+        };
+        let rela_size = without_plt(value(DT_RELA), value(DT_RELASZ));
+        let rel_size = without_plt(value(DT_REL), value(DT_RELSZ));
+
+        let dynamic = rela_size.checked_div(rela_ent).unwrap_or(0)
+            + rel_size.checked_div(rel_ent).unwrap_or(0);
+        // Linkers sort relative relocations first and count them, so that the
+        // loader can apply them without looking at their types.
+        let relative = (value(DT_RELACOUNT) + value(DT_RELCOUNT)).min(dynamic);
+        let relr = match self.dynamic_value(DT_RELR) {
+            Some(ptr) => {
+                let start = self.dynamic_address(ptr) as *const usize;
+                let len = value(DT_RELRSZ) / mem::size_of::<usize>();
+                count_relr(unsafe { slice::from_raw_parts(start, len) })
+            }
+            None => 0,
+        };
+
+        Some(RelocationCounts {
+            relative: relative + relr,
+            symbolic: dynamic - relative,
+            lazy: plt_size.checked_div(plt_ent).unwrap_or(0),
+        })
+    }
+
+    fn init_routines(&self) -> Vec<Avma> {
+        let mut routines = self.routine_array(DT_PREINIT_ARRAY, DT_PREINIT_ARRAYSZ);
+        routines.extend(self.dynamic_value(DT_INIT).map(|ptr| Avma(self.dynamic_address(ptr))));
+        routines.extend(self.routine_array(DT_INIT_ARRAY, DT_INIT_ARRAYSZ));
+        routines
+    }
+
+    fn fini_routines(&self) -> Vec<Avma> {
+        let mut routines = self.routine_array(DT_FINI_ARRAY, DT_FINI_ARRAYSZ);
+        routines.reverse();
+        routines.extend(self.dynamic_value(DT_FINI).map(|ptr| Avma(self.dynamic_address(ptr))));
+        routines
+    }
+
+    fn function_starts(&self) -> Vec<Avma> {
+        let phdr = match self.headers.iter().find(|phdr| phdr.p_type == libc::PT_GNU_EH_FRAME) {
+            Some(phdr) => phdr,
+            None => return Vec::new(),
+        };
+        let start = (self.addr as usize).wrapping_add(phdr.p_vaddr as usize);
+        let hdr = unsafe { slice::from_raw_parts(start as *const u8, phdr.p_memsz as usize) };
+        let starts = eh_frame_hdr_function_starts(hdr, start);
+        if starts.is_empty() {
+            trace::unparsable(self.name, ".eh_frame_hdr");
+        }
+        starts
+            .into_iter()
+            .map(|address| Avma(address as *const u8))
+            .collect()
+    }
+
+    fn symbol_at(&self, address: Avma) -> Option<Symbol<'_>> {
+        // `dladdr` would happily find the symbol in another shared library.
+        if !self.segments().any(|seg| seg.contains_avma(self, address)) {
+            return None;
+        }
+        dladdr_symbol(address)
+    }
+
+    fn loaded_modules_generation() -> u64 {
+        unsafe extern "C" fn callback(info: *mut libc::dl_phdr_info,
+                                      size: usize,
+                                      state: *mut libc::c_void)
+                                      -> libc::c_int {
+            let state = &mut *(state as *mut (Option<u64>, u64));
+            let info = &*info;
+
+            // `dlpi_adds` and `dlpi_subs` were added to the end of the struct
+            // later on, so only read them if the loader says they're there.
+            let subs_offset = &info.dlpi_subs as *const _ as usize - info as *const _ as usize;
+            if size >= subs_offset + mem::size_of_val(&info.dlpi_subs) {
+                state.0 = Some(info.dlpi_adds.wrapping_add(info.dlpi_subs));
+                return BREAK;
+            }
+
+            state.1 += 1;
+            CONTINUE
+        }
+
+        let mut state: (Option<u64>, u64) = (None, 0);
+        unsafe {
+            libc::dl_iterate_phdr(Some(callback), &mut state as *mut _ as *mut _);
+        }
+        state.0.unwrap_or(state.1)
+    }
+
+    #[inline]
+    fn lock_hazards() -> LockHazards {
+        LockHazards {
+            loader_lock: true,
+            crate_lock: false,
+            allocator_lock: false,
+        }
+    }
+
+    #[inline]
+    fn signal_safe_lock_hazards() -> LockHazards {
+        // Both go through `dl_iterate_phdr`.
+        Self::lock_hazards()
+    }
+
     #[inline]
     fn each<F, C>(f: F)
         where F: FnMut(&Self) -> C,
               C: Into<IterationControl>
     {
+        let _span = trace::enumeration("each");
         let mut state = IterState {
-            f: f,
+            f,
             panic: None,
         };
 
@@ -190,6 +1135,13 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
             panic::resume_unwind(panic);
         }
     }
+
+    unsafe fn each_unsafe_signal_safe<F, C>(mut f: F)
+        where F: FnMut(&Self) -> C,
+              C: Into<IterationControl>
+    {
+        libc::dl_iterate_phdr(Some(Self::signal_safe_callback::<F, C>), &mut f as *mut _ as *mut _);
+    }
 }
 
 impl<'a> fmt::Debug for SharedLibrary<'a> {
@@ -200,9 +1152,8 @@ impl<'a> fmt::Debug for SharedLibrary<'a> {
         // Debug does not usually have a trailing comma in the list,
         // last element must be formatted separately.
         let l = self.headers.len();
-        self.headers[..(l - 1)].into_iter()
-            .map(|phdr| write!(f, "{:?}, ", &DebugPhdr(phdr)))
-            .collect::<fmt::Result>()?;
+        self.headers[..(l - 1)].iter()
+            .try_for_each(|phdr| write!(f, "{:?}, ", &DebugPhdr(phdr)))?;
 
         write!(f, "{:?}", &DebugPhdr(&self.headers[l - 1]))?;
 
@@ -210,6 +1161,218 @@ impl<'a> fmt::Debug for SharedLibrary<'a> {
     }
 }
 
+/// Read the initial locations from the binary search table of an
+/// `.eh_frame_hdr` section mapped at `address`.
+///
+/// Linkers always encode the table as pairs of 4-byte offsets from the start
+/// of the section, so other encodings aren't supported.
+fn eh_frame_hdr_function_starts(hdr: &[u8], address: usize) -> Vec<usize> {
+    fn encoded_len(encoding: u8) -> Option<usize> {
+        match encoding & 0x0f {
+            DW_EH_PE_ABSPTR => Some(mem::size_of::<usize>()),
+            DW_EH_PE_UDATA2 | DW_EH_PE_SDATA2 => Some(2),
+            DW_EH_PE_UDATA4 | DW_EH_PE_SDATA4 => Some(4),
+            DW_EH_PE_UDATA8 | DW_EH_PE_SDATA8 => Some(8),
+            _ => None,
+        }
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+        let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    let (version, frame_ptr_enc, count_enc, table_enc) = match *hdr {
+        [version, frame_ptr_enc, count_enc, table_enc, ..] => {
+            (version, frame_ptr_enc, count_enc, table_enc)
+        }
+        _ => return Vec::new(),
+    };
+    if version != 1
+        || frame_ptr_enc == DW_EH_PE_OMIT
+        || count_enc & 0x0f != DW_EH_PE_UDATA4
+        || table_enc != DW_EH_PE_DATAREL | DW_EH_PE_SDATA4
+    {
+        return Vec::new();
+    }
+
+    let count_offset = match encoded_len(frame_ptr_enc) {
+        Some(len) => 4 + len,
+        None => return Vec::new(),
+    };
+    let count = match read_u32(hdr, count_offset) {
+        Some(count) => count as usize,
+        None => return Vec::new(),
+    };
+
+    let table = count_offset + 4;
+    (0..count)
+        .map_while(|i| read_u32(hdr, table + i * 8))
+        .map(|location| address.wrapping_add(location as i32 as isize as usize))
+        .collect()
+}
+
+/// Count the relative relocations encoded in a `DT_RELR` table.
+///
+/// Even entries are addresses, each of which is relocated. Odd entries are
+/// bitmaps of the words following the previous address, with the lowest bit
+/// marking them as such.
+fn count_relr(entries: &[usize]) -> usize {
+    entries
+        .iter()
+        .map(|&entry| if entry & 1 == 0 { 1 } else { entry.count_ones() as usize - 1 })
+        .sum()
+}
+
+/// An ELF file on disk, for reading the sections that aren't loaded into
+/// memory.
+struct ElfFile {
+    file: File,
+    sections: Vec<(Vec<u8>, Shdr)>,
+}
+
+impl ElfFile {
+    /// Open the ELF file at `path` and read its section headers.
+    fn open(path: &Path) -> io::Result<ElfFile> {
+        fn invalid() -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, "not a valid ELF file")
+        }
+
+        let file = File::open(path)?;
+        let mut ehdr = [0u8; mem::size_of::<Ehdr>()];
+        read_at(&file, 0, &mut ehdr)?;
+        let ehdr = unsafe { &*(ehdr.as_ptr() as *const Ehdr) };
+        if &ehdr.e_ident[..4] != b"\x7fELF" || ehdr.e_shentsize as usize != mem::size_of::<Shdr>() {
+            return Err(invalid());
+        }
+
+        let mut shdrs = vec![Shdr::default(); ehdr.e_shnum as usize];
+        {
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(shdrs.as_mut_ptr() as *mut u8,
+                                          shdrs.len() * mem::size_of::<Shdr>())
+            };
+            read_at(&file, ehdr.e_shoff, bytes)?;
+        }
+
+        let strtab = shdrs.get(ehdr.e_shstrndx as usize).ok_or_else(invalid)?;
+        let mut strings = vec![0u8; strtab.sh_size];
+        read_at(&file, strtab.sh_offset, &mut strings)?;
+
+        let sections = shdrs
+            .iter()
+            .filter_map(|shdr| {
+                let name = strings.get(shdr.sh_name as usize..)?;
+                let len = name.iter().position(|&b| b == 0)?;
+                Some((name[..len].to_vec(), *shdr))
+            })
+            .collect();
+        Ok(ElfFile { file, sections })
+    }
+
+    /// Read the contents of the section with the given name, if there is one
+    /// with contents in the file. Compressed sections aren't supported.
+    fn section(&mut self, name: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let shdr = match self.section_header(name) {
+            Some(shdr) => shdr,
+            None => return Ok(None),
+        };
+        let mut contents = vec![0u8; shdr.sh_size];
+        read_at(&self.file, shdr.sh_offset, &mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /// Get the header of the section with the given name, if there is one
+    /// with uncompressed contents in the file.
+    fn section_header(&self, name: &[u8]) -> Option<Shdr> {
+        match self.sections.iter().find(|(this, _)| &this[..] == name) {
+            Some(&(_, shdr)) if shdr.sh_type != SHT_NOBITS && shdr.sh_flags & SHF_COMPRESSED == 0 => {
+                Some(shdr)
+            }
+            _ => None,
+        }
+    }
+
+    /// Read the compilation directory of the first compilation unit from
+    /// this file's DWARF, if it has any.
+    fn compilation_directory(&self) -> Option<PathBuf> {
+        dwarf::compilation_directory(self)
+    }
+}
+
+impl dwarf::Sections for ElfFile {
+    fn read(&self, section: dwarf::Section, offset: u64, buf: &mut [u8]) -> Option<usize> {
+        let name: &[u8] = match section {
+            dwarf::Section::Info => b".debug_info",
+            dwarf::Section::Abbrev => b".debug_abbrev",
+            dwarf::Section::Str => b".debug_str",
+            dwarf::Section::LineStr => b".debug_line_str",
+        };
+        let shdr = self.section_header(name)?;
+        let offset = usize::try_from(offset).ok()?;
+        let len = cmp::min(buf.len(), shdr.sh_size.saturating_sub(offset));
+        read_at(&self.file, shdr.sh_offset.checked_add(offset)?, &mut buf[..len]).ok()?;
+        Some(len)
+    }
+}
+
+fn read_at(mut file: &File, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(buf)
+}
+
+/// Find out whether the block device with the given number, or the disk it
+/// is a partition of, is marked removable in `/sys`.
+fn is_removable_device(device: u64) -> bool {
+    let (major, minor) = device_numbers(device);
+    let device = match fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor)) {
+        Ok(device) => device,
+        // File systems like tmpfs and overlayfs have no block device.
+        Err(_) => return false,
+    };
+    [Some(device.as_path()), device.parent()]
+        .iter()
+        .flatten()
+        .filter_map(|dir| fs::read(dir.join("removable")).ok())
+        .any(|removable| removable.starts_with(b"1"))
+}
+
+/// Split a device number into its major and minor numbers, as glibc's
+/// `gnu_dev_major` and `gnu_dev_minor` do.
+fn device_numbers(device: u64) -> (u64, u64) {
+    let major = ((device >> 8) & 0xfff) | ((device >> 32) & 0xffff_f000);
+    let minor = (device & 0xff) | ((device >> 12) & 0xffff_ff00);
+    (major, minor)
+}
+
+/// Read the names of the sections of the ELF file at `path`.
+fn section_names(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    Ok(ElfFile::open(path)?.sections.into_iter().map(|(name, _)| name).collect())
+}
+
+fn find_go_build_id<'a>(mut notes: NoteIter<'a>) -> Option<&'a str> {
+    notes
+        .find(|note| note.name() == b"Go" && note.note_type() == NT_GO_BUILD_ID)
+        .and_then(|note| str::from_utf8(note.desc()).ok())
+}
+
+/// Parse the numeric suffix of a SONAME like `libssl.so.1.1` into a version.
+fn version_from_soname(soname: &[u8]) -> Option<SharedLibraryVersion> {
+    let suffix_start = soname.windows(4).position(|w| w == b".so.")? + 4;
+
+    let mut components = [0; 3];
+    let parts = soname[suffix_start..].split(|c| *c == b'.');
+    for (component, part) in components.iter_mut().zip(parts) {
+        *component = str::from_utf8(part).ok()?.parse().ok()?;
+    }
+
+    Some(SharedLibraryVersion {
+        major: components[0],
+        minor: components[1],
+        patch: components[2],
+    })
+}
+
 struct DebugPhdr<'a>(&'a Phdr);
 
 impl<'a> fmt::Debug for DebugPhdr<'a> {
@@ -234,7 +1397,9 @@ impl<'a> fmt::Debug for DebugPhdr<'a> {
 #[cfg(test)]
 mod tests {
     use linux;
-    use super::super::{IterationControl, SharedLibrary, Segment};
+    use super::Ehdr;
+    use super::super::{IterationControl, SharedLibrary, SharedLibraryId, Segment};
+    use std::mem;
 
     #[test]
     fn have_libc() {
@@ -270,6 +1435,288 @@ mod tests {
         assert_eq!(second_count, first_count - 1);
     }
 
+    #[test]
+    fn libc_version() {
+        linux::SharedLibrary::each(|shlib| {
+            if shlib.soname().map(|s| s.to_bytes()) == Some(b"libc.so.6") {
+                let version = shlib.version().unwrap();
+                assert_eq!((version.major, version.minor, version.patch), (6, 0, 0));
+            }
+        });
+    }
+
+    #[test]
+    fn version_from_soname() {
+        use super::version_from_soname;
+
+        let version = version_from_soname(b"libssl.so.1.1").unwrap();
+        assert_eq!(version.to_string(), "1.1.0");
+        let version = version_from_soname(b"libfoo.so.1.2.3.4").unwrap();
+        assert_eq!(version.to_string(), "1.2.3");
+        assert!(version_from_soname(b"libfoo.so").is_none());
+        assert!(version_from_soname(b"libfoo.so.x").is_none());
+    }
+
+    #[test]
+    fn generation_changes_on_dlopen() {
+        use libc;
+
+        fn is_libresolv(shlib: &linux::SharedLibrary) -> bool {
+            shlib.name().to_bytes().ends_with(b"libresolv.so.2")
+        }
+
+        let mut already_loaded = false;
+        linux::SharedLibrary::each(|shlib| {
+            already_loaded |= is_libresolv(shlib);
+        });
+        if already_loaded {
+            return;
+        }
+
+        let before = linux::SharedLibrary::loaded_modules_generation();
+        let handle = unsafe { libc::dlopen("libresolv.so.2\0".as_ptr() as _, libc::RTLD_NOW) };
+        if handle.is_null() {
+            return;
+        }
+        let after = linux::SharedLibrary::loaded_modules_generation();
+        unsafe {
+            libc::dlclose(handle);
+        }
+
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn libc_has_build_id() {
+        linux::SharedLibrary::each(|shlib| {
+            if shlib.soname().map(|s| s.to_bytes()) == Some(b"libc.so.6") {
+                assert!(shlib.build_id().is_some_and(|id| !id.is_empty()));
+            }
+        });
+    }
+
+    #[test]
+    fn id_is_build_id() {
+        linux::SharedLibrary::each(|shlib| {
+            let expected = shlib.build_id().map(|id| SharedLibraryId::GnuBuildId(id.to_vec()));
+            assert_eq!(shlib.id(), expected);
+        });
+    }
+
+    #[test]
+    fn build_id_is_a_gnu_note() {
+        linux::SharedLibrary::each(|shlib| {
+            if let Some(id) = shlib.build_id() {
+                assert!(shlib.notes().any(|note| {
+                    note.name() == b"GNU" && note.note_type() == 3 && note.desc() == id
+                }));
+            }
+        });
+    }
+
+    #[test]
+    fn libc_has_relocations() {
+        linux::SharedLibrary::each(|shlib| {
+            if shlib.name().to_bytes().ends_with(b"/libc.so.6") {
+                let counts = shlib.relocation_counts().expect("libc should be dynamic");
+                assert!(counts.relative > 0);
+                assert!(counts.symbolic + counts.lazy > 0);
+            }
+        });
+    }
+
+    #[test]
+    fn init_and_fini_routines_are_executable() {
+        let mut main_has_initializers = None;
+        linux::SharedLibrary::each(|shlib| {
+            let routines = shlib.init_routines().into_iter().chain(shlib.fini_routines());
+            for routine in routines {
+                assert!(shlib.segments().any(|seg| {
+                    seg.permissions().execute && seg.contains_avma(shlib, routine)
+                }));
+            }
+            main_has_initializers.get_or_insert(!shlib.init_routines().is_empty());
+        });
+        // The standard library registers an initializer to capture `argv`.
+        assert_eq!(main_has_initializers, Some(true));
+    }
+
+    #[test]
+    fn function_starts_include_this_function() {
+        let this: fn() = function_starts_include_this_function;
+        let this = super::Avma(this as *const u8);
+        linux::SharedLibrary::each(|shlib| {
+            let starts = shlib.function_starts();
+            assert!(starts.windows(2).all(|pair| pair[0] <= pair[1]));
+            if shlib.segments().any(|seg| seg.contains_avma(shlib, this)) {
+                assert!(starts.binary_search(&this).is_ok());
+            }
+        });
+    }
+
+    #[test]
+    fn parse_eh_frame_hdr() {
+        use super::eh_frame_hdr_function_starts;
+
+        let mut hdr = vec![1, 0x1b, 0x03, 0x3b];
+        hdr.extend_from_slice(&0x1000i32.to_ne_bytes());
+        hdr.extend_from_slice(&2u32.to_ne_bytes());
+        for &(location, fde) in &[(-0x100i32, 0x1010i32), (0x20, 0x1020)] {
+            hdr.extend_from_slice(&location.to_ne_bytes());
+            hdr.extend_from_slice(&fde.to_ne_bytes());
+        }
+        assert_eq!(eh_frame_hdr_function_starts(&hdr, 0x10000), vec![0xff00, 0x10020]);
+
+        // A truncated table only yields the complete entries.
+        assert_eq!(eh_frame_hdr_function_starts(&hdr[..hdr.len() - 8], 0x10000), vec![0xff00]);
+
+        // No lookup table.
+        hdr[3] = 0xff;
+        assert!(eh_frame_hdr_function_starts(&hdr, 0x10000).is_empty());
+        assert!(eh_frame_hdr_function_starts(&[], 0x10000).is_empty());
+    }
+
+    #[test]
+    fn count_relr_addresses_and_bitmaps() {
+        use super::count_relr;
+
+        assert_eq!(count_relr(&[]), 0);
+        assert_eq!(count_relr(&[0x1000, 0x2000]), 2);
+        // An address, then a bitmap relocating the first and third word after it.
+        assert_eq!(count_relr(&[0x1000, 0b1011]), 3);
+    }
+
+    #[test]
+    fn parse_go_build_id_note() {
+        use super::{find_go_build_id, NoteIter};
+
+        fn note(bytes: &mut Vec<u8>, name: &[u8], note_type: u32, desc: &[u8]) {
+            bytes.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            bytes.extend_from_slice(&(desc.len() as u32).to_ne_bytes());
+            bytes.extend_from_slice(&note_type.to_ne_bytes());
+            bytes.extend_from_slice(name);
+            let padded = (bytes.len() + 3) & !3;
+            bytes.resize(padded, 0);
+            bytes.extend_from_slice(desc);
+            let padded = (bytes.len() + 3) & !3;
+            bytes.resize(padded, 0);
+        }
+
+        let mut bytes = Vec::new();
+        note(&mut bytes, b"GNU\0", 3, &[0xab; 20]);
+        // The Go linker writes a namesz of 4, padding the name with nuls.
+        note(&mut bytes, b"Go\0\0", 4, b"abc/def");
+        let notes = || NoteIter {
+            addr: 0,
+            headers: [].iter(),
+            data: &bytes,
+            align: 4,
+        };
+
+        let parsed: Vec<_> = notes().map(|n| (n.name(), n.note_type(), n.desc())).collect();
+        assert_eq!(parsed, vec![
+            (&b"GNU"[..], 3, &[0xab; 20][..]),
+            (&b"Go"[..], 4, &b"abc/def"[..]),
+        ]);
+        assert_eq!(find_go_build_id(notes()), Some("abc/def"));
+
+        // Sizes that run past the end of the segment, or would overflow on
+        // 32-bit targets, end iteration of the segment.
+        let mut bytes = bytes.clone();
+        bytes.extend_from_slice(&u32::MAX.to_ne_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_ne_bytes());
+        bytes.extend_from_slice(&1u32.to_ne_bytes());
+        let notes = NoteIter {
+            addr: 0,
+            headers: [].iter(),
+            data: &bytes,
+            align: 4,
+        };
+        assert_eq!(notes.count(), 2);
+    }
+
+    #[test]
+    fn parse_gnu_property_and_build_attribute_notes() {
+        use super::{
+            BuildAttributeKind, BuildAttributeValue, NoteIter, NT_GNU_BUILD_ATTRIBUTE_FUNC,
+            NT_GNU_BUILD_ATTRIBUTE_OPEN, NT_GNU_PROPERTY_TYPE_0,
+        };
+
+        fn note(bytes: &mut Vec<u8>, name: &[u8], note_type: u32, desc: &[u8]) {
+            bytes.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            bytes.extend_from_slice(&(desc.len() as u32).to_ne_bytes());
+            bytes.extend_from_slice(&note_type.to_ne_bytes());
+            bytes.extend_from_slice(name);
+            let padded = (bytes.len() + 3) & !3;
+            bytes.resize(padded, 0);
+            bytes.extend_from_slice(desc);
+            let padded = (bytes.len() + 3) & !3;
+            bytes.resize(padded, 0);
+        }
+
+        let align = mem::size_of::<usize>();
+        let mut properties = Vec::new();
+        for &(property_type, data) in &[(0xc000_0002u32, &[3u8, 0, 0, 0][..]), (1, &[7][..])] {
+            properties.extend_from_slice(&property_type.to_ne_bytes());
+            properties.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+            properties.extend_from_slice(data);
+            let padded = (properties.len() + align - 1) & !(align - 1);
+            properties.resize(padded, 0);
+        }
+
+        let mut bytes = Vec::new();
+        note(&mut bytes, b"GNU\0", NT_GNU_PROPERTY_TYPE_0, &properties);
+        note(&mut bytes, b"GA$\x013p3\0", NT_GNU_BUILD_ATTRIBUTE_OPEN, &[0; 16]);
+        note(&mut bytes, b"GA*\x02\x02\0", NT_GNU_BUILD_ATTRIBUTE_OPEN, &[]);
+        note(&mut bytes, b"GA*FORTIFY\0\xff\0", NT_GNU_BUILD_ATTRIBUTE_FUNC, &[]);
+        note(&mut bytes, b"GA!omit_frame_pointer\0", NT_GNU_BUILD_ATTRIBUTE_FUNC, &[]);
+        let notes: Vec<_> = NoteIter {
+            addr: 0,
+            headers: [].iter(),
+            data: &bytes,
+            align: 4,
+        }
+        .collect();
+        assert_eq!(notes.len(), 5);
+
+        let parsed: Vec<_> = notes[0]
+            .gnu_properties()
+            .map(|p| (p.property_type(), p.data()))
+            .collect();
+        assert_eq!(parsed, vec![(0xc000_0002, &[3, 0, 0, 0][..]), (1, &[7][..])]);
+        assert_eq!(notes[0].build_attribute(), None);
+
+        let parsed: Vec<_> = notes[1..]
+            .iter()
+            .map(|n| {
+                assert_eq!(n.gnu_properties().count(), 0);
+                let attribute = n.build_attribute().unwrap();
+                (attribute.kind(), attribute.value())
+            })
+            .collect();
+        assert_eq!(parsed, vec![
+            (BuildAttributeKind::Version, BuildAttributeValue::String(b"3p3")),
+            (BuildAttributeKind::StackProtector, BuildAttributeValue::Number(2)),
+            (BuildAttributeKind::Other(b"FORTIFY"), BuildAttributeValue::Number(0xff)),
+            (BuildAttributeKind::Other(b"omit_frame_pointer"), BuildAttributeValue::Bool(false)),
+        ]);
+    }
+
+    #[test]
+    fn loaded_modules_have_gnu_properties() {
+        // Current toolchains record at least the x86 ISA level, or AArch64's
+        // BTI and PAC, in some of the system's libraries.
+        let mut found = false;
+        linux::SharedLibrary::each(|shlib| {
+            found |= shlib.notes().any(|n| n.gnu_properties().next().is_some());
+            // Whether or not the library was built with annobin.
+            if let Some(notes) = shlib.build_attribute_notes() {
+                assert!(notes.iter().all(|n| n.name().starts_with(b"GA")));
+            }
+        });
+        assert!(found);
+    }
+
     #[test]
     fn get_name() {
         linux::SharedLibrary::each(|shlib| {
@@ -292,4 +1739,176 @@ mod tests {
             assert!(found_load);
         });
     }
+
+    #[test]
+    fn libc_has_executable_load_segment() {
+        linux::SharedLibrary::each(|shlib| {
+            if shlib.soname().map(|s| s.to_bytes()) == Some(b"libc.so.6") {
+                assert!(shlib.segments().any(|seg| {
+                    seg.name().to_bytes() == b"LOAD" && seg.permissions().to_string() == "r-x"
+                }));
+            }
+        });
+    }
+
+    #[test]
+    fn load_segments_are_page_congruent() {
+        // The loader maps each PT_LOAD so that its address and file offset
+        // agree modulo the page size.
+        linux::SharedLibrary::each(|shlib| {
+            for seg in shlib.segments().filter(|seg| seg.name().to_bytes() == b"LOAD") {
+                let svma = seg.stated_virtual_memory_address().0 as u64;
+                assert_eq!(svma % 4096, seg.file_offset() % 4096, "{:?}", seg);
+            }
+        });
+    }
+
+    #[test]
+    fn file_size_at_most_memory_size() {
+        linux::SharedLibrary::each(|shlib| {
+            for seg in shlib.segments().filter(|seg| seg.name().to_bytes() == b"LOAD") {
+                assert!(seg.file_size() <= seg.len() as u64, "{:?}", seg);
+            }
+        });
+    }
+
+    #[test]
+    fn virtual_memory_size_covers_load_segments() {
+        linux::SharedLibrary::each(|shlib| {
+            let size = shlib.virtual_memory_size();
+            let loads: usize = shlib.segments()
+                .filter(|seg| seg.name().to_bytes() == b"LOAD")
+                .map(|seg| seg.len())
+                .sum();
+            assert!(size >= loads, "{:?}", shlib);
+            assert!(size > 0 || loads == 0);
+        });
+    }
+
+    #[test]
+    fn header_bytes_hold_the_program_headers() {
+        linux::SharedLibrary::each(|shlib| {
+            let header = shlib.header_bytes().expect("headers should be mapped");
+            assert_eq!(&header[..4], b"\x7fELF");
+            assert!(header.len() >= mem::size_of::<Ehdr>());
+
+            let ehdr = unsafe { &*(header.as_ptr() as *const Ehdr) };
+            assert_eq!(ehdr.e_phnum as usize, shlib.segments().len());
+        });
+    }
+
+    #[test]
+    // Only dev profile tests are built with debug info.
+    #[cfg(debug_assertions)]
+    fn test_binary_has_embedded_debug_info() {
+        linux::SharedLibrary::each(|shlib| {
+            assert_eq!(shlib.debug_info_availability(), ::DebugInfoAvailability::Embedded);
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn section_names_of_test_binary() {
+        let names = super::section_names(::std::path::Path::new("/proc/self/exe")).unwrap();
+        assert!(names.iter().any(|name| &name[..] == b".text"));
+        assert!(super::section_names(::std::path::Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn test_binary_volume_kind() {
+        linux::SharedLibrary::each(|shlib| {
+            assert!(shlib.volume_kind().is_some());
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn test_binary_section_map() {
+        linux::SharedLibrary::each(|shlib| {
+            let map = shlib.section_map().expect("should read the section headers");
+            assert_eq!(map.len(), shlib.segments().len());
+            let text = map
+                .iter()
+                .zip(shlib.segments())
+                .filter_map(|(sections, seg)| {
+                    sections.iter().find(|section| section.name == b".text").map(|text| (text, seg))
+                })
+                .next();
+            let (text, seg) = text.expect("should find .text in a segment");
+            assert!(seg.permissions().execute);
+            assert_eq!(text.svma.0 as usize,
+                       seg.stated_virtual_memory_address().0 as usize + text.segment_offset);
+            assert!(text.file_offset.is_some());
+
+            for sections in &map {
+                assert!(sections.windows(2).all(|pair| pair[0].svma.0 <= pair[1].svma.0));
+            }
+            assert!(map.iter().flatten().any(|section| {
+                section.name == b".bss" && section.file_offset.is_none()
+            }));
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn split_device_numbers() {
+        use super::device_numbers;
+
+        assert_eq!(device_numbers(0x0801), (8, 1));
+        assert_eq!(device_numbers(0x0000_1001_0040_0356), (0x1003, 0x10_0456));
+    }
+
+    #[test]
+    fn segments_exact_size_and_reversible() {
+        linux::SharedLibrary::each(|shlib| {
+            let forward: Vec<_> = shlib.segments()
+                .map(|seg| seg.stated_virtual_memory_address())
+                .collect();
+            let mut backward: Vec<_> = shlib.segments()
+                .rev()
+                .map(|seg| seg.stated_virtual_memory_address())
+                .collect();
+            backward.reverse();
+
+            assert_eq!(shlib.segments().len(), forward.len());
+            assert_eq!(forward, backward);
+        });
+    }
+
+    #[test]
+    fn vdso_is_not_read_from_the_current_directory() {
+        use super::super::DebugInfoAvailability;
+        use libc;
+        use std::{env, fs, panic, process};
+
+        // A file with sections and debug information, named like the vDSO.
+        let dir = env::temp_dir().join(format!("findshlibs-vdso-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy(env::current_exe().unwrap(), dir.join("linux-vdso.so.1")).unwrap();
+
+        // Changing directory would break other tests, so do it in a child.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let result = panic::catch_unwind(|| {
+                env::set_current_dir(&dir).unwrap();
+                linux::SharedLibrary::each(|shlib| {
+                    if shlib.name().to_bytes() == b"linux-vdso.so.1" {
+                        assert!(shlib.debug_info_availability() != DebugInfoAvailability::Embedded);
+                        assert!(shlib.compilation_directory().is_none());
+                        assert!(shlib.volume_kind().is_none());
+                        assert!(shlib.section_map().is_none());
+                    }
+                });
+            });
+            unsafe { libc::_exit(result.is_err() as libc::c_int) };
+        }
+
+        let mut status = 0;
+        let waited = unsafe { libc::waitpid(pid, &mut status, 0) };
+        let _ = fs::remove_dir_all(&dir);
+        assert!(pid > 0, "fork failed");
+        assert_eq!(waited, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
 }
@@ -0,0 +1,164 @@
+//! Fast lookup of the shared library containing an address.
+//!
+//! Finding the shared library an address belongs to with `SharedLibrary::each`
+//! means walking the loader's list and every segment for each address, which
+//! is too slow for samplers and profilers resolving many addresses per second.
+//! A [`ModuleMap`](./struct.ModuleMap.html) is built from one enumeration and
+//! answers each lookup with a binary search instead.
+//!
+//! ```
+//! use findshlibs::lookup::ModuleMap;
+//! use findshlibs::Avma;
+//!
+//! let map = ModuleMap::new();
+//! let address = Avma(ModuleMap::new as *const u8);
+//! if let Some(module) = map.lookup(address) {
+//!     println!("{} is in {}", address, module.name().to_string_lossy());
+//! }
+//! ```
+
+use super::snapshot::{ModuleInfo, ProcessMap, SymbolInfo};
+use super::Avma;
+
+use std::ops::Range;
+
+/// An index of the address ranges occupied by the segments of a set of
+/// shared libraries.
+///
+/// The map is a snapshot: it doesn't notice shared libraries being loaded or
+/// unloaded afterwards. Use `SharedLibrary::loaded_modules_generation` to
+/// find out when it needs rebuilding.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleMap {
+    modules: Vec<ModuleInfo>,
+    /// The non-empty segments of every module, sorted by start address, and
+    /// the index of the module each belongs to.
+    ranges: Vec<(Range<usize>, usize)>,
+    /// The greatest end of `ranges[..=i]`, so that lookups can stop searching
+    /// backwards through overlapping ranges once none can reach the address.
+    reach: Vec<usize>,
+}
+
+impl ModuleMap {
+    /// Build a map of the shared libraries currently loaded in this process.
+    #[inline]
+    pub fn new() -> Self {
+        ModuleMap::from(ProcessMap::new())
+    }
+
+    /// Build a map of the given modules.
+    pub fn from_modules(modules: Vec<ModuleInfo>) -> Self {
+        let mut ranges: Vec<_> = modules
+            .iter()
+            .enumerate()
+            .flat_map(|(index, module)| {
+                module
+                    .segments()
+                    .iter()
+                    .filter(|seg| !seg.is_empty())
+                    .map(move |seg| {
+                        let start = module.actual_virtual_memory_address(seg).0 as usize;
+                        (start..start.saturating_add(seg.len()), index)
+                    })
+            })
+            .collect();
+        ranges.sort_by_key(|(range, _)| range.start);
+        let reach = ranges
+            .iter()
+            .scan(0, |reach, (range, _)| {
+                *reach = range.end.max(*reach);
+                Some(*reach)
+            })
+            .collect();
+        ModuleMap { modules, ranges, reach }
+    }
+
+    /// Find the module with a segment containing the given address.
+    ///
+    /// If segments of several modules overlap, which only happens with
+    /// modules that didn't come from the loader, the module whose segment
+    /// containing the address starts last is found.
+    pub fn lookup(&self, address: Avma) -> Option<&ModuleInfo> {
+        let address = address.0 as usize;
+        let after = self.ranges.partition_point(|(range, _)| range.start <= address);
+        self.ranges[..after]
+            .iter()
+            .zip(&self.reach)
+            .rev()
+            .take_while(|(_, &reach)| address < reach)
+            .find(|((range, _), _)| address < range.end)
+            .map(|((_, index), _)| &self.modules[*index])
+    }
+
+    /// Find the nearest exported or dynamic symbol at or before the given
+    /// address, in the module containing it. See `ModuleInfo::symbol_at`.
+    #[inline]
+    pub fn symbol_at(&self, address: Avma) -> Option<SymbolInfo> {
+        self.lookup(address)?.symbol_at(address)
+    }
+
+    /// Get the modules in this map, in the order they were given.
+    #[inline]
+    pub fn modules(&self) -> &[ModuleInfo] {
+        &self.modules
+    }
+}
+
+impl From<ProcessMap> for ModuleMap {
+    #[inline]
+    fn from(map: ProcessMap) -> Self {
+        ModuleMap::from_modules(map.modules().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::snapshot::Source;
+
+    fn name_at(map: &ModuleMap, address: usize) -> Option<&str> {
+        map.lookup(Avma(address as *const u8))
+            .map(|module| module.name().to_str().unwrap())
+    }
+
+    #[test]
+    fn lookup_finds_containing_segment() {
+        let map = ModuleMap::from_modules(vec![
+            ModuleInfo::fake("b", &[(0x5000, 0x1000), (0x7000, 0x1000)], Source::MapsParse),
+            ModuleInfo::fake("a", &[(0x1000, 0x2000), (0x3000, 0)], Source::MapsParse),
+        ]);
+        assert_eq!(name_at(&map, 0xfff), None);
+        assert_eq!(name_at(&map, 0x1000), Some("a"));
+        assert_eq!(name_at(&map, 0x2fff), Some("a"));
+        assert_eq!(name_at(&map, 0x3000), None);
+        assert_eq!(name_at(&map, 0x5800), Some("b"));
+        assert_eq!(name_at(&map, 0x6000), None);
+        assert_eq!(name_at(&map, 0x7fff), Some("b"));
+        assert_eq!(name_at(&map, 0x8000), None);
+        assert_eq!(name_at(&map, usize::MAX), None);
+        assert_eq!(map.modules()[0].name().to_str(), Ok("b"));
+
+        assert_eq!(name_at(&ModuleMap::default(), 0x1000), None);
+
+        let map = ModuleMap::from_modules(vec![
+            ModuleInfo::fake("outer", &[(0x1000, 0x8000)], Source::MapsParse),
+            ModuleInfo::fake("inner", &[(0x2000, 0x1000)], Source::MapsParse),
+        ]);
+        assert_eq!(name_at(&map, 0x1000), Some("outer"));
+        assert_eq!(name_at(&map, 0x2000), Some("inner"));
+        assert_eq!(name_at(&map, 0x5000), Some("outer"));
+        assert_eq!(name_at(&map, 0x9000), None);
+    }
+
+    #[test]
+    fn lookup_in_this_process() {
+        let map = ModuleMap::new();
+        let function: fn() = lookup_in_this_process;
+        let module = map.lookup(Avma(function as *const u8));
+        assert_eq!(module.is_some(), ::TARGET_SUPPORTED);
+        if let Some(module) = module {
+            assert_eq!(Some(module), map.modules().first());
+        }
+        assert!(map.lookup(Avma(::std::ptr::null())).is_none());
+    }
+}
@@ -1,15 +1,24 @@
 //! The MacOS implementation of the [SharedLibrary
 //! trait](../trait.SharedLibrary.html).
 
-use super::{Bias, IterationControl, Svma, SharedLibraryId};
+use super::{dladdr_symbol, Avma, Bias, DebugInfoAvailability, IterationControl,
+            LockHazards, RelocationCounts, SegmentPermissions, Svma, SharedLibraryId, SharedLibraryVersion,
+            SectionInfo, Symbol, VolumeKind};
 use super::Segment as SegmentTrait;
 use super::SharedLibrary as SharedLibraryTrait;
+use super::trace;
 
-use std::ffi::CStr;
+use std::ffi::{CStr, OsStr};
+use std::io;
 use std::marker::PhantomData;
-use std::ptr;
-use std::sync::Mutex;
-use std::usize;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, Once};
+
+use libc;
 
 mod bindings;
 
@@ -20,9 +29,60 @@ lazy_static! {
     /// *you* must take this lock whenever dynamically adding or removing shared
     /// libraries to ensure that there are no races with iterating shared
     /// libraries.
+    ///
+    /// Don't `fork` while another thread holds this lock: the child only gets
+    /// a copy of the forking thread, and nothing would ever release it there.
     pub static ref DYLD_LOCK: Mutex<()> = Mutex::new(());
 }
 
+/// The number of images dyld has told us about being added or removed.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static REGISTER_GENERATION_CALLBACKS: Once = Once::new();
+
+const MH_EXECUTE: u32 = 0x2;
+
+// Not every SDK's headers know about chained fixups or init offsets yet.
+const LC_DYLD_CHAINED_FIXUPS: u32 = 0x8000_0034;
+
+const SECTION_TYPE: u32 = 0x0000_00ff;
+const S_ZEROFILL: u32 = 0x01;
+const S_GB_ZEROFILL: u32 = 0x0c;
+const S_THREAD_LOCAL_ZEROFILL: u32 = 0x12;
+const S_MOD_INIT_FUNC_POINTERS: u32 = 0x09;
+const S_MOD_TERM_FUNC_POINTERS: u32 = 0x0a;
+const S_INIT_FUNC_OFFSETS: u32 = 0x16;
+
+// From `<mach-o/stab.h>`.
+const N_SO: u8 = 0x64;
+
+// Mount flags from `<sys/mount.h>`; `libc` doesn't have `MNT_REMOVABLE`.
+const MNT_REMOVABLE: u32 = 0x0000_0200;
+const MNT_LOCAL: u32 = 0x0000_1000;
+
+const REBASE_OPCODE_DONE: u8 = 0x00;
+const REBASE_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB: u8 = 0x20;
+const REBASE_OPCODE_ADD_ADDR_ULEB: u8 = 0x30;
+const REBASE_OPCODE_DO_REBASE_IMM_TIMES: u8 = 0x50;
+const REBASE_OPCODE_DO_REBASE_ULEB_TIMES: u8 = 0x60;
+const REBASE_OPCODE_DO_REBASE_ADD_ADDR_ULEB: u8 = 0x70;
+const REBASE_OPCODE_DO_REBASE_ULEB_TIMES_SKIPPING_ULEB: u8 = 0x80;
+
+const BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB: u8 = 0x20;
+const BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM: u8 = 0x40;
+const BIND_OPCODE_SET_ADDEND_SLEB: u8 = 0x60;
+const BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB: u8 = 0x70;
+const BIND_OPCODE_ADD_ADDR_ULEB: u8 = 0x80;
+const BIND_OPCODE_DO_BIND: u8 = 0x90;
+const BIND_OPCODE_DO_BIND_ADD_ADDR_ULEB: u8 = 0xa0;
+const BIND_OPCODE_DO_BIND_ADD_ADDR_IMM_SCALED: u8 = 0xb0;
+const BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB: u8 = 0xc0;
+const BIND_OPCODE_THREADED: u8 = 0xd0;
+const BIND_SUBOPCODE_THREADED_SET_BIND_ORDINAL_TABLE_SIZE_ULEB: u8 = 0x00;
+
+unsafe extern "C" fn bump_generation(_header: *const bindings::mach_header, _slide: isize) {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
 /// A Mach-O segment.
 #[derive(Debug)]
 pub enum Segment<'a> {
@@ -64,66 +124,255 @@ impl<'a> SegmentTrait for Segment<'a> {
             }
         }
     }
+
+    #[inline]
+    fn file_size(&self) -> u64 {
+        match *self {
+            Segment::Segment32(seg) => u64::from(seg.filesize),
+            Segment::Segment64(seg) => seg.filesize,
+        }
+    }
+
+    #[inline]
+    fn file_offset(&self) -> u64 {
+        match *self {
+            Segment::Segment32(seg) => u64::from(seg.fileoff),
+            Segment::Segment64(seg) => seg.fileoff,
+        }
+    }
+
+    #[inline]
+    fn permissions(&self) -> SegmentPermissions {
+        const VM_PROT_READ: bindings::vm_prot_t = 1;
+        const VM_PROT_WRITE: bindings::vm_prot_t = 2;
+        const VM_PROT_EXECUTE: bindings::vm_prot_t = 4;
+
+        let prot = match *self {
+            Segment::Segment32(seg) => seg.initprot,
+            Segment::Segment64(seg) => seg.initprot,
+        };
+        SegmentPermissions {
+            read: prot & VM_PROT_READ != 0,
+            write: prot & VM_PROT_WRITE != 0,
+            execute: prot & VM_PROT_EXECUTE != 0,
+        }
+    }
+}
+
+/// The parts of a Mach-O section header that are the same for 32 and 64 bits.
+struct Section {
+    name: [u8; 16],
+    addr: usize,
+    size: usize,
+    offset: u32,
+    flags: u32,
+}
+
+impl Section {
+    /// Get the section's name, which isn't NUL-terminated if it is 16 bytes
+    /// long.
+    fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+}
+
+/// Copy a C section name, whichever signedness `c_char` has.
+fn section_name(name: &[::std::os::raw::c_char; 16]) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    for (byte, &c) in bytes.iter_mut().zip(name) {
+        *byte = c as u8;
+    }
+    bytes
+}
+
+impl<'a> Segment<'a> {
+    /// Get this segment's sections, whose headers follow the segment's load
+    /// command. The addresses are stated virtual memory addresses.
+    fn sections(&self) -> Vec<Section> {
+        match *self {
+            Segment::Segment32(seg) => {
+                let start = unsafe {
+                    (seg as *const bindings::segment_command).offset(1) as *const bindings::section
+                };
+                unsafe { slice::from_raw_parts(start, seg.nsects as usize) }
+                    .iter()
+                    .map(|sect| Section {
+                        name: section_name(&sect.sectname),
+                        addr: sect.addr as usize,
+                        size: sect.size as usize,
+                        offset: sect.offset,
+                        flags: sect.flags,
+                    })
+                    .collect()
+            }
+            Segment::Segment64(seg) => {
+                let start = unsafe {
+                    (seg as *const bindings::segment_command_64).offset(1)
+                        as *const bindings::section_64
+                };
+                unsafe { slice::from_raw_parts(start, seg.nsects as usize) }
+                    .iter()
+                    .map(|sect| Section {
+                        name: section_name(&sect.sectname),
+                        addr: sect.addr as usize,
+                        size: sect.size as usize,
+                        offset: sect.offset,
+                        flags: sect.flags,
+                    })
+                    .collect()
+            }
+        }
+    }
 }
 
 /// An iterator over Mach-O segments.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SegmentIter<'a> {
     phantom: PhantomData<&'a SharedLibrary<'a>>,
     commands: *const bindings::load_command,
     num_commands: usize,
+    num_segments: usize,
 }
 
 impl<'a> SegmentIter<'a> {
-    fn find_uuid(&self) -> Option<[u8; 16]> {
-        let mut num_commands = self.num_commands;
-        let mut commands = self.commands;
+    fn new(commands: *const bindings::load_command, num_commands: usize) -> Self {
+        let mut iter = SegmentIter {
+            phantom: PhantomData,
+            commands,
+            num_commands,
+            num_segments: 0,
+        };
 
-        while num_commands > 0 {
-            num_commands -= 1;
-            let this_command = unsafe { commands.as_ref().unwrap() };
-            let command_size = this_command.cmdsize as isize;
-            if let bindings::LC_UUID = this_command.cmd {
-                let uuid_cmd = commands as *const bindings::uuid_command;
-                return Some(unsafe { (*uuid_cmd).uuid });
+        // Load commands are variable-sized, so the only way to know how many
+        // segments there are is to walk them once up front.
+        let mut scan = iter.clone();
+        while let Some(command) = scan.next_command() {
+            if Self::segment(command).is_some() {
+                iter.num_segments += 1;
             }
-            commands = unsafe { (commands as *const u8).offset(command_size) as *const _ };
         }
 
+        iter
+    }
+
+    fn find_command(&self, cmd: u32) -> Option<&'a bindings::load_command> {
+        let mut scan = self.clone();
+        while let Some(command) = scan.next_command() {
+            if command.cmd == cmd {
+                return Some(command);
+            }
+        }
         None
     }
+
+    fn find_uuid(&self) -> Option<[u8; 16]> {
+        self.find_command(bindings::LC_UUID).map(|command| {
+            let uuid_cmd =
+                command as *const bindings::load_command as *const bindings::uuid_command;
+            unsafe { (*uuid_cmd).uuid }
+        })
+    }
+
+    fn find_dyld_info(&self) -> Option<&'a bindings::dyld_info_command> {
+        self.find_command(bindings::LC_DYLD_INFO_ONLY)
+            .or_else(|| self.find_command(bindings::LC_DYLD_INFO))
+            .map(|command| unsafe {
+                &*(command as *const bindings::load_command as *const bindings::dyld_info_command)
+            })
+    }
+
+    fn find_symtab(&self) -> Option<&'a bindings::symtab_command> {
+        self.find_command(bindings::LC_SYMTAB).map(|command| unsafe {
+            &*(command as *const bindings::load_command as *const bindings::symtab_command)
+        })
+    }
+
+    fn find_version(&self) -> Option<SharedLibraryVersion> {
+        self.find_command(bindings::LC_ID_DYLIB).map(|command| {
+            let dylib_cmd =
+                command as *const bindings::load_command as *const bindings::dylib_command;
+            // Encoded as a 32-bit `xxxx.yy.zz` nibble-packed version.
+            let version = unsafe { (*dylib_cmd).dylib.current_version };
+            SharedLibraryVersion {
+                major: version >> 16,
+                minor: (version >> 8) & 0xff,
+                patch: version & 0xff,
+            }
+        })
+    }
+
+    /// Advance past the next load command, whatever kind it is.
+    fn next_command(&mut self) -> Option<&'a bindings::load_command> {
+        if self.num_commands == 0 {
+            return None;
+        }
+        self.num_commands -= 1;
+
+        let this_command = unsafe { self.commands.as_ref().unwrap() };
+        let command_size = this_command.cmdsize as isize;
+        self.commands = unsafe { (self.commands as *const u8).offset(command_size) as *const _ };
+        Some(this_command)
+    }
+
+    /// Interpret the given load command as a segment, if it is one.
+    fn segment(command: &'a bindings::load_command) -> Option<Segment<'a>> {
+        let command_ptr = command as *const bindings::load_command;
+        match command.cmd {
+            bindings::LC_SEGMENT => {
+                let segment = command_ptr as *const bindings::segment_command;
+                Some(Segment::Segment32(unsafe { segment.as_ref().unwrap() }))
+            }
+            bindings::LC_SEGMENT_64 => {
+                let segment = command_ptr as *const bindings::segment_command_64;
+                Some(Segment::Segment64(unsafe { segment.as_ref().unwrap() }))
+            }
+            // Some other kind of load command.
+            _ => None,
+        }
+    }
 }
 
 impl<'a> Iterator for SegmentIter<'a> {
     type Item = Segment<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while self.num_commands > 0 {
-            self.num_commands -= 1;
-
-            let this_command = unsafe { self.commands.as_ref().unwrap() };
-            let command_size = this_command.cmdsize as isize;
-
-            match this_command.cmd {
-                bindings::LC_SEGMENT => {
-                    let segment = self.commands as *const bindings::segment_command;
-                    let segment = unsafe { segment.as_ref().unwrap() };
-                    self.commands =
-                        unsafe { (self.commands as *const u8).offset(command_size) as *const _ };
-                    return Some(Segment::Segment32(segment));
-                }
-                bindings::LC_SEGMENT_64 => {
-                    let segment = self.commands as *const bindings::segment_command_64;
-                    let segment = unsafe { segment.as_ref().unwrap() };
-                    self.commands =
-                        unsafe { (self.commands as *const u8).offset(command_size) as *const _ };
-                    return Some(Segment::Segment64(segment));
-                }
-                _ => {
-                    // Some other kind of load command; skip to the next one.
-                    self.commands =
-                        unsafe { (self.commands as *const u8).offset(command_size) as *const _ };
-                    continue;
+        if self.num_segments == 0 {
+            return None;
+        }
+
+        while let Some(command) = self.next_command() {
+            if let Some(segment) = Self::segment(command) {
+                self.num_segments -= 1;
+                return Some(segment);
+            }
+        }
+
+        None
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.num_segments, Some(self.num_segments))
+    }
+}
+
+impl<'a> DoubleEndedIterator for SegmentIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.num_segments == 0 {
+            return None;
+        }
+
+        // We can't walk load commands backwards, so find the last segment not
+        // yet yielded by scanning forward from the front.
+        let mut scan = self.clone();
+        let mut remaining = self.num_segments;
+        while let Some(command) = scan.next_command() {
+            if let Some(segment) = Self::segment(command) {
+                remaining -= 1;
+                if remaining == 0 {
+                    self.num_segments -= 1;
+                    return Some(segment);
                 }
             }
         }
@@ -132,6 +381,8 @@ impl<'a> Iterator for SegmentIter<'a> {
     }
 }
 
+impl<'a> ExactSizeIterator for SegmentIter<'a> {}
+
 #[derive(Debug)]
 enum MachType {
     Mach32,
@@ -150,7 +401,7 @@ impl MachType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum MachHeader<'a> {
     Header32(&'a bindings::mach_header),
     Header64(&'a bindings::mach_header_64),
@@ -174,7 +425,7 @@ impl<'a> MachHeader<'a> {
 /// This wraps the `_dyld_image_count` and
 /// `_dyld_get_image_{header,vmaddr_slide,name}` system APIs from the
 /// `<mach-o/dyld.h>` header.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SharedLibrary<'a> {
     header: MachHeader<'a>,
     slide: isize,
@@ -184,11 +435,55 @@ pub struct SharedLibrary<'a> {
 impl<'a> SharedLibrary<'a> {
     fn new(header: MachHeader<'a>, slide: isize, name: &'a CStr) -> Self {
         SharedLibrary {
-            header: header,
-            slide: slide,
-            name: name,
+            header,
+            slide,
+            name,
         }
     }
+
+    /// Get the address the Mach-O header is mapped at, which offsets in the
+    /// image are relative to.
+    fn header_address(&self) -> usize {
+        match self.header {
+            MachHeader::Header32(header) => header as *const _ as usize,
+            MachHeader::Header64(header) => header as *const _ as usize,
+        }
+    }
+
+    /// Get the address that file offsets into `__LINKEDIT` are relative to,
+    /// now that it is mapped.
+    fn linkedit_base(&self) -> Option<usize> {
+        let linkedit = self.segments().find(|seg| seg.name().to_bytes() == b"__LINKEDIT")?;
+        Some((linkedit.actual_virtual_memory_address(self).0 as usize)
+            .wrapping_sub(linkedit.file_offset() as usize))
+    }
+
+    /// Collect the function pointers in every section of the given type, and
+    /// if `offsets` is set, the functions referenced by the 32-bit offsets
+    /// from the header in every `S_INIT_FUNC_OFFSETS` section.
+    fn routines(&self, pointers_type: u32, offsets: bool) -> Vec<Avma> {
+        let header = self.header_address();
+        let mut routines = Vec::new();
+        for seg in self.segments() {
+            for sect in seg.sections() {
+                let start = sect.addr.wrapping_add(self.slide as usize);
+                let size = sect.size;
+                let section_type = sect.flags & SECTION_TYPE;
+                if section_type == pointers_type {
+                    let len = size / mem::size_of::<usize>();
+                    let pointers = unsafe { slice::from_raw_parts(start as *const usize, len) };
+                    routines.extend(pointers.iter().map(|&ptr| Avma(ptr as *const u8)));
+                } else if offsets && section_type == S_INIT_FUNC_OFFSETS {
+                    let len = size / mem::size_of::<u32>();
+                    let offsets = unsafe { slice::from_raw_parts(start as *const u32, len) };
+                    routines.extend(offsets.iter().map(|&offset| {
+                        Avma(header.wrapping_add(offset as usize) as *const u8)
+                    }));
+                }
+            }
+        }
+        routines
+    }
 }
 
 impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
@@ -204,27 +499,45 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
         self.segments().find_uuid().map(SharedLibraryId::Uuid)
     }
 
+    fn version(&self) -> Option<SharedLibraryVersion> {
+        self.segments().find_version()
+    }
+
+    fn debug_info_availability(&self) -> DebugInfoAvailability {
+        // Object files carry their DWARF in a `__DWARF` segment; linked images
+        // leave it behind and are matched with their dSYM bundle by UUID.
+        if self.segments().any(|seg| seg.name().to_bytes() == b"__DWARF") {
+            DebugInfoAvailability::Embedded
+        } else if self.segments().find_uuid().is_some() {
+            DebugInfoAvailability::External
+        } else {
+            DebugInfoAvailability::None
+        }
+    }
+
+    fn virtual_memory_size(&self) -> usize {
+        let (start, end) = self.segments()
+            .filter(|seg| seg.len() > 0 && seg.name().to_bytes() != b"__PAGEZERO")
+            .fold((usize::MAX, 0), |(start, end), seg| {
+                let svma = seg.stated_virtual_memory_address().0 as usize;
+                (start.min(svma), end.max(svma + seg.len()))
+            });
+        end.saturating_sub(start)
+    }
+
     fn segments(&self) -> Self::SegmentIter {
         match self.header {
             MachHeader::Header32(header) => {
                 let num_commands = header.ncmds;
                 let header = header as *const bindings::mach_header;
                 let commands = unsafe { header.offset(1) as *const bindings::load_command };
-                SegmentIter {
-                    phantom: PhantomData,
-                    commands: commands,
-                    num_commands: num_commands as usize,
-                }
+                SegmentIter::new(commands, num_commands as usize)
             }
             MachHeader::Header64(header) => {
                 let num_commands = header.ncmds;
                 let header = header as *const bindings::mach_header_64;
                 let commands = unsafe { header.offset(1) as *const bindings::load_command };
-                SegmentIter {
-                    phantom: PhantomData,
-                    commands: commands,
-                    num_commands: num_commands as usize,
-                }
+                SegmentIter::new(commands, num_commands as usize)
             }
         }
     }
@@ -234,7 +547,184 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
         Bias(self.slide)
     }
 
-    fn each<F, C>(mut f: F)
+    #[inline]
+    fn load_slide(&self) -> isize {
+        self.slide
+    }
+
+    fn header_bytes(&self) -> Option<&[u8]> {
+        let (start, len) = match self.header {
+            MachHeader::Header32(header) => (
+                header as *const _ as *const u8,
+                mem::size_of::<bindings::mach_header>() + header.sizeofcmds as usize,
+            ),
+            MachHeader::Header64(header) => (
+                header as *const _ as *const u8,
+                mem::size_of::<bindings::mach_header_64>() + header.sizeofcmds as usize,
+            ),
+        };
+        Some(unsafe { slice::from_raw_parts(start, len) })
+    }
+
+    fn compilation_directory(&self) -> Option<PathBuf> {
+        let symtab = self.segments().find_symtab()?;
+        let base = self.linkedit_base()?;
+        let entry_size = match self.header {
+            MachHeader::Header32(_) => mem::size_of::<u32>() * 3,
+            MachHeader::Header64(_) => mem::size_of::<u32>() * 2 + mem::size_of::<u64>(),
+        };
+        let (symbols, strings) = unsafe {
+            (slice::from_raw_parts(base.wrapping_add(symtab.symoff as usize) as *const u8,
+                                   symtab.nsyms as usize * entry_size),
+             slice::from_raw_parts(base.wrapping_add(symtab.stroff as usize) as *const u8,
+                                   symtab.strsize as usize))
+        };
+        stabs_compilation_directory(symbols, entry_size, strings)
+    }
+
+    fn volume_kind(&self) -> Option<VolumeKind> {
+        let mut stat: libc::statfs = unsafe { mem::zeroed() };
+        if unsafe { libc::statfs(self.name.as_ptr(), &mut stat) } != 0 {
+            trace::os_error("statfs", &io::Error::last_os_error());
+            return None;
+        }
+        Some(if stat.f_flags & MNT_LOCAL == 0 {
+            VolumeKind::Network
+        } else if stat.f_flags & MNT_REMOVABLE != 0 {
+            VolumeKind::Removable
+        } else {
+            VolumeKind::Local
+        })
+    }
+
+    fn is_main_executable(&self) -> bool {
+        let filetype = match self.header {
+            MachHeader::Header32(header) => header.filetype,
+            MachHeader::Header64(header) => header.filetype,
+        };
+        filetype == MH_EXECUTE
+    }
+
+    fn section_map(&self) -> Option<Vec<Vec<SectionInfo>>> {
+        Some(self
+            .segments()
+            .map(|seg| {
+                let start = seg.stated_virtual_memory_address().0 as usize;
+                let mut sections: Vec<_> = seg
+                    .sections()
+                    .iter()
+                    .map(|sect| {
+                        let zerofill = matches!(sect.flags & SECTION_TYPE,
+                                                S_ZEROFILL | S_GB_ZEROFILL | S_THREAD_LOCAL_ZEROFILL);
+                        SectionInfo {
+                            name: sect.name().to_vec(),
+                            svma: Svma(sect.addr as *const u8),
+                            len: sect.size,
+                            segment_offset: sect.addr.wrapping_sub(start),
+                            file_offset: if zerofill { None } else { Some(sect.offset as u64) },
+                        }
+                    })
+                    .collect();
+                sections.sort_by_key(|section| section.svma.0);
+                sections
+            })
+            .collect())
+    }
+
+    fn relocation_counts(&self) -> Option<RelocationCounts> {
+        let segments = self.segments();
+        if segments.find_command(LC_DYLD_CHAINED_FIXUPS).is_some() {
+            return None;
+        }
+        let info = segments.find_dyld_info()?;
+
+        // The opcodes live in `__LINKEDIT`, at the given offsets into the file.
+        let base = self.linkedit_base()?;
+        let opcodes = |offset: u32, size: u32| -> &[u8] {
+            if size == 0 {
+                return &[];
+            }
+            let start = base.wrapping_add(offset as usize) as *const u8;
+            unsafe { slice::from_raw_parts(start, size as usize) }
+        };
+
+        Some(RelocationCounts {
+            relative: count_rebases(opcodes(info.rebase_off, info.rebase_size)),
+            symbolic: count_binds(opcodes(info.bind_off, info.bind_size))
+                + count_binds(opcodes(info.weak_bind_off, info.weak_bind_size)),
+            lazy: count_binds(opcodes(info.lazy_bind_off, info.lazy_bind_size)),
+        })
+    }
+
+    fn init_routines(&self) -> Vec<Avma> {
+        self.routines(S_MOD_INIT_FUNC_POINTERS, true)
+    }
+
+    fn fini_routines(&self) -> Vec<Avma> {
+        self.routines(S_MOD_TERM_FUNC_POINTERS, false)
+    }
+
+    fn function_starts(&self) -> Vec<Avma> {
+        let unwind_info = self.segments()
+            .filter(|seg| seg.name().to_bytes() == b"__TEXT")
+            .flat_map(|seg| seg.sections())
+            .find(|sect| sect.name() == b"__unwind_info");
+        let sect = match unwind_info {
+            Some(sect) => sect,
+            None => return Vec::new(),
+        };
+
+        let start = sect.addr.wrapping_add(self.slide as usize) as *const u8;
+        let info = unsafe { slice::from_raw_parts(start, sect.size) };
+        let header = self.header_address();
+        let starts = unwind_info_function_starts(info);
+        if starts.is_empty() {
+            trace::unparsable(self.name, "__unwind_info");
+        }
+        starts
+            .into_iter()
+            .map(|offset| Avma(header.wrapping_add(offset as usize) as *const u8))
+            .collect()
+    }
+
+    fn symbol_at(&self, address: Avma) -> Option<Symbol<'_>> {
+        // `dladdr` would happily find the symbol in another shared library.
+        if !self.segments().any(|seg| seg.contains_avma(self, address)) {
+            return None;
+        }
+        dladdr_symbol(address)
+    }
+
+    fn loaded_modules_generation() -> u64 {
+        // Registering the add-image callback immediately invokes it for every
+        // image that is already loaded, which is fine: we only promise that
+        // the value changes.
+        REGISTER_GENERATION_CALLBACKS.call_once(|| unsafe {
+            bindings::_dyld_register_func_for_add_image(Some(bump_generation));
+            bindings::_dyld_register_func_for_remove_image(Some(bump_generation));
+        });
+        GENERATION.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn lock_hazards() -> LockHazards {
+        LockHazards {
+            loader_lock: true,
+            crate_lock: true,
+            allocator_lock: false,
+        }
+    }
+
+    #[inline]
+    fn signal_safe_lock_hazards() -> LockHazards {
+        LockHazards {
+            loader_lock: true,
+            crate_lock: false,
+            allocator_lock: false,
+        }
+    }
+
+    fn each<F, C>(f: F)
         where F: FnMut(&Self) -> C,
               C: Into<IterationControl>
     {
@@ -242,6 +732,27 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
         // else adds or removes shared libraries while we are iterating them.
         let _dyld_lock = DYLD_LOCK.lock();
 
+        let _span = trace::enumeration("each");
+        Self::each_unlocked(f, true);
+    }
+
+    unsafe fn each_unsafe_signal_safe<F, C>(f: F)
+        where F: FnMut(&Self) -> C,
+              C: Into<IterationControl>
+    {
+        // The `DYLD_LOCK` may be held by the thread that crashed, so don't
+        // take it.
+        Self::each_unlocked(f, false);
+    }
+}
+
+impl<'a> SharedLibrary<'a> {
+    /// Invoke `f` with each image dyld knows about, emitting `tracing`
+    /// events along the way if `traced` is set.
+    fn each_unlocked<F, C>(mut f: F, traced: bool)
+        where F: FnMut(&Self) -> C,
+              C: Into<IterationControl>
+    {
         let count = unsafe { bindings::_dyld_image_count() };
 
         for image_idx in 0..count {
@@ -254,26 +765,349 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
             if let Some(header) = unsafe { MachHeader::from_header_ptr(header) } {
                 assert!(slide != 0,
                         "If we have a header pointer, slide should be valid");
-                assert!(name != ptr::null(),
+                assert!(!name.is_null(),
                         "If we have a header pointer, name should be valid");
 
                 let name = unsafe { CStr::from_ptr(name) };
                 let shlib = SharedLibrary::new(header, slide, name);
+                if traced {
+                    trace::visited(&shlib);
+                }
 
                 match f(&shlib).into() {
                     IterationControl::Break => break,
-                    IterationControl::Continue => continue,
+                    IterationControl::Continue | IterationControl::Skip => continue,
+                }
+            } else if traced && !name.is_null() {
+                // The image was removed concurrently, or its header isn't a
+                // Mach-O header this backend understands.
+                trace::skipped(unsafe { CStr::from_ptr(name) }, "no valid Mach-O header");
+            }
+        }
+    }
+}
+
+/// Find the first `N_SO` stab naming a directory in a symbol table with
+/// `entry_size`-byte `nlist` entries.
+///
+/// The linker emits a directory stab followed by a file name stab for each
+/// source file in the debug map.
+fn stabs_compilation_directory(symbols: &[u8], entry_size: usize, strings: &[u8])
+                               -> Option<PathBuf> {
+    symbols
+        .chunks_exact(entry_size)
+        .filter(|symbol| symbol[4] == N_SO)
+        .filter_map(|symbol| {
+            let strx = u32::from_ne_bytes([symbol[0], symbol[1], symbol[2], symbol[3]]);
+            let name = strings.get(strx as usize..)?;
+            Some(&name[..name.iter().position(|&b| b == 0)?])
+        })
+        .find(|name| name.len() > 1 && name.ends_with(b"/"))
+        .map(|dir| PathBuf::from(OsStr::from_bytes(&dir[..dir.len() - 1])))
+}
+
+/// Read the function offsets from the second-level pages of a compact unwind
+/// info section, sorted and without duplicates.
+fn unwind_info_function_starts(info: &[u8]) -> Vec<u32> {
+    const UNWIND_SECOND_LEVEL_REGULAR: u32 = 2;
+    const UNWIND_SECOND_LEVEL_COMPRESSED: u32 = 3;
+
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes = info.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes = info.get(offset..offset.checked_add(2)?)?;
+        Some(u16::from_ne_bytes([bytes[0], bytes[1]]))
+    };
+
+    let mut starts = Vec::new();
+    if read_u32(0) != Some(1) {
+        return starts;
+    }
+    let (index_offset, index_count) = match (read_u32(20), read_u32(24)) {
+        (Some(offset), Some(count)) => (offset as usize, count as usize),
+        _ => return starts,
+    };
+
+    for i in 0..index_count {
+        // Each index entry is 12 bytes: the offset of its first function, the
+        // offset of its second-level page, and the offset of its LSDAs. The
+        // last one only marks the end of the last function, without a page.
+        let entry = index_offset + i * 12;
+        let (first_function, page) = match (read_u32(entry), read_u32(entry + 4)) {
+            (Some(first_function), Some(page)) if page != 0 => (first_function, page as usize),
+            _ => continue,
+        };
+        // Both kinds of pages start with their kind, and the offset and
+        // number of their entries.
+        let header = (read_u32(page), read_u16(page + 4), read_u16(page + 6));
+        let (kind, entries, count) = match header {
+            (Some(kind), Some(entries), Some(count)) => {
+                (kind, page + entries as usize, count as usize)
+            }
+            _ => continue,
+        };
+
+        match kind {
+            UNWIND_SECOND_LEVEL_REGULAR => {
+                // Pairs of a function offset and an encoding.
+                starts.extend((0..count).map_while(|j| read_u32(entries + j * 8)));
+            }
+            UNWIND_SECOND_LEVEL_COMPRESSED => {
+                // The low 24 bits are the offset from the index entry's first
+                // function, the high 8 bits index the encodings.
+                starts.extend((0..count).map_while(|j| {
+                    read_u32(entries + j * 4)
+                        .map(|entry| first_function.wrapping_add(entry & 0x00ff_ffff))
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    starts.sort_unstable();
+    starts.dedup();
+    starts
+}
+
+/// Read a LEB128 number, of which only the magnitude matters for signed ones.
+fn read_leb128(opcodes: &mut slice::Iter<'_, u8>) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    for &byte in opcodes {
+        if shift < 64 {
+            value |= u64::from(byte & 0x7f) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
+
+/// Count the rebases performed by a stream of rebase opcodes.
+fn count_rebases(opcodes: &[u8]) -> usize {
+    let mut opcodes = opcodes.iter();
+    let mut count = 0;
+    while let Some(&byte) = opcodes.next() {
+        let immediate = (byte & 0x0f) as usize;
+        match byte & 0xf0 {
+            REBASE_OPCODE_DONE => break,
+            REBASE_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB | REBASE_OPCODE_ADD_ADDR_ULEB => {
+                read_leb128(&mut opcodes);
+            }
+            REBASE_OPCODE_DO_REBASE_IMM_TIMES => count += immediate,
+            REBASE_OPCODE_DO_REBASE_ULEB_TIMES => count += read_leb128(&mut opcodes) as usize,
+            REBASE_OPCODE_DO_REBASE_ADD_ADDR_ULEB => {
+                read_leb128(&mut opcodes);
+                count += 1;
+            }
+            REBASE_OPCODE_DO_REBASE_ULEB_TIMES_SKIPPING_ULEB => {
+                count += read_leb128(&mut opcodes) as usize;
+                read_leb128(&mut opcodes);
+            }
+            // The other opcodes only set state from their immediate.
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Count the binds performed by a stream of bind, weak bind or lazy bind
+/// opcodes.
+///
+/// Lazy binds are separated by `BIND_OPCODE_DONE`, so unlike rebases, this
+/// reads the stream to its end.
+fn count_binds(opcodes: &[u8]) -> usize {
+    let mut opcodes = opcodes.iter();
+    let mut count = 0;
+    while let Some(&byte) = opcodes.next() {
+        match byte & 0xf0 {
+            BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB
+            | BIND_OPCODE_SET_ADDEND_SLEB
+            | BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB
+            | BIND_OPCODE_ADD_ADDR_ULEB => {
+                read_leb128(&mut opcodes);
+            }
+            BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM => {
+                // Skip the NUL-terminated symbol name.
+                for &byte in opcodes.by_ref() {
+                    if byte == 0 {
+                        break;
+                    }
                 }
             }
+            BIND_OPCODE_DO_BIND | BIND_OPCODE_DO_BIND_ADD_ADDR_IMM_SCALED => count += 1,
+            BIND_OPCODE_DO_BIND_ADD_ADDR_ULEB => {
+                read_leb128(&mut opcodes);
+                count += 1;
+            }
+            BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB => {
+                count += read_leb128(&mut opcodes) as usize;
+                read_leb128(&mut opcodes);
+            }
+            BIND_OPCODE_THREADED
+                if byte & 0x0f == BIND_SUBOPCODE_THREADED_SET_BIND_ORDINAL_TABLE_SIZE_ULEB =>
+            {
+                read_leb128(&mut opcodes);
+            }
+            // The other opcodes only set state from their immediate.
+            _ => {}
         }
     }
+    count
 }
 
 #[cfg(test)]
 mod tests {
     use macos;
+    use super::bindings;
     use super::super::{IterationControl, SharedLibrary, Segment};
 
+    #[test]
+    fn parse_unwind_info() {
+        use super::unwind_info_function_starts;
+
+        fn push(bytes: &mut Vec<u8>, values: &[u32]) {
+            for value in values {
+                bytes.extend_from_slice(&value.to_ne_bytes());
+            }
+        }
+
+        // The header, with the index at offset 28.
+        let mut info = Vec::new();
+        push(&mut info, &[1, 0, 0, 0, 0, 28, 3]);
+        // The index: a compressed page at 64, a regular page at 84, and the
+        // end marker.
+        push(&mut info, &[0x1000, 64, 0, 0x2000, 84, 0, 0x3000, 0, 0]);
+        assert_eq!(info.len(), 64);
+        // A compressed page with two entries and no local encodings.
+        push(&mut info, &[3]);
+        for &value in &[12u16, 2, 0, 0] {
+            info.extend_from_slice(&value.to_ne_bytes());
+        }
+        push(&mut info, &[0x0100_0000, 0x0200_0040]);
+        // A regular page with one entry.
+        push(&mut info, &[2]);
+        info.extend_from_slice(&8u16.to_ne_bytes());
+        info.extend_from_slice(&1u16.to_ne_bytes());
+        push(&mut info, &[0x2000, 0]);
+
+        assert_eq!(unwind_info_function_starts(&info), vec![0x1000, 0x1040, 0x2000]);
+        assert!(unwind_info_function_starts(&[]).is_empty());
+    }
+
+    #[test]
+    fn function_starts_are_sorted() {
+        macos::SharedLibrary::each(|shlib| {
+            let starts = shlib.function_starts();
+            assert!(starts.windows(2).all(|pair| pair[0] < pair[1]));
+        });
+    }
+
+    #[test]
+    fn text_section_is_in_text_segment() {
+        macos::SharedLibrary::each(|shlib| {
+            let map = shlib.section_map().unwrap();
+            assert_eq!(map.len(), shlib.segments().len());
+            let (sections, seg) = map
+                .iter()
+                .zip(shlib.segments())
+                .find(|(_, seg)| seg.name().to_bytes() == b"__TEXT")
+                .expect("should have a __TEXT segment");
+            let text = sections.iter().find(|section| section.name == b"__text").unwrap();
+            assert_eq!(text.svma.0 as usize,
+                       seg.stated_virtual_memory_address().0 as usize + text.segment_offset);
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn images_are_on_some_volume() {
+        macos::SharedLibrary::each(|shlib| {
+            assert!(shlib.volume_kind().is_some());
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn count_rebase_opcodes() {
+        use super::count_rebases;
+
+        let opcodes = [
+            0x11, // REBASE_OPCODE_SET_TYPE_IMM(REBASE_TYPE_POINTER)
+            0x22, 0x10, // REBASE_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB(2, 0x10)
+            0x53, // REBASE_OPCODE_DO_REBASE_IMM_TIMES(3)
+            0x60, 0x80, 0x01, // REBASE_OPCODE_DO_REBASE_ULEB_TIMES(128)
+            0x70, 0x08, // REBASE_OPCODE_DO_REBASE_ADD_ADDR_ULEB(8)
+            0x00, // REBASE_OPCODE_DONE
+            0x51, // Not reached.
+        ];
+        assert_eq!(count_rebases(&opcodes), 3 + 128 + 1);
+    }
+
+    #[test]
+    fn count_bind_opcodes() {
+        use super::count_binds;
+
+        let opcodes = [
+            0x11, // BIND_OPCODE_SET_DYLIB_ORDINAL_IMM(1)
+            0x40, b'_', b'f', 0x00, // BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM("_f")
+            0x51, // BIND_OPCODE_SET_TYPE_IMM(BIND_TYPE_POINTER)
+            0x72, 0x08, // BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB(2, 8)
+            0x90, // BIND_OPCODE_DO_BIND
+            0x00, // BIND_OPCODE_DONE, which separates lazy binds.
+            0x40, 0x90, 0x00, // BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM("\x90")
+            0xc0, 0x02, 0x08, // BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB(2, 8)
+        ];
+        assert_eq!(count_binds(&opcodes), 3);
+    }
+
+    #[test]
+    fn compilation_directory_from_stabs() {
+        use super::{stabs_compilation_directory, N_SO};
+
+        fn nlist(strx: u32, n_type: u8) -> Vec<u8> {
+            let mut entry = strx.to_ne_bytes().to_vec();
+            entry.extend_from_slice(&[n_type, 1, 0, 0]);
+            entry.extend_from_slice(&0u64.to_ne_bytes());
+            entry
+        }
+
+        let strings = b"\0_main\0/Users/me/project/\0src/main.rs\0";
+        let symbols: Vec<u8> = [nlist(1, 0x0f), nlist(7, N_SO), nlist(26, N_SO), nlist(0, N_SO)]
+            .concat();
+        assert_eq!(stabs_compilation_directory(&symbols, 16, strings),
+                   Some(::std::path::PathBuf::from("/Users/me/project")));
+        assert_eq!(stabs_compilation_directory(&symbols[..16], 16, strings), None);
+
+        macos::SharedLibrary::each(|shlib| {
+            let _ = shlib.compilation_directory();
+        });
+    }
+
+    #[test]
+    fn init_routines_are_executable() {
+        macos::SharedLibrary::each(|shlib| {
+            for routine in shlib.init_routines() {
+                assert!(shlib.segments().any(|seg| {
+                    seg.permissions().execute && seg.contains_avma(shlib, routine)
+                }));
+            }
+        });
+    }
+
+    #[test]
+    fn relocation_counts_for_every_image() {
+        macos::SharedLibrary::each(|shlib| {
+            if let Some(counts) = shlib.relocation_counts() {
+                assert_eq!(counts.total(), counts.relative + counts.symbolic + counts.lazy);
+            }
+        });
+    }
+
     #[test]
     fn have_libdyld() {
         let mut found_dyld = false;
@@ -322,6 +1156,19 @@ mod tests {
         });
     }
 
+    #[test]
+    fn libdyld_version() {
+        macos::SharedLibrary::each(|shlib| {
+            let is_libdyld = shlib.name
+                .to_bytes()
+                .split(|c| *c == b'.' || *c == b'/')
+                .any(|s| s == b"libdyld");
+            if is_libdyld {
+                assert!(shlib.version().is_some());
+            }
+        });
+    }
+
     #[test]
     fn have_text_or_pagezero() {
         macos::SharedLibrary::each(|shlib| {
@@ -337,4 +1184,56 @@ mod tests {
             assert!(found_text_or_pagezero);
         });
     }
+
+    #[test]
+    fn text_is_executable() {
+        macos::SharedLibrary::each(|shlib| {
+            for seg in shlib.segments() {
+                if seg.name().to_bytes() == b"__TEXT" {
+                    assert!(seg.permissions().execute);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn main_executable_text_starts_the_file() {
+        // Images in the shared cache report offsets into the cache, so only
+        // check the main executable.
+        macos::SharedLibrary::each(|shlib| {
+            for seg in shlib.segments() {
+                if seg.name().to_bytes() == b"__TEXT" {
+                    assert_eq!(seg.file_offset(), 0);
+                }
+            }
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn header_bytes_start_with_magic() {
+        macos::SharedLibrary::each(|shlib| {
+            let header = shlib.header_bytes().unwrap();
+            let magic = [header[0], header[1], header[2], header[3]];
+            let magic = u32::from_ne_bytes(magic);
+            assert!(magic == bindings::MH_MAGIC || magic == bindings::MH_MAGIC_64);
+        });
+    }
+
+    #[test]
+    fn segments_exact_size_and_reversible() {
+        macos::SharedLibrary::each(|shlib| {
+            let forward: Vec<_> = shlib.segments()
+                .map(|seg| seg.stated_virtual_memory_address())
+                .collect();
+            let mut backward: Vec<_> = shlib.segments()
+                .rev()
+                .map(|seg| seg.stated_virtual_memory_address())
+                .collect();
+            backward.reverse();
+
+            assert_eq!(shlib.segments().len(), forward.len());
+            assert_eq!(forward, backward);
+        });
+    }
 }
@@ -0,0 +1,122 @@
+//! Parallel iteration over the shared libraries loaded in this process.
+//!
+//! This module is only available with the `rayon` feature enabled.
+//!
+//! Unlike `SharedLibrary::each`, which hands out one shared library at a time
+//! while the platform's loader list is being walked, these functions first take
+//! a [`Snapshot`](./struct.Snapshot.html) of the loaded shared libraries and
+//! then process them in parallel on rayon's thread pool.
+
+use rayon::prelude::*;
+
+use snapshot::{self, ModuleInfo};
+
+/// A point-in-time snapshot of the shared libraries loaded in this process.
+///
+/// The snapshot holds owned copies of each shared library's information, so
+/// it stays valid even if some of its shared libraries are unloaded after it
+/// was taken.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    modules: Vec<ModuleInfo>,
+}
+
+impl Snapshot {
+    /// Take a snapshot of the shared libraries currently loaded in this
+    /// process.
+    pub fn new() -> Self {
+        Snapshot {
+            modules: snapshot::loaded_modules(),
+        }
+    }
+
+    /// Get the number of shared libraries in this snapshot.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// Is this snapshot empty?
+    ///
+    /// This is always the case on unsupported platforms.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    /// Get a parallel iterator over the shared libraries in this snapshot.
+    #[inline]
+    pub fn par_iter(&self) -> impl IndexedParallelIterator<Item = &ModuleInfo> {
+        self.modules.par_iter()
+    }
+}
+
+impl Default for Snapshot {
+    #[inline]
+    fn default() -> Self {
+        Snapshot::new()
+    }
+}
+
+/// Find all shared libraries in this process and invoke `f` with each one, in
+/// parallel.
+///
+/// There is no ordering between invocations of `f`, and iteration cannot be
+/// stopped early. If `f` panics, the panic is propagated to the caller once all
+/// other invocations have finished.
+pub fn par_each<F>(f: F)
+where
+    F: Fn(&ModuleInfo) + Sync + Send,
+{
+    Snapshot::new().par_iter().for_each(f);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{SharedLibrary, TargetSharedLibrary};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn par_each_visits_every_library() {
+        // Other tests load and unload libraries concurrently, so count again
+        // if anything was loaded or unloaded in between.
+        for _ in 0..100 {
+            let generation = TargetSharedLibrary::loaded_modules_generation();
+            let mut expected = 0;
+            TargetSharedLibrary::each(|_| {
+                expected += 1;
+            });
+
+            let count = AtomicUsize::new(0);
+            par_each(|_| {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+            if TargetSharedLibrary::loaded_modules_generation() == generation {
+                assert_eq!(count.load(Ordering::SeqCst), expected);
+                return;
+            }
+        }
+        panic!("shared libraries kept being loaded or unloaded");
+    }
+
+    #[test]
+    fn snapshot_par_iter_visits_every_library() {
+        let snapshot = Snapshot::new();
+        let count = AtomicUsize::new(0);
+        snapshot.par_iter().for_each(|_| {
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(count.load(Ordering::SeqCst), snapshot.len());
+    }
+
+    #[test]
+    fn snapshot_par_iter_names() {
+        let snapshot = Snapshot::new();
+        let names: Vec<_> = snapshot
+            .par_iter()
+            .map(|shlib| shlib.name().to_owned())
+            .collect();
+        assert_eq!(names.len(), snapshot.len());
+    }
+}
@@ -0,0 +1,176 @@
+//! Policies for turning the names the loader reports into paths.
+//!
+//! The loader reports shared libraries by the path they were loaded from,
+//! which may go through symlinks, differ in case from the file on disk, or
+//! be empty for the main executable on Linux. What the right name is depends
+//! on the consumer: a report shown to a person wants the loader's name, while
+//! a cache key wants one name per file. A [`PathPolicy`](./trait.PathPolicy.html)
+//! makes that choice once, and is honored by
+//! `snapshot::EnumerateOptions::path_policy` (and so `ModuleInfo::name` and
+//! everything exported from snapshots), `filter::Filter::path_policy` and
+//! `report::Options::path_policy`.
+//!
+//! ```
+//! use findshlibs::path::{PathPolicy, ResolveSymlinks};
+//! use findshlibs::{SharedLibrary, TargetSharedLibrary};
+//!
+//! TargetSharedLibrary::each(|shlib| {
+//!     println!("{}", ResolveSymlinks.apply(shlib.name()).to_string_lossy());
+//! });
+//! ```
+
+use super::filter::NameMatching;
+
+use std::borrow::Cow;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fmt::Debug;
+use std::fs;
+
+/// A way of turning the name the loader reports for a shared library into
+/// the name consumers see.
+pub trait PathPolicy: Debug + Send + Sync {
+    /// Get the name to use for the shared library the loader reports as
+    /// `name`.
+    fn apply<'a>(&self, name: &'a CStr) -> Cow<'a, CStr>;
+}
+
+/// Keep the names the loader reports, which is what happens without a
+/// policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LoaderReported;
+
+impl PathPolicy for LoaderReported {
+    #[inline]
+    fn apply<'a>(&self, name: &'a CStr) -> Cow<'a, CStr> {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Resolve symlinks and relative components, so that each file has one name.
+///
+/// The empty name of the main executable on Linux is resolved to the path of
+/// its file. Names that can't be resolved, such as those of files that were
+/// deleted or of the vDSO, are kept as the loader reports them. Each name
+/// takes a few system calls to resolve.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ResolveSymlinks;
+
+impl PathPolicy for ResolveSymlinks {
+    fn apply<'a>(&self, name: &'a CStr) -> Cow<'a, CStr> {
+        let path = match name.to_bytes() {
+            b"" if cfg!(target_os = "linux") => OsStr::new("/proc/self/exe"),
+            b"" => return Cow::Borrowed(name),
+            bytes => match os_str(bytes) {
+                Some(path) => path,
+                None => return Cow::Borrowed(name),
+            },
+        };
+        fs::canonicalize(path)
+            .ok()
+            .and_then(|path| into_bytes(path.into_os_string()))
+            .and_then(|bytes| CString::new(bytes).ok())
+            .map_or(Cow::Borrowed(name), Cow::Owned)
+    }
+}
+
+#[cfg(unix)]
+fn os_str(bytes: &[u8]) -> Option<&OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_str(bytes: &[u8]) -> Option<&OsStr> {
+    ::std::str::from_utf8(bytes).ok().map(OsStr::new)
+}
+
+#[cfg(unix)]
+fn into_bytes(path: OsString) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(path.into_vec())
+}
+
+#[cfg(not(unix))]
+fn into_bytes(path: OsString) -> Option<Vec<u8>> {
+    path.into_string().ok().map(String::into_bytes)
+}
+
+/// Normalize names with the given `NameMatching`, such as to lowercase them
+/// on file systems that ignore case.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Normalize(pub NameMatching);
+
+impl PathPolicy for Normalize {
+    fn apply<'a>(&self, name: &'a CStr) -> Cow<'a, CStr> {
+        match self.0.normalize(name.to_bytes()) {
+            Cow::Borrowed(_) => Cow::Borrowed(name),
+            // Neither lowercasing nor decomposing produces NUL bytes.
+            Cow::Owned(bytes) => CString::new(bytes).map_or(Cow::Borrowed(name), Cow::Owned),
+        }
+    }
+}
+
+/// Apply one policy and then the other, such as to resolve symlinks and then
+/// normalize the result.
+impl<A: PathPolicy, B: PathPolicy> PathPolicy for (A, B) {
+    fn apply<'a>(&self, name: &'a CStr) -> Cow<'a, CStr> {
+        match self.0.apply(name) {
+            Cow::Borrowed(name) => self.1.apply(name),
+            Cow::Owned(name) => Cow::Owned(self.1.apply(&name).into_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cstr(bytes: &[u8]) -> &CStr {
+        CStr::from_bytes_with_nul(bytes).unwrap()
+    }
+
+    #[test]
+    fn loader_reported_keeps_names() {
+        let name = cstr(b"/usr/lib/../lib/libfoo.so\0");
+        assert_eq!(LoaderReported.apply(name), Cow::Borrowed(name));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlinks() {
+        use std::env;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::symlink;
+
+        let dir = env::temp_dir().join(format!("findshlibs-path-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("libtarget.so");
+        let link = dir.join("liblink.so");
+        fs::write(&target, b"").unwrap();
+        let _ = fs::remove_file(&link);
+        symlink(&target, &link).unwrap();
+
+        let name = CString::new(link.as_os_str().as_bytes()).unwrap();
+        let resolved = ResolveSymlinks.apply(&name);
+        let expected = fs::canonicalize(&target).unwrap();
+        assert_eq!(resolved.to_bytes(), expected.as_os_str().as_bytes());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let missing = cstr(b"/surely/this/does/not/exist.so\0");
+        assert_eq!(ResolveSymlinks.apply(missing), Cow::Borrowed(missing));
+        if cfg!(target_os = "linux") {
+            assert!(!ResolveSymlinks.apply(cstr(b"\0")).to_bytes().is_empty());
+        }
+    }
+
+    #[test]
+    fn normalize_and_chain() {
+        let name = cstr(b"/Lib/LibFoo.so\0");
+        let lowercase = Normalize(NameMatching::new().case_insensitive(true));
+        assert_eq!(lowercase.apply(name).to_bytes(), b"/lib/libfoo.so");
+        assert_eq!(Normalize(NameMatching::new()).apply(name), Cow::Borrowed(name));
+
+        let chained = (LoaderReported, lowercase);
+        assert_eq!(chained.apply(name).to_bytes(), b"/lib/libfoo.so");
+    }
+}
@@ -0,0 +1,418 @@
+//! Approximate load and unload events by polling.
+//!
+//! None of the supported platforms' loaders offer a portable way to be told
+//! when shared libraries come and go. As a fallback, a [`Poller`] periodically
+//! re-enumerates the loaded shared libraries on a background thread and
+//! reports the differences between consecutive snapshots as [`Event`]s.
+//!
+//! Because it only compares snapshots, a shared library that is loaded and
+//! unloaded again between two polls is never reported.
+//!
+//! [`Poller::watch`] only reports the shared libraries that match a
+//! [`Filter`] by name, debug-id or other conditions, such as for plugin
+//! frameworks waiting for their plugins:
+//!
+//! ```
+//! use findshlibs::filter::Filter;
+//! use findshlibs::poll::{Event, Poller};
+//! use std::time::Duration;
+//!
+//! let filter = Filter::new().name_glob("libplugin_*.so");
+//! let poller = Poller::watch(Duration::from_millis(100), filter, |event| match event {
+//!     Event::Loaded(module) => println!("loaded {:?}", module.name()),
+//!     Event::Unloaded(module) => println!("unloaded {:?}", module.name()),
+//! }).unwrap();
+//! poller.stop();
+//! ```
+//!
+//! A forked child doesn't inherit its parent's threads, so a `Poller` that
+//! was running in the parent reports nothing in the child. Spawn a new one
+//! there if needed.
+//!
+//! [`Poller`]: ./struct.Poller.html
+//! [`Poller::watch`]: ./struct.Poller.html#method.watch
+//! [`Filter`]: ../filter/struct.Filter.html
+//! [`Event`]: ./enum.Event.html
+
+use super::filter::Filter;
+use super::snapshot::{loaded_modules_matching, ModuleInfo};
+use super::{Bias, SharedLibrary, TargetSharedLibrary};
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// A change to the set of loaded shared libraries.
+///
+/// The modules carry their load generations, so an `Unloaded` event's
+/// `ModuleInfo::tagged_base` identifies exactly which cached attributions
+/// went stale, even if another module is loaded at the same base.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A shared library was loaded since the last poll.
+    Loaded(ModuleInfo),
+    /// A shared library was unloaded since the last poll.
+    Unloaded(ModuleInfo),
+}
+
+/// A background thread polling for loaded and unloaded shared libraries.
+///
+/// The thread is stopped when the `Poller` is dropped. Dropping a copy of
+/// a `Poller` in a forked child leaves the parent's thread alone.
+#[derive(Debug)]
+pub struct Poller {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    /// The process the thread was spawned in.
+    pid: u32,
+}
+
+impl Poller {
+    /// Spawn a background thread that checks for changes every `interval` and
+    /// invokes `f` with each one.
+    ///
+    /// Only changes after the poller was spawned are reported; the shared
+    /// libraries that are already loaded don't produce `Loaded` events.
+    #[inline]
+    pub fn spawn<F>(interval: Duration, f: F) -> io::Result<Poller>
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        Poller::watch(interval, Filter::new(), f)
+    }
+
+    /// Like `spawn`, but only report changes to shared libraries that match
+    /// `filter`.
+    ///
+    /// The filter is checked while enumerating, so only matching shared
+    /// libraries are compared between polls.
+    pub fn watch<F>(interval: Duration, filter: Filter, mut f: F) -> io::Result<Poller>
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let mut generation = TargetSharedLibrary::loaded_modules_generation();
+        let mut previous = loaded_modules_matching(&filter);
+
+        let thread = thread::Builder::new()
+            .name("findshlibs-poller".into())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::SeqCst) {
+                    thread::park_timeout(interval);
+                    if thread_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    // Skip re-enumerating if nothing could have changed.
+                    let current_generation = TargetSharedLibrary::loaded_modules_generation();
+                    if current_generation == generation {
+                        continue;
+                    }
+                    generation = current_generation;
+
+                    let mut current = loaded_modules_matching(&filter);
+                    stamp_load_times(&previous, &mut current, SystemTime::now());
+                    for event in diff(&previous, &current) {
+                        f(event);
+                    }
+                    previous = current;
+                }
+            })?;
+
+        Ok(Poller {
+            stop,
+            thread: Some(thread),
+            pid: process::id(),
+        })
+    }
+
+    /// Stop polling and wait for the background thread to exit.
+    #[inline]
+    pub fn stop(self) {
+        // The work happens in `Drop`.
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            // In a forked child the thread doesn't exist, and its handle
+            // mustn't be joined or detached.
+            if process::id() != self.pid {
+                mem::forget(thread);
+                return;
+            }
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Modules are considered the same if they have the same name and were loaded
+/// at the same address.
+fn key(module: &ModuleInfo) -> (&CStr, Bias) {
+    (module.name(), module.virtual_memory_bias())
+}
+
+/// Carry over the load times of modules that were already loaded, and stamp
+/// new ones with `now`.
+fn stamp_load_times(previous: &[ModuleInfo], current: &mut [ModuleInfo], now: SystemTime) {
+    let load_times: HashMap<_, _> = previous
+        .iter()
+        .map(|module| (key(module), module.load_time()))
+        .collect();
+    for module in current {
+        let load_time = match load_times.get(&key(module)) {
+            Some(load_time) => *load_time,
+            None => Some(now),
+        };
+        module.set_load_time(load_time);
+    }
+}
+
+fn diff(previous: &[ModuleInfo], current: &[ModuleInfo]) -> Vec<Event> {
+    let previous_keys: HashSet<_> = previous.iter().map(key).collect();
+    let current_keys: HashSet<_> = current.iter().map(key).collect();
+
+    let unloaded = previous
+        .iter()
+        .filter(|module| !current_keys.contains(&key(module)))
+        .map(|module| Event::Unloaded(module.clone()));
+    let loaded = current
+        .iter()
+        .filter(|module| !previous_keys.contains(&key(module)))
+        .map(|module| Event::Loaded(module.clone()));
+
+    unloaded.chain(loaded).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::snapshot::loaded_modules;
+
+    #[test]
+    fn diff_of_identical_snapshots_is_empty() {
+        let modules = loaded_modules();
+        assert!(diff(&modules, &modules).is_empty());
+    }
+
+    #[test]
+    fn diff_reports_loads_and_unloads() {
+        let modules = loaded_modules();
+        if modules.is_empty() {
+            return;
+        }
+
+        let events = diff(&modules[1..], &modules);
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            Event::Loaded(ref module) => assert_eq!(module.name(), modules[0].name()),
+            ref other => panic!("expected a load event, got {:?}", other),
+        }
+
+        let events = diff(&modules, &modules[1..]);
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            Event::Unloaded(ref module) => assert_eq!(module.name(), modules[0].name()),
+            ref other => panic!("expected an unload event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn new_modules_get_load_times() {
+        let mut modules = loaded_modules();
+        if modules.is_empty() {
+            return;
+        }
+
+        let previous = modules[1..].to_vec();
+        let now = SystemTime::now();
+        stamp_load_times(&previous, &mut modules, now);
+        assert_eq!(modules[0].load_time(), Some(now));
+        assert!(modules[1..].iter().all(|module| module.load_time().is_none()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn forked_children_can_drop_pollers() {
+        use libc;
+
+        let poller = Poller::spawn(Duration::from_millis(1), |_| {}).unwrap();
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+            0 => {
+                drop(poller);
+                unsafe { libc::_exit(0) };
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+                poller.stop();
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn poller_sees_dlopen() {
+        use libc;
+        use std::sync::mpsc;
+        use std::time::Instant;
+
+        fn is_libm(module: &ModuleInfo) -> bool {
+            module.name().to_bytes().ends_with(b"libm.so.6")
+        }
+
+        if loaded_modules().iter().any(is_libm) {
+            return;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let poller = Poller::spawn(Duration::from_millis(10), move |event| {
+            let _ = sender.send(event);
+        }).unwrap();
+
+        let handle = unsafe { libc::dlopen("libm.so.6\0".as_ptr() as _, libc::RTLD_NOW) };
+        if handle.is_null() {
+            return;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut found = false;
+        while !found && Instant::now() < deadline {
+            if let Ok(Event::Loaded(module)) = receiver.recv_timeout(Duration::from_millis(100)) {
+                found = is_libm(&module);
+                assert!(module.load_time().is_some());
+            }
+        }
+
+        poller.stop();
+        unsafe {
+            libc::dlclose(handle);
+        }
+        assert!(found, "should have seen libm being loaded");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watch_only_reports_matching_modules() {
+        use libc;
+        use std::sync::mpsc;
+        use std::time::Instant;
+
+        fn is_libanl(module: &ModuleInfo) -> bool {
+            module.name().to_bytes().ends_with(b"libanl.so.1")
+        }
+
+        if loaded_modules().iter().any(is_libanl) {
+            return;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let filter = Filter::new().name_glob("libanl.so*");
+        let poller = Poller::watch(Duration::from_millis(10), filter, move |event| {
+            let _ = sender.send(event);
+        }).unwrap();
+
+        let handle = unsafe { libc::dlopen("libanl.so.1\0".as_ptr() as _, libc::RTLD_NOW) };
+        if handle.is_null() {
+            return;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut found = false;
+        while !found && Instant::now() < deadline {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(Event::Loaded(module)) => found = is_libanl(&module),
+                Ok(Event::Unloaded(module)) => assert!(is_libanl(&module)),
+                Err(_) => {}
+            }
+        }
+
+        poller.stop();
+        unsafe {
+            libc::dlclose(handle);
+        }
+        assert!(found, "should have seen libanl being loaded");
+        assert!(receiver.try_iter().all(|event| match event {
+            Event::Loaded(module) | Event::Unloaded(module) => is_libanl(&module),
+        }));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watch_reports_modules_by_id() {
+        use libc;
+        use std::sync::mpsc;
+        use std::time::Instant;
+
+        fn is_broken_locale(module: &ModuleInfo) -> bool {
+            module.name().to_bytes().ends_with(b"libBrokenLocale.so.1")
+        }
+
+        if loaded_modules().iter().any(is_broken_locale) {
+            return;
+        }
+        let name = "libBrokenLocale.so.1\0";
+        let open = || unsafe { libc::dlopen(name.as_ptr() as _, libc::RTLD_NOW) };
+        let handle = open();
+        if handle.is_null() {
+            return;
+        }
+        let id = loaded_modules()
+            .into_iter()
+            .find(is_broken_locale)
+            .and_then(|module| module.id().cloned());
+        unsafe {
+            libc::dlclose(handle);
+        }
+        let id = match id {
+            Some(id) if !loaded_modules().iter().any(is_broken_locale) => id,
+            _ => return,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let filter = Filter::new().id(id.clone());
+        let poller = Poller::watch(Duration::from_millis(10), filter, move |event| {
+            let _ = sender.send(event);
+        }).unwrap();
+
+        let handle = open();
+        assert!(!handle.is_null());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut found = false;
+        while !found && Instant::now() < deadline {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(Event::Loaded(module)) | Ok(Event::Unloaded(module)) => {
+                    assert_eq!(module.id(), Some(&id));
+                    found = is_broken_locale(&module);
+                }
+                Err(_) => {}
+            }
+        }
+
+        poller.stop();
+        unsafe {
+            libc::dlclose(handle);
+        }
+        assert!(found, "should have seen libBrokenLocale being loaded");
+    }
+
+    #[test]
+    fn poller_stops() {
+        let poller = Poller::spawn(Duration::from_millis(10), |_| {}).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        poller.stop();
+    }
+}
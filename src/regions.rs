@@ -0,0 +1,1815 @@
+//! Executable memory that doesn't belong to any loaded shared library.
+//!
+//! JIT compilers, trampolines and injected code live in executable mappings
+//! that the loader knows nothing about. Profilers and symbolizers can use
+//! [`unknown_executable_regions`](./fn.unknown_executable_regions.html) to
+//! tell "JIT code" apart from addresses that are genuinely unknown.
+//!
+//! This is currently only implemented on Linux, by reading `/proc/self/maps`.
+//! On other platforms an error of kind `io::ErrorKind::Other` is returned.
+//!
+//! The memory map and the set of loaded shared libraries are read one after
+//! the other, so a shared library that is loaded or unloaded concurrently may
+//! briefly show up as an unknown region.
+//!
+//! [`scan_for_images`](./fn.scan_for_images.html) goes one step further and
+//! looks for image headers in memory, to find code that was mapped without
+//! going through the loader.
+//!
+//! [`classify_addr`](./fn.classify_addr.html) puts the two together to tell
+//! what a single address, such as that of a crash, points into, along with
+//! the bounds of every thread's stack from
+//! [`thread_stacks`](./fn.thread_stacks.html).
+//!
+//! A [`ThreadSnapshot`](./struct.ThreadSnapshot.html) pairs the loaded
+//! modules with those stacks, which is the context walking the stacks offline
+//! needs.
+
+use super::lookup::ModuleMap;
+use super::snapshot::{EnumerateOptions, ModuleInfo, ProcessMap, SegmentInfo, Source};
+use super::{trace, Avma, ManagedImageKind, SegmentPermissions, Svma};
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::process;
+
+/// A mapped region of memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    range: Range<usize>,
+    permissions: SegmentPermissions,
+    file_offset: u64,
+    path: Option<String>,
+}
+
+impl Region {
+    /// Get the half-open range of addresses this region occupies.
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// Get the current memory protection of this region.
+    #[inline]
+    pub fn permissions(&self) -> SegmentPermissions {
+        self.permissions
+    }
+
+    /// Get the offset within the backing file at which this region starts, or
+    /// zero for anonymous memory.
+    #[inline]
+    pub fn file_offset(&self) -> u64 {
+        self.file_offset
+    }
+
+    /// Get the file or pseudo-file (such as `[vsyscall]`) backing this region,
+    /// if any.
+    #[inline]
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_ref().map(|path| &path[..])
+    }
+
+    /// Is this region anonymous memory, as used by JIT compilers?
+    #[inline]
+    pub fn is_anonymous(&self) -> bool {
+        self.path.is_none()
+    }
+}
+
+/// The executable file format of an image found by
+/// [`scan_for_images`](./fn.scan_for_images.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    /// An ELF image.
+    Elf,
+    /// A 32- or 64-bit Mach-O image.
+    MachO,
+    /// A PE image.
+    Pe,
+}
+
+/// The width and byte order of an image's headers.
+///
+/// Images found by scanning memory needn't match this process: a 64-bit
+/// process can map 32-bit images, such as an emulator running them, or big
+/// endian ones it only inspects. Their headers are parsed with their own
+/// layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImageLayout {
+    /// The width of the addresses in the headers, 32 or 64 bits.
+    pub address_bits: u8,
+    /// Whether the headers' integers are big endian.
+    pub big_endian: bool,
+}
+
+impl ImageLayout {
+    /// Get the layout of this process's own images.
+    #[inline]
+    pub fn native() -> Self {
+        ImageLayout {
+            address_bits: (mem::size_of::<usize>() * 8) as u8,
+            big_endian: cfg!(target_endian = "big"),
+        }
+    }
+}
+
+/// An image header found by scanning memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScannedImage {
+    address: usize,
+    format: ImageFormat,
+    region: Region,
+}
+
+impl ScannedImage {
+    /// Get the address of the image's header.
+    #[inline]
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// Get the format of the image's header.
+    #[inline]
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Get the memory region containing the image's header.
+    #[inline]
+    pub fn region(&self) -> &Region {
+        &self.region
+    }
+
+    /// Get how this image was discovered, which is always
+    /// `Source::MemoryScan`.
+    #[inline]
+    pub fn discovered_by(&self) -> Source {
+        Source::MemoryScan
+    }
+
+    /// Get the width and byte order of the image's headers, or `None` if they
+    /// are malformed.
+    pub fn layout(&self) -> Option<ImageLayout> {
+        image_layout(self.format, self.reader())
+    }
+
+    /// Get the number of bytes the image spans in memory, from the lowest to
+    /// the highest address of its segments or sections, as stated by its
+    /// headers. Returns `None` if they are malformed or can't be read.
+    ///
+    /// ELF program headers are located by their file offset, which assumes
+    /// they are mapped along with the ELF header, as linkers arrange.
+    pub fn image_size(&self) -> Option<u64> {
+        image_size(self.format, self.reader())
+    }
+
+    /// Read the image's memory `rva` bytes past its header.
+    fn reader(&self) -> impl FnMut(usize, &mut [u8]) -> bool {
+        let address = self.address;
+        move |rva, buf| {
+            let len = buf.len();
+            address
+                .checked_add(rva)
+                .is_some_and(|address| imp::read_memory(address, buf).len() == len)
+        }
+    }
+
+    /// Find out whether this is a ReadyToRun image of precompiled .NET code.
+    ///
+    /// The .NET runtime maps ReadyToRun images itself on Linux and macOS, so
+    /// they only show up here. This assumes the image is mapped with its
+    /// sections at their relative virtual addresses, as the runtime does.
+    pub fn managed_image_kind(&self) -> Option<ManagedImageKind> {
+        if self.format != ImageFormat::Pe {
+            return None;
+        }
+        pe_managed_image_kind(self.reader())
+    }
+}
+
+/// Get every mapped memory region of this process, in address order.
+pub fn memory_regions() -> io::Result<Vec<Region>> {
+    imp::memory_regions()
+}
+
+/// Get the executable memory regions of this process that don't overlap any
+/// loaded shared library's segments, in address order.
+pub fn unknown_executable_regions() -> io::Result<Vec<Region>> {
+    let regions = memory_regions()?;
+    let ranges = module_ranges();
+
+    Ok(regions
+        .into_iter()
+        .filter(|region| region.permissions.execute)
+        .filter(|region| {
+            !ranges
+                .iter()
+                .any(|range| range.start < region.range.end && region.range.start < range.end)
+        })
+        .collect())
+}
+
+/// Reconstruct modules from the file-backed mappings in the memory map, in
+/// address order of their first mapping.
+///
+/// Each file with at least one executable mapping becomes one module, with a
+/// segment per mapping. The segments' addresses are actual addresses, so the
+/// bias is always zero, and there is no debug-id. The whole of each mapping
+/// counts as file-backed, because zero-filled tails are mapped anonymously
+/// and don't show up under the file's path. Every module's source is
+/// `Source::MapsParse`.
+///
+/// This sees files that were mapped without going through the loader, but
+/// unlike `SharedLibrary::each`, it can't tell apart two copies of the same
+/// file, and it reports the path the file had when it was mapped.
+pub fn mapped_modules() -> io::Result<Vec<ModuleInfo>> {
+    let mut files: Vec<(String, Vec<Region>)> = Vec::new();
+    for region in memory_regions()? {
+        let path = match region.path() {
+            Some(path) if !path.starts_with('[') => path.to_owned(),
+            _ => continue,
+        };
+        match files.iter_mut().find(|file| file.0 == path) {
+            Some(file) => file.1.push(region),
+            None => files.push((path, vec![region])),
+        }
+    }
+
+    Ok(files
+        .into_iter()
+        .filter(|file| file.1.iter().any(|region| region.permissions.execute))
+        .filter_map(|(path, regions)| {
+            let name = CString::new(path).ok()?;
+            let segments = regions
+                .into_iter()
+                .map(|region| {
+                    SegmentInfo::new(
+                        CString::default(),
+                        Svma(region.range.start as *const u8),
+                        region.range.end - region.range.start,
+                        region.file_offset,
+                        (region.range.end - region.range.start) as u64,
+                        region.permissions,
+                    )
+                })
+                .collect();
+            Some(ModuleInfo::from_parts(name, segments, Source::MapsParse))
+        })
+        .collect())
+}
+
+/// The stack of a thread of this process.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ThreadStack {
+    thread_id: u64,
+    range: Range<usize>,
+}
+
+impl ThreadStack {
+    /// Get the ID of the thread: its kernel thread ID on Linux, and the ID
+    /// `pthread_threadid_np` reports on macOS.
+    #[inline]
+    pub fn thread_id(&self) -> u64 {
+        self.thread_id
+    }
+
+    /// Get the half-open range of addresses the stack may grow into, not
+    /// counting any guard page.
+    ///
+    /// For the main thread on Linux, unless it is the calling thread, this is
+    /// only the part of the stack the kernel has mapped so far.
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+}
+
+/// The modules loaded in this process, together with the stacks of its
+/// threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadSnapshot {
+    modules: ProcessMap,
+    stacks: Vec<ThreadStack>,
+}
+
+impl ThreadSnapshot {
+    /// Take a snapshot of the loaded modules and of the threads' stacks, as
+    /// found by `thread_stacks`.
+    ///
+    /// The two are taken one after the other, so threads may come and go and
+    /// shared libraries may be loaded in between.
+    pub fn new() -> io::Result<Self> {
+        Ok(ThreadSnapshot {
+            modules: ProcessMap::new(),
+            stacks: thread_stacks()?,
+        })
+    }
+
+    /// Get the loaded modules.
+    #[inline]
+    pub fn modules(&self) -> &ProcessMap {
+        &self.modules
+    }
+
+    /// Get the stacks of the threads, ordered by thread ID.
+    #[inline]
+    pub fn stacks(&self) -> &[ThreadStack] {
+        &self.stacks
+    }
+
+    /// Get the stack of the thread with the given ID, if it was found.
+    #[inline]
+    pub fn stack_of(&self, thread_id: u64) -> Option<&ThreadStack> {
+        self.stacks.iter().find(|stack| stack.thread_id == thread_id)
+    }
+}
+
+/// Get the bounds of the stacks of this process's threads, ordered by thread
+/// ID.
+///
+/// * On Linux, the calling thread's stack comes from `pthread_getattr_np`,
+///   and the main thread's is its `[stack]` region. Other threads are listed
+///   in `/proc/self/task`, and their stack is the region containing the stack
+///   pointer their `syscall` file shows. That is only known while they are
+///   blocked, so threads that are running are left out.
+///
+/// * On macOS, the threads come from `task_threads`, and their stacks from
+///   `pthread_get_stackaddr_np`. Threads that weren't created with pthreads
+///   are left out.
+///
+/// On other platforms an error of kind `io::ErrorKind::Other` is returned.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn thread_stacks() -> io::Result<Vec<ThreadStack>> {
+    let mut stacks = imp::thread_stacks()?;
+    stacks.sort_by_key(|stack| stack.thread_id);
+    Ok(stacks)
+}
+
+/// Get the bounds of the stacks of this process's threads.
+///
+/// This is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn thread_stacks() -> io::Result<Vec<ThreadStack>> {
+    Err(io::Error::other("querying thread stacks is not supported on this platform"))
+}
+
+/// Get the bounds of the calling thread's stack, from `pthread_getattr_np`
+/// on Linux and `pthread_get_stackaddr_np` on macOS.
+///
+/// On other platforms an error of kind `io::ErrorKind::Other` is returned.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn current_thread_stack() -> io::Result<ThreadStack> {
+    imp::current_thread_stack()
+}
+
+/// Get the bounds of the calling thread's stack.
+///
+/// This is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn current_thread_stack() -> io::Result<ThreadStack> {
+    Err(io::Error::other("querying thread stacks is not supported on this platform"))
+}
+
+/// What an address points into, as found by
+/// [`classify_addr`](./fn.classify_addr.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AddressKind {
+    /// A segment of a loaded shared library.
+    Module(ModuleInfo),
+    /// A thread's stack.
+    Stack(ThreadStack),
+    /// The heap grown by `brk`, which is the `[heap]` region.
+    Heap(Region),
+    /// A region without any permissions, such as the guard page below a
+    /// thread's stack.
+    GuardPage(Region),
+    /// An executable region that doesn't belong to any loaded shared library,
+    /// such as JIT code or a trampoline.
+    UnknownExecutable(Region),
+    /// Any other mapped region, such as data or memory the allocator got with
+    /// `mmap`.
+    OtherRegion(Region),
+    /// No mapped region at all.
+    Unmapped,
+}
+
+/// Find out what the given address points into, such as to tell whether a
+/// crash was a fault in a shared library, in JIT code, on a guard page or on
+/// a wild pointer.
+///
+/// Addresses in loaded shared libraries and on the stacks `thread_stacks`
+/// finds are recognized on every supported platform. Other addresses need
+/// `memory_regions`, which fails on platforms other than Linux.
+///
+/// Only the `[heap]` region counts as the heap: memory the allocator maps
+/// for large allocations or other threads is an `OtherRegion`.
+pub fn classify_addr(address: Avma) -> io::Result<AddressKind> {
+    if let Some(module) = ModuleMap::new().lookup(address) {
+        return Ok(AddressKind::Module(module.clone()));
+    }
+
+    let address = address.0 as usize;
+    let stack = thread_stacks()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|stack| stack.range.contains(&address));
+    if let Some(stack) = stack {
+        return Ok(AddressKind::Stack(stack));
+    }
+
+    let region = memory_regions()?
+        .into_iter()
+        .find(|region| region.range.start <= address && address < region.range.end);
+    Ok(match region {
+        Some(region) => classify_region(region),
+        None => AddressKind::Unmapped,
+    })
+}
+
+/// Classify a region of the memory map by its path and permissions.
+fn classify_region(region: Region) -> AddressKind {
+    let permissions = region.permissions;
+    match region.path() {
+        // The main thread's stack; its thread ID is the process ID.
+        Some("[stack]") => AddressKind::Stack(ThreadStack {
+            thread_id: process::id().into(),
+            range: region.range,
+        }),
+        Some("[heap]") => AddressKind::Heap(region),
+        _ if !permissions.read && !permissions.write && !permissions.execute => {
+            AddressKind::GuardPage(region)
+        }
+        _ if permissions.execute => AddressKind::UnknownExecutable(region),
+        _ => AddressKind::OtherRegion(region),
+    }
+}
+
+/// Look for ELF, Mach-O and PE headers at page-aligned addresses that don't
+/// belong to any loaded shared library, in address order.
+///
+/// Every readable region is scanned, not just executable ones, because
+/// images usually map their headers separately from their code. This reads
+/// a little from every page in the process and is meant for diagnosing
+/// injected or manually mapped code, not for regular use. Anything that
+/// happens to look like an image header is reported, so expect false
+/// positives, such as copies of files that were read into memory.
+///
+/// Memory is read through the kernel, so pages that are unmapped while the
+/// scan runs are skipped rather than faulting.
+pub fn scan_for_images() -> io::Result<Vec<ScannedImage>> {
+    let regions = memory_regions()?;
+    let ranges = module_ranges();
+    let page_size = imp::page_size();
+    let mut page = vec![0; page_size];
+
+    let mut images = Vec::new();
+    for region in regions {
+        // Reading some of the kernel's pseudo-mappings faults.
+        if !region.permissions.read || region.path().is_some_and(|path| path.starts_with("[vvar")) {
+            continue;
+        }
+
+        let mut address = (region.range.start + page_size - 1) & !(page_size - 1);
+        while address < region.range.end {
+            if !ranges.iter().any(|range| range.contains(&address)) {
+                let len = page_size.min(region.range.end - address);
+                let bytes = imp::read_memory(address, &mut page[..len]);
+                if let Some(format) = image_format(bytes) {
+                    images.push(ScannedImage {
+                        address,
+                        format,
+                        region: region.clone(),
+                    });
+                }
+            }
+            address += page_size;
+        }
+    }
+    Ok(images)
+}
+
+/// Get the address ranges of every loaded shared library's segments.
+fn module_ranges() -> Vec<Range<usize>> {
+    let modules = ProcessMap::with_options(&EnumerateOptions::new().debug_info(false));
+    modules
+        .modules()
+        .iter()
+        .flat_map(|module| {
+            module.segments().iter().filter(|seg| !seg.is_empty()).map(move |seg| {
+                let start = module.actual_virtual_memory_address(seg).0 as usize;
+                start..start + seg.len()
+            })
+        })
+        .collect()
+}
+
+/// Recognize the magic numbers at the start of an image header.
+fn image_format(bytes: &[u8]) -> Option<ImageFormat> {
+    const ELF_MAGIC: &[u8] = b"\x7fELF";
+    const MACHO_MAGICS: [[u8; 4]; 4] = [
+        [0xce, 0xfa, 0xed, 0xfe],
+        [0xcf, 0xfa, 0xed, 0xfe],
+        [0xfe, 0xed, 0xfa, 0xce],
+        [0xfe, 0xed, 0xfa, 0xcf],
+    ];
+    // The DOS header's `e_lfanew` field holds the offset of the PE signature.
+    const E_LFANEW: usize = 0x3c;
+
+    if bytes.starts_with(ELF_MAGIC) {
+        return Some(ImageFormat::Elf);
+    }
+    if bytes.len() >= 4 && MACHO_MAGICS.iter().any(|magic| bytes[..4] == magic[..]) {
+        return Some(ImageFormat::MachO);
+    }
+    if bytes.starts_with(b"MZ") && bytes.len() >= E_LFANEW + 4 {
+        let mut lfanew = [0; 4];
+        lfanew.copy_from_slice(&bytes[E_LFANEW..E_LFANEW + 4]);
+        let lfanew = u32::from_le_bytes(lfanew) as usize;
+        if bytes.get(lfanew..lfanew.saturating_add(4)) == Some(&b"PE\0\0"[..]) {
+            return Some(ImageFormat::Pe);
+        }
+    }
+    None
+}
+
+/// Reads integers from an image's headers in the image's layout, `read`
+/// filling `buf.len()` bytes at an offset from the start of the headers.
+struct HeaderReader<F> {
+    read: F,
+    layout: ImageLayout,
+}
+
+impl<F: FnMut(usize, &mut [u8]) -> bool> HeaderReader<F> {
+    fn bytes<const N: usize>(&mut self, offset: usize) -> Option<[u8; N]> {
+        let mut bytes = [0; N];
+        if (self.read)(offset, &mut bytes) {
+            Some(bytes)
+        } else {
+            None
+        }
+    }
+
+    fn u16(&mut self, offset: usize) -> Option<u16> {
+        let bytes = self.bytes(offset)?;
+        if self.layout.big_endian {
+            Some(u16::from_be_bytes(bytes))
+        } else {
+            Some(u16::from_le_bytes(bytes))
+        }
+    }
+
+    fn u32(&mut self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes(offset)?;
+        if self.layout.big_endian {
+            Some(u32::from_be_bytes(bytes))
+        } else {
+            Some(u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn u64(&mut self, offset: usize) -> Option<u64> {
+        let bytes = self.bytes(offset)?;
+        if self.layout.big_endian {
+            Some(u64::from_be_bytes(bytes))
+        } else {
+            Some(u64::from_le_bytes(bytes))
+        }
+    }
+
+    /// Read an address-sized integer.
+    fn word(&mut self, offset: usize) -> Option<u64> {
+        if self.layout.address_bits == 64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+}
+
+/// Work out the width and byte order of an image's headers, reading them
+/// with `read` as in `pe_managed_image_kind`.
+fn image_layout<F>(format: ImageFormat, mut read: F) -> Option<ImageLayout>
+    where F: FnMut(usize, &mut [u8]) -> bool
+{
+    const E_LFANEW: usize = 0x3c;
+    const PE32_MAGIC: u16 = 0x10b;
+    const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+    let mut ident = [0; 6];
+    if !read(0, &mut ident) {
+        return None;
+    }
+    match format {
+        ImageFormat::Elf => {
+            let address_bits = match ident[4] {
+                1 => 32,
+                2 => 64,
+                _ => return None,
+            };
+            let big_endian = match ident[5] {
+                1 => false,
+                2 => true,
+                _ => return None,
+            };
+            Some(ImageLayout { address_bits, big_endian })
+        }
+        ImageFormat::MachO => {
+            let (address_bits, big_endian) = match ident[..4] {
+                [0xce, 0xfa, 0xed, 0xfe] => (32, false),
+                [0xcf, 0xfa, 0xed, 0xfe] => (64, false),
+                [0xfe, 0xed, 0xfa, 0xce] => (32, true),
+                [0xfe, 0xed, 0xfa, 0xcf] => (64, true),
+                _ => return None,
+            };
+            Some(ImageLayout { address_bits, big_endian })
+        }
+        ImageFormat::Pe => {
+            let layout = ImageLayout {
+                address_bits: 32,
+                big_endian: false,
+            };
+            let mut reader = HeaderReader { read, layout };
+            let optional_header = reader.u32(E_LFANEW)? as usize + 24;
+            let address_bits = match reader.u16(optional_header)? {
+                PE32_MAGIC => 32,
+                PE32_PLUS_MAGIC => 64,
+                _ => return None,
+            };
+            Some(ImageLayout { address_bits, big_endian: false })
+        }
+    }
+}
+
+/// Work out how many bytes an image spans in memory from its headers, read
+/// in the image's own layout.
+fn image_size<F>(format: ImageFormat, mut read: F) -> Option<u64>
+    where F: FnMut(usize, &mut [u8]) -> bool
+{
+    const E_LFANEW: usize = 0x3c;
+    const PT_LOAD: u32 = 1;
+    const LC_SEGMENT: u32 = 0x1;
+    const LC_SEGMENT_64: u32 = 0x19;
+
+    let layout = image_layout(format, &mut read)?;
+    let wide = layout.address_bits == 64;
+    let mut reader = HeaderReader { read, layout };
+
+    // The lowest and highest address of the segments seen so far.
+    let mut span: Option<(u64, u64)> = None;
+    let mut add = |start: u64, len: u64| {
+        let end = start.checked_add(len)?;
+        span = Some(match span {
+            Some((low, high)) => (low.min(start), high.max(end)),
+            None => (start, end),
+        });
+        Some(())
+    };
+
+    match format {
+        ImageFormat::Elf => {
+            // The offsets of `e_phoff`, `e_phentsize`, and `p_vaddr` and
+            // `p_memsz` in each program header.
+            let (phoff, phentsize, vaddr, memsz) = if wide {
+                (0x20, 0x36, 16, 40)
+            } else {
+                (0x1c, 0x2a, 8, 20)
+            };
+            let phoff = reader.word(phoff)?;
+            let phnum = reader.u16(phentsize + 2)?;
+            let phentsize = reader.u16(phentsize)?;
+            for i in 0..u64::from(phnum) {
+                let phdr = usize::try_from(phoff.checked_add(i * u64::from(phentsize))?).ok()?;
+                if reader.u32(phdr)? == PT_LOAD {
+                    add(reader.word(phdr + vaddr)?, reader.word(phdr + memsz)?)?;
+                }
+            }
+        }
+        ImageFormat::MachO => {
+            let ncmds = reader.u32(16)?;
+            let mut command = if wide { 32 } else { 28 };
+            for _ in 0..ncmds {
+                let cmd = reader.u32(command)?;
+                let cmdsize = reader.u32(command + 4)? as usize;
+                if cmdsize < 8 {
+                    return None;
+                }
+                // Skip segments that can never be mapped accessible, such as
+                // `__PAGEZERO`.
+                match cmd {
+                    LC_SEGMENT if reader.u32(command + 40)? != 0 => {
+                        let vmaddr = reader.u32(command + 24)?;
+                        add(u64::from(vmaddr), u64::from(reader.u32(command + 28)?))?;
+                    }
+                    LC_SEGMENT_64 if reader.u32(command + 56)? != 0 => {
+                        add(reader.u64(command + 24)?, reader.u64(command + 32)?)?;
+                    }
+                    _ => {}
+                }
+                command = command.checked_add(cmdsize)?;
+            }
+        }
+        ImageFormat::Pe => {
+            // `SizeOfImage` is at the same offset in PE32 and PE32+ images.
+            let optional_header = reader.u32(E_LFANEW)? as usize + 24;
+            return reader.u32(optional_header + 56).map(u64::from);
+        }
+    }
+    span.map(|(low, high)| high - low)
+}
+
+/// Recognize a ReadyToRun image from its PE headers, reading `buf.len()`
+/// bytes at a relative virtual address with `read`.
+fn pe_managed_image_kind<F>(mut read: F) -> Option<ManagedImageKind>
+    where F: FnMut(usize, &mut [u8]) -> bool
+{
+    const E_LFANEW: usize = 0x3c;
+    const PE32_MAGIC: u16 = 0x10b;
+    const PE32_PLUS_MAGIC: u16 = 0x20b;
+    const CLR_RUNTIME_HEADER: u32 = 14;
+    // The offset of `ManagedNativeHeader` in `IMAGE_COR20_HEADER`.
+    const MANAGED_NATIVE_HEADER: usize = 64;
+    const READY_TO_RUN_SIGNATURE: u32 = 0x0052_5452;
+
+    let mut read_u32 = |rva: usize| {
+        let mut bytes = [0; 4];
+        if read(rva, &mut bytes) {
+            Some(u32::from_le_bytes(bytes))
+        } else {
+            None
+        }
+    };
+
+    // The optional header follows the PE signature and the file header.
+    let optional_header = read_u32(E_LFANEW)? as usize + 24;
+    let (rva_count, directories) = match read_u32(optional_header)? as u16 {
+        PE32_MAGIC => (optional_header + 92, optional_header + 96),
+        PE32_PLUS_MAGIC => (optional_header + 108, optional_header + 112),
+        _ => return None,
+    };
+    if read_u32(rva_count)? <= CLR_RUNTIME_HEADER {
+        return None;
+    }
+    let cor20_header = read_u32(directories + 8 * CLR_RUNTIME_HEADER as usize)? as usize;
+    if cor20_header == 0 {
+        return None;
+    }
+    let native_header = read_u32(cor20_header + MANAGED_NATIVE_HEADER)? as usize;
+    if native_header != 0 && read_u32(native_header)? == READY_TO_RUN_SIGNATURE {
+        Some(ManagedImageKind::ReadyToRun)
+    } else {
+        None
+    }
+}
+
+/// How much of a range of memory is resident in physical memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Residency {
+    /// The number of bytes currently resident.
+    pub resident_bytes: usize,
+    /// The total number of bytes that were queried.
+    pub total_bytes: usize,
+}
+
+impl Residency {
+    /// Get the resident fraction, between zero and one.
+    #[inline]
+    pub fn fraction(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            self.resident_bytes as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+/// Find out how much of the given range of addresses is resident, using
+/// `mincore`.
+///
+/// The range is widened to whole pages. An error is returned if any part of
+/// the range isn't mapped.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn residency(range: Range<usize>) -> io::Result<Residency> {
+    use libc;
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    if range.start >= range.end {
+        return Ok(Residency::default());
+    }
+    let start = range.start & !(page_size - 1);
+    let end = (range.end + page_size - 1) & !(page_size - 1);
+    let mut pages = vec![0u8; (end - start) / page_size];
+
+    let result =
+        unsafe { libc::mincore(start as *mut libc::c_void, end - start, pages.as_mut_ptr() as _) };
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        trace::os_error("mincore", &err);
+        return Err(err);
+    }
+
+    let resident = pages.iter().filter(|&&page| page & 1 != 0).count();
+    Ok(Residency {
+        resident_bytes: resident * page_size,
+        total_bytes: end - start,
+    })
+}
+
+/// Find out how much of the given range of addresses is resident.
+///
+/// This is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn residency(_range: Range<usize>) -> io::Result<Residency> {
+    Err(io::Error::other("querying residency is not supported on this platform"))
+}
+
+/// How much of a range of memory is mapped with huge pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HugePageUsage {
+    /// The number of bytes mapped with huge pages, whether transparent huge
+    /// pages or `hugetlbfs`.
+    pub huge_bytes: usize,
+    /// The total number of bytes mapped.
+    pub total_bytes: usize,
+}
+
+/// Find out how much of the memory regions overlapping the given range of
+/// addresses is mapped with huge pages, such as text that was remapped onto
+/// huge pages at startup.
+///
+/// The counts cover every region that overlaps the range in full, because
+/// the kernel only reports them per region.
+///
+/// This is currently only implemented on Linux, by reading
+/// `/proc/self/smaps`.
+pub fn huge_page_usage(range: Range<usize>) -> io::Result<HugePageUsage> {
+    let mut total = HugePageUsage::default();
+    for entry in smaps_overlapping(&[range])? {
+        total.huge_bytes += entry.huge;
+        total.total_bytes += entry.size;
+    }
+    Ok(total)
+}
+
+/// How the resident pages of a range of memory are shared with other
+/// processes, in bytes.
+///
+/// Private dirty pages of a file mapping are copies the process made by
+/// writing to it, such as relocated data or patched code. Clean pages are
+/// still backed by the file, and shared pages are also mapped by other
+/// processes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Unmodified pages also mapped by other processes.
+    pub shared_clean: usize,
+    /// Modified pages also mapped by other processes.
+    pub shared_dirty: usize,
+    /// Unmodified pages only mapped by this process.
+    pub private_clean: usize,
+    /// Modified pages only mapped by this process, which for file mappings
+    /// means copied on write.
+    pub private_dirty: usize,
+}
+
+impl MemoryUsage {
+    /// Get the total number of resident bytes.
+    #[inline]
+    pub fn resident(&self) -> usize {
+        self.shared_clean + self.shared_dirty + self.private_clean + self.private_dirty
+    }
+}
+
+/// Find out how the memory regions overlapping the given range of addresses
+/// are shared.
+///
+/// Like `huge_page_usage`, this counts every overlapping region in full, and
+/// is currently only implemented on Linux.
+#[inline]
+pub fn memory_usage(range: Range<usize>) -> io::Result<MemoryUsage> {
+    memory_usage_of(&[range])
+}
+
+/// Find out how the memory regions overlapping any of the given ranges are
+/// shared, counting each region once.
+pub(crate) fn memory_usage_of(ranges: &[Range<usize>]) -> io::Result<MemoryUsage> {
+    let mut total = MemoryUsage::default();
+    for entry in smaps_overlapping(ranges)? {
+        total.shared_clean += entry.shared_clean;
+        total.shared_dirty += entry.shared_dirty;
+        total.private_clean += entry.private_clean;
+        total.private_dirty += entry.private_dirty;
+    }
+    Ok(total)
+}
+
+/// The counters from a region's entry in `/proc/<pid>/smaps`, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct SmapsEntry {
+    size: usize,
+    shared_clean: usize,
+    shared_dirty: usize,
+    private_clean: usize,
+    private_dirty: usize,
+    huge: usize,
+}
+
+/// Get the smaps entries of the regions that overlap any of the given ranges.
+fn smaps_overlapping(ranges: &[Range<usize>]) -> io::Result<Vec<SmapsEntry>> {
+    Ok(imp::smaps()?
+        .into_iter()
+        .filter(|(region, _)| {
+            ranges
+                .iter()
+                .any(|range| region.start < range.end && range.start < region.end)
+        })
+        .map(|(_, entry)| entry)
+        .collect())
+}
+
+/// Parse the contents of `/proc/<pid>/smaps` into each region's range and
+/// counters.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_smaps(smaps: &str) -> Option<Vec<(Range<usize>, SmapsEntry)>> {
+    const HUGE_FIELDS: [&str; 5] = [
+        "AnonHugePages:",
+        "ShmemPmdMapped:",
+        "FilePmdMapped:",
+        "Shared_Hugetlb:",
+        "Private_Hugetlb:",
+    ];
+
+    let mut regions: Vec<(Range<usize>, SmapsEntry)> = Vec::new();
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let key = fields.next()?;
+        if !key.ends_with(':') {
+            regions.push((parse_maps_line(line)?.range, SmapsEntry::default()));
+            continue;
+        }
+
+        let entry = match regions.last_mut() {
+            Some(region) => &mut region.1,
+            None => return None,
+        };
+        let bytes = match (fields.next(), fields.next()) {
+            (Some(value), Some("kB")) => value.parse::<usize>().ok()? * 1024,
+            _ => continue,
+        };
+        match key {
+            "Size:" => entry.size = bytes,
+            "Shared_Clean:" => entry.shared_clean = bytes,
+            "Shared_Dirty:" => entry.shared_dirty = bytes,
+            "Private_Clean:" => entry.private_clean = bytes,
+            "Private_Dirty:" => entry.private_dirty = bytes,
+            _ if HUGE_FIELDS.contains(&key) => entry.huge += bytes,
+            _ => {}
+        }
+    }
+    Some(regions)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::{
+        parse_maps_line, parse_smaps, parse_syscall_stack_pointer, trace, Region, SmapsEntry,
+        ThreadStack,
+    };
+
+    use libc;
+    use std::fs::{self, File};
+    use std::io::{self, BufRead, BufReader, Read};
+    use std::mem;
+    use std::ops::Range;
+    use std::process;
+    use std::ptr;
+
+    pub fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    /// Copy memory at `address` into `buf` with `process_vm_readv`, which
+    /// fails instead of faulting if the memory isn't mapped. Returns the bytes
+    /// that could be read.
+    pub fn read_memory(address: usize, buf: &mut [u8]) -> &[u8] {
+        let local = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: address as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let read = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+        &buf[..read.max(0) as usize]
+    }
+
+    pub fn smaps() -> io::Result<Vec<(Range<usize>, SmapsEntry)>> {
+        let mut smaps = String::new();
+        File::open("/proc/self/smaps")
+            .and_then(|mut file| file.read_to_string(&mut smaps))
+            .inspect_err(|err| trace::os_error("read /proc/self/smaps", err))?;
+        parse_smaps(&smaps)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad smaps contents"))
+    }
+
+    pub fn memory_regions() -> io::Result<Vec<Region>> {
+        let maps = BufReader::new(File::open("/proc/self/maps").inspect_err(|err| {
+            trace::os_error("open /proc/self/maps", err)
+        })?);
+        let mut regions = Vec::new();
+        for line in maps.lines() {
+            let line = line?;
+            let region = parse_maps_line(&line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("bad maps line: {}", line))
+            })?;
+            regions.push(region);
+        }
+        Ok(regions)
+    }
+
+    pub fn current_thread_stack() -> io::Result<ThreadStack> {
+        let mut attr: libc::pthread_attr_t = unsafe { mem::zeroed() };
+        let err = unsafe { libc::pthread_getattr_np(libc::pthread_self(), &mut attr) };
+        if err != 0 {
+            let err = io::Error::from_raw_os_error(err);
+            trace::os_error("pthread_getattr_np", &err);
+            return Err(err);
+        }
+        let mut addr = ptr::null_mut();
+        let mut size = 0;
+        let err = unsafe {
+            let err = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+            libc::pthread_attr_destroy(&mut attr);
+            err
+        };
+        if err != 0 {
+            let err = io::Error::from_raw_os_error(err);
+            trace::os_error("pthread_attr_getstack", &err);
+            return Err(err);
+        }
+
+        let start = addr as usize;
+        Ok(ThreadStack {
+            thread_id: unsafe { libc::syscall(libc::SYS_gettid) } as u64,
+            range: start..start.saturating_add(size),
+        })
+    }
+
+    pub fn thread_stacks() -> io::Result<Vec<ThreadStack>> {
+        let current = current_thread_stack().ok();
+        let regions = memory_regions()?;
+        let tasks = fs::read_dir("/proc/self/task")
+            .inspect_err(|err| trace::os_error("read /proc/self/task", err))?;
+
+        let mut stacks = Vec::new();
+        for task in tasks {
+            let thread_id = match task?.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(thread_id) => thread_id,
+                None => continue,
+            };
+            if let Some(ref current) = current {
+                if current.thread_id == thread_id {
+                    stacks.push(current.clone());
+                    continue;
+                }
+            }
+
+            let region = if thread_id == u64::from(process::id()) {
+                regions.iter().find(|region| region.path() == Some("[stack]"))
+            } else {
+                stack_pointer(thread_id)
+                    .and_then(|sp| regions.iter().find(|region| region.range.contains(&sp)))
+            };
+            if let Some(region) = region {
+                stacks.push(ThreadStack {
+                    thread_id,
+                    range: region.range(),
+                });
+            }
+        }
+        Ok(stacks)
+    }
+
+    /// Get the stack pointer of a thread of this process that is blocked.
+    fn stack_pointer(thread_id: u64) -> Option<usize> {
+        let mut syscall = String::new();
+        File::open(format!("/proc/self/task/{}/syscall", thread_id))
+            .and_then(|mut file| file.read_to_string(&mut syscall))
+            .ok()?;
+        parse_syscall_stack_pointer(&syscall)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::{Region, SmapsEntry};
+
+    use std::io;
+    use std::ops::Range;
+
+    // libc wants its Mach functions replaced by the `mach2` crate's.
+    #[cfg(target_os = "macos")]
+    #[allow(deprecated)]
+    pub fn thread_stacks() -> io::Result<Vec<super::ThreadStack>> {
+        use libc;
+        use std::{mem, ptr, slice};
+
+        extern "C" {
+            fn mach_port_deallocate(task: libc::mach_port_t, name: libc::mach_port_t)
+                -> libc::kern_return_t;
+        }
+
+        let task = unsafe { libc::mach_task_self() };
+        let mut threads = ptr::null_mut();
+        let mut count = 0;
+        let result = unsafe { libc::task_threads(task, &mut threads, &mut count) };
+        if result != libc::KERN_SUCCESS {
+            let err = io::Error::other(format!("task_threads failed with {}", result));
+            super::trace::os_error("task_threads", &err);
+            return Err(err);
+        }
+
+        let ports = unsafe { slice::from_raw_parts(threads, count as usize) };
+        let mut stacks = Vec::new();
+        for &port in ports {
+            let mut info: libc::thread_identifier_info = unsafe { mem::zeroed() };
+            let mut info_count = libc::THREAD_IDENTIFIER_INFO_COUNT;
+            let result = unsafe {
+                libc::thread_info(
+                    port,
+                    libc::THREAD_IDENTIFIER_INFO as libc::thread_flavor_t,
+                    &mut info as *mut _ as libc::thread_info_t,
+                    &mut info_count,
+                )
+            };
+            let thread = unsafe { libc::pthread_from_mach_thread_np(port) };
+            if result == libc::KERN_SUCCESS && thread != 0 {
+                let end = unsafe { libc::pthread_get_stackaddr_np(thread) } as usize;
+                let size = unsafe { libc::pthread_get_stacksize_np(thread) };
+                stacks.push(super::ThreadStack {
+                    thread_id: info.thread_id,
+                    range: end.saturating_sub(size)..end,
+                });
+            }
+            unsafe {
+                mach_port_deallocate(task, port);
+            }
+        }
+        unsafe {
+            libc::vm_deallocate(
+                task,
+                threads as libc::vm_address_t,
+                mem::size_of_val(ports),
+            );
+        }
+        Ok(stacks)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn current_thread_stack() -> io::Result<super::ThreadStack> {
+        use libc;
+
+        let thread = unsafe { libc::pthread_self() };
+        let mut thread_id = 0;
+        unsafe {
+            libc::pthread_threadid_np(thread, &mut thread_id);
+        }
+        // The stack address is the highest address, since stacks grow down.
+        let end = unsafe { libc::pthread_get_stackaddr_np(thread) } as usize;
+        let size = unsafe { libc::pthread_get_stacksize_np(thread) };
+        Ok(super::ThreadStack {
+            thread_id,
+            range: end.saturating_sub(size)..end,
+        })
+    }
+
+    pub fn page_size() -> usize {
+        4096
+    }
+
+    pub fn smaps() -> io::Result<Vec<(Range<usize>, SmapsEntry)>> {
+        Err(io::Error::other(
+            "querying memory usage is not supported on this platform",
+        ))
+    }
+
+    pub fn read_memory(_address: usize, _buf: &mut [u8]) -> &[u8] {
+        &[]
+    }
+
+    pub fn memory_regions() -> io::Result<Vec<Region>> {
+        Err(io::Error::other(
+            "enumerating memory regions is not supported on this platform",
+        ))
+    }
+}
+
+/// Parse the stack pointer out of `/proc/<pid>/task/<tid>/syscall`, which is
+/// the second to last field while the thread is blocked, in or out of a
+/// system call, and `running` otherwise:
+///
+/// ```text
+/// 230 0x1 0x1 0x7f5e3ebfeab0 0x0 0x0 0x7f5e3f7016e8 0x7f5e3ebfea70 0x7f5e3f0ed545
+/// ```
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_syscall_stack_pointer(syscall: &str) -> Option<usize> {
+    let fields: Vec<_> = syscall.split_whitespace().collect();
+    if fields.len() < 3 {
+        return None;
+    }
+    let sp = fields[fields.len() - 2];
+    usize::from_str_radix(sp.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parse a line of `/proc/<pid>/maps`:
+///
+/// ```text
+/// 7f3a1c428000-7f3a1c5bd000 r-xp 00028000 08:01 1835012    /usr/lib/libc.so.6
+/// ```
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_maps_line(line: &str) -> Option<Region> {
+    let mut fields = line.splitn(6, ' ');
+    let mut addresses = fields.next()?.splitn(2, '-');
+    let start = usize::from_str_radix(addresses.next()?, 16).ok()?;
+    let end = usize::from_str_radix(addresses.next()?, 16).ok()?;
+
+    let perms = fields.next()?.as_bytes();
+    if perms.len() < 3 {
+        return None;
+    }
+    let permissions = SegmentPermissions {
+        read: perms[0] == b'r',
+        write: perms[1] == b'w',
+        execute: perms[2] == b'x',
+    };
+
+    let file_offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+
+    // Skip the device and inode.
+    for _ in 0..2 {
+        fields.next()?;
+    }
+    let path = fields.next().map(str::trim_start).filter(|path| !path.is_empty());
+
+    Some(Region {
+        range: start..end,
+        permissions,
+        file_offset,
+        path: path.map(|path| path.to_owned()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_maps_lines() {
+        let region = parse_maps_line(
+            "7f3a1c428000-7f3a1c5bd000 r-xp 00028000 08:01 1835012                    /usr/lib/libc.so.6",
+        ).unwrap();
+        assert_eq!(region.range(), 0x7f3a_1c42_8000..0x7f3a_1c5b_d000);
+        assert_eq!(region.permissions().to_string(), "r-x");
+        assert_eq!(region.file_offset(), 0x28000);
+        assert_eq!(region.path(), Some("/usr/lib/libc.so.6"));
+
+        let region = parse_maps_line("7f3a1c000000-7f3a1c001000 rwxp 00000000 00:00 0 ").unwrap();
+        assert!(region.is_anonymous());
+        assert!(region.permissions().write);
+
+        let region = parse_maps_line("55d0-55e0 rw-p 00000000 00:00 0").unwrap();
+        assert!(region.is_anonymous());
+
+        assert!(parse_maps_line("not a maps line").is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finds_anonymous_executable_mapping() {
+        use libc;
+
+        let len = 4096;
+        let page = unsafe {
+            libc::mmap(
+                ::std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        let start = page as usize;
+
+        let regions = unknown_executable_regions().unwrap();
+        unsafe {
+            libc::munmap(page, len);
+        }
+
+        assert!(regions.iter().all(|region| region.permissions().execute));
+        assert!(regions.iter().any(|region| {
+            let range = region.range();
+            region.is_anonymous() && range.start <= start && start + len <= range.end
+        }));
+    }
+
+    #[test]
+    fn classify_module_addresses() {
+        let this: fn() = classify_module_addresses;
+        match classify_addr(Avma(this as *const u8)) {
+            Ok(AddressKind::Module(module)) => assert!(module.is_main_executable()),
+            Err(_) if !::TARGET_SUPPORTED => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn classify_stack_addresses() {
+        fn check() {
+            let local = 0u8;
+            let stack = current_thread_stack().unwrap();
+            assert!(stack.range().contains(&(&local as *const u8 as usize)));
+            assert_eq!(classify_addr(Avma(&local)).unwrap(), AddressKind::Stack(stack));
+        }
+
+        check();
+        ::std::thread::spawn(check).join().unwrap();
+    }
+
+    #[test]
+    fn parse_syscall_stack_pointers() {
+        let blocked = "230 0x1 0x1 0x7f5e3ebfeab0 0x0 0x0 0x0 0x7f5e3ebfea70 0x7f5e3f0ed545\n";
+        assert_eq!(parse_syscall_stack_pointer(blocked), Some(0x7f5e_3ebf_ea70));
+        assert_eq!(parse_syscall_stack_pointer("-1 0x7ffd1000 0x401000\n"), Some(0x7ffd_1000));
+        assert_eq!(parse_syscall_stack_pointer("running\n"), None);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn thread_stacks_include_blocked_threads() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let (address_sender, address_receiver) = mpsc::channel();
+        let (done_sender, done_receiver) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let local = 0u8;
+            let thread_id = current_thread_stack().unwrap().thread_id();
+            address_sender.send((&local as *const u8 as usize, thread_id)).unwrap();
+            // Stay blocked until the stacks have been looked at.
+            let _ = done_receiver.recv();
+        });
+        let (address, thread_id) = address_receiver.recv().unwrap();
+
+        // On Linux, the thread's stack is only found once it has blocked.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let snapshot = loop {
+            let snapshot = ThreadSnapshot::new().unwrap();
+            if snapshot.stack_of(thread_id).is_some() || Instant::now() > deadline {
+                break snapshot;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let kind = classify_addr(Avma(address as *const u8));
+        drop(done_sender);
+        thread.join().unwrap();
+
+        let stacks = snapshot.stacks();
+        assert!(stacks.windows(2).all(|pair| pair[0].thread_id() < pair[1].thread_id()));
+        let current = current_thread_stack().unwrap();
+        assert_eq!(snapshot.stack_of(current.thread_id()), Some(&current));
+        let stack = snapshot.stack_of(thread_id).unwrap();
+        assert!(stack.range().contains(&address));
+        assert_eq!(kind.unwrap(), AddressKind::Stack(stack.clone()));
+        assert_eq!(snapshot.modules().modules().is_empty(), !::TARGET_SUPPORTED);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn classify_other_addresses() {
+        use libc;
+
+        // Threads other than the main one allocate from their own arenas,
+        // which aren't in the `[heap]` region.
+        let heap = Box::new(0u64);
+        match classify_addr(Avma(&*heap as *const u64 as *const u8)).unwrap() {
+            AddressKind::Heap(region) | AddressKind::OtherRegion(region) => {
+                assert!(region.permissions().write)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let regions = memory_regions().unwrap();
+        let named = |name| regions.iter().find(|region| region.path() == Some(name));
+        if let Some(heap) = named("[heap]") {
+            let address = Avma(heap.range().start as *const u8);
+            assert_eq!(classify_addr(address).unwrap(), AddressKind::Heap(heap.clone()));
+        }
+        let main_stack = named("[stack]").unwrap();
+        match classify_addr(Avma((main_stack.range().end - 1) as *const u8)).unwrap() {
+            AddressKind::Stack(stack) => assert_eq!(stack.thread_id(), process::id().into()),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let len = 4096;
+        let page = unsafe {
+            libc::mmap(
+                ::std::ptr::null_mut(),
+                len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        let kind = classify_addr(Avma(page as *const u8));
+        unsafe {
+            libc::munmap(page, len);
+        }
+        match kind.unwrap() {
+            AddressKind::GuardPage(region) => assert!(region.range().contains(&(page as usize))),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // The first page is never mapped, so that null pointers fault.
+        assert_eq!(classify_addr(Avma(8 as *const u8)).unwrap(), AddressKind::Unmapped);
+    }
+
+    #[test]
+    fn parse_smaps_entries() {
+        let smaps = "\
+7f0000000000-7f0000400000 r-xp 00000000 fe:00 1234   /usr/bin/app
+Size:               4096 kB
+KernelPageSize:        4 kB
+Shared_Clean:        100 kB
+Shared_Dirty:          0 kB
+Private_Clean:        20 kB
+Private_Dirty:         8 kB
+AnonHugePages:      2048 kB
+FilePmdMapped:         0 kB
+THPeligible:           1
+VmFlags: rd ex mr mw me
+7f0000400000-7f0000401000 rw-p 00000000 00:00 0
+Size:                  4 kB
+AnonHugePages:         0 kB
+Private_Hugetlb:       0 kB
+";
+        let regions = parse_smaps(smaps).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].0, 0x7f00_0000_0000..0x7f00_0040_0000);
+        assert_eq!(regions[0].1, SmapsEntry {
+            size: 4 << 20,
+            shared_clean: 100 << 10,
+            shared_dirty: 0,
+            private_clean: 20 << 10,
+            private_dirty: 8 << 10,
+            huge: 2 << 20,
+        });
+        assert_eq!(regions[1].1.huge, 0);
+
+        assert!(parse_smaps("Size: 4 kB\n").is_none());
+    }
+
+    #[test]
+    fn recognizes_image_formats() {
+        assert_eq!(image_format(b"\x7fELF\x02\x01"), Some(ImageFormat::Elf));
+        assert_eq!(image_format(&[0xcf, 0xfa, 0xed, 0xfe, 7]), Some(ImageFormat::MachO));
+
+        let mut pe = vec![0; 0x100];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3c] = 0x80;
+        assert_eq!(image_format(&pe), None);
+        pe[0x80..0x84].copy_from_slice(b"PE\0\0");
+        assert_eq!(image_format(&pe), Some(ImageFormat::Pe));
+        pe[0x3c] = 0xff;
+        assert_eq!(image_format(&pe), None);
+
+        assert_eq!(image_format(b"\x7fEL"), None);
+        assert_eq!(image_format(b""), None);
+    }
+
+    /// Read from `image` as `pe_managed_image_kind` and `image_size` expect.
+    fn read_from(image: &[u8]) -> impl FnMut(usize, &mut [u8]) -> bool + '_ {
+        move |offset, buf| {
+            let len = buf.len();
+            match image.get(offset..offset + len) {
+                Some(bytes) => {
+                    buf.copy_from_slice(bytes);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    /// Write `value` into `image` at `offset`, `len` bytes wide.
+    fn put(image: &mut [u8], layout: ImageLayout, offset: usize, len: usize, value: u64) {
+        let bytes = if layout.big_endian {
+            value.to_be_bytes()[8 - len..].to_vec()
+        } else {
+            value.to_le_bytes()[..len].to_vec()
+        };
+        image[offset..offset + len].copy_from_slice(&bytes);
+    }
+
+    #[test]
+    fn parses_elf_headers_of_either_layout() {
+        for &(address_bits, big_endian) in &[(32, false), (32, true), (64, false), (64, true)] {
+            let layout = ImageLayout { address_bits, big_endian };
+            let wide = address_bits == 64;
+            let word = if wide { 8 } else { 4 };
+            let mut image = vec![0; 0x200];
+            image[..4].copy_from_slice(b"\x7fELF");
+            image[4] = if wide { 2 } else { 1 };
+            image[5] = if big_endian { 2 } else { 1 };
+
+            let (phoff, phentsize, vaddr, memsz) = if wide {
+                (0x20, 0x36, 16, 40)
+            } else {
+                (0x1c, 0x2a, 8, 20)
+            };
+            let size = if wide { 56 } else { 32 };
+            put(&mut image, layout, phoff, word, 0x40);
+            put(&mut image, layout, phentsize, 2, size as u64);
+            put(&mut image, layout, phentsize + 2, 2, 3);
+            // Two loadable segments around a `PT_NOTE` that is ignored.
+            let loads = [(1, 0x1000, 0x800), (4, 0x9000_0000, 0x10), (1, 0x3000, 0x1234)];
+            for (i, &(kind, address, len)) in loads.iter().enumerate() {
+                let phdr = 0x40 + i * size;
+                put(&mut image, layout, phdr, 4, kind);
+                put(&mut image, layout, phdr + vaddr, word, address);
+                put(&mut image, layout, phdr + memsz, word, len);
+            }
+
+            assert_eq!(image_format(&image), Some(ImageFormat::Elf));
+            assert_eq!(image_layout(ImageFormat::Elf, read_from(&image)), Some(layout));
+            assert_eq!(image_size(ImageFormat::Elf, read_from(&image)), Some(0x4234 - 0x1000));
+            assert_eq!(image_size(ImageFormat::Elf, read_from(&image[..0x40 + size])), None);
+        }
+    }
+
+    #[test]
+    fn parses_mach_o_headers_of_either_layout() {
+        for &(address_bits, big_endian) in &[(32, false), (32, true), (64, false), (64, true)] {
+            let layout = ImageLayout { address_bits, big_endian };
+            let wide = address_bits == 64;
+            let mut image = vec![0; 0x200];
+            put(&mut image, layout, 0, 4, if wide { 0xfeed_facf } else { 0xfeed_face });
+            put(&mut image, layout, 16, 4, 3);
+
+            let (cmd, cmdsize, vmsize, maxprot) = if wide {
+                (0x19, 72, 32, 56)
+            } else {
+                (0x1, 56, 28, 40)
+            };
+            let word = if wide { 8 } else { 4 };
+            // `__PAGEZERO`, which is never mapped, then two segments.
+            let segments = [(0, 0x1000, 0), (0x1000, 0x4000, 5), (0x5000, 0x800, 3)];
+            let mut command = if wide { 32 } else { 28 };
+            for &(address, len, protection) in &segments {
+                put(&mut image, layout, command, 4, cmd);
+                put(&mut image, layout, command + 4, 4, cmdsize);
+                put(&mut image, layout, command + 24, word, address);
+                put(&mut image, layout, command + vmsize, word, len);
+                put(&mut image, layout, command + maxprot, 4, protection);
+                command += cmdsize as usize;
+            }
+
+            assert_eq!(image_format(&image), Some(ImageFormat::MachO));
+            assert_eq!(image_layout(ImageFormat::MachO, read_from(&image)), Some(layout));
+            assert_eq!(image_size(ImageFormat::MachO, read_from(&image)), Some(0x4800));
+        }
+    }
+
+    #[test]
+    fn parses_pe_headers_of_either_width() {
+        let layout = ImageLayout {
+            address_bits: 32,
+            big_endian: false,
+        };
+        let mut image = vec![0; 0x200];
+        image[..2].copy_from_slice(b"MZ");
+        put(&mut image, layout, 0x3c, 4, 0x80);
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        put(&mut image, layout, 0x98 + 56, 4, 0x0002_3000);
+        assert_eq!(image_format(&image), Some(ImageFormat::Pe));
+
+        for &(magic, address_bits) in &[(0x10b, 32), (0x20b, 64)] {
+            put(&mut image, layout, 0x98, 2, magic);
+            let layout = image_layout(ImageFormat::Pe, read_from(&image)).unwrap();
+            assert_eq!(layout.address_bits, address_bits);
+            assert!(!layout.big_endian);
+            assert_eq!(image_size(ImageFormat::Pe, read_from(&image)), Some(0x0002_3000));
+        }
+        put(&mut image, layout, 0x98, 2, 0x107);
+        assert_eq!(image_layout(ImageFormat::Pe, read_from(&image)), None);
+    }
+
+    #[test]
+    fn native_images_have_the_native_layout() {
+        let options = EnumerateOptions::new().header_bytes(64);
+        for module in ProcessMap::with_options(&options).modules() {
+            let header = module.header();
+            if let Some(format) = image_format(header) {
+                assert_eq!(image_layout(format, read_from(header)), Some(ImageLayout::native()));
+            }
+        }
+    }
+
+    #[test]
+    fn recognizes_ready_to_run_images() {
+        fn put(image: &mut [u8], offset: usize, value: u32) {
+            image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        let mut image = vec![0; 0x400];
+        image[..2].copy_from_slice(b"MZ");
+        put(&mut image, 0x3c, 0x80);
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        put(&mut image, 0x98, 0x20b);
+        put(&mut image, 0x98 + 108, 16);
+        put(&mut image, 0x98 + 112 + 8 * 14, 0x200);
+        put(&mut image, 0x200 + 64, 0x300);
+
+        let kind = |image: &[u8]| {
+            pe_managed_image_kind(|rva, buf| {
+                let len = buf.len();
+                match image.get(rva..rva + len) {
+                    Some(bytes) => {
+                        buf.copy_from_slice(bytes);
+                        true
+                    }
+                    None => false,
+                }
+            })
+        };
+
+        assert_eq!(kind(&image), None);
+        put(&mut image, 0x300, 0x0052_5452);
+        assert_eq!(kind(&image), Some(ManagedImageKind::ReadyToRun));
+
+        // Without a CLR runtime header, this is native code.
+        put(&mut image, 0x98 + 108, 14);
+        assert_eq!(kind(&image), None);
+        put(&mut image, 0x98 + 108, 16);
+
+        // PE32 images have a shorter optional header.
+        put(&mut image, 0x98, 0x10b);
+        assert_eq!(kind(&image), None);
+        put(&mut image, 0x98 + 92, 16);
+        put(&mut image, 0x98 + 96 + 8 * 14, 0x200);
+        assert_eq!(kind(&image), Some(ManagedImageKind::ReadyToRun));
+
+        assert_eq!(kind(&image[..0x200]), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn scan_finds_hidden_elf_header() {
+        use libc;
+
+        let len = 2 * imp::page_size();
+        let page = unsafe {
+            libc::mmap(
+                ::std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        unsafe {
+            ::std::ptr::copy_nonoverlapping(b"\x7fELF".as_ptr(), page as *mut u8, 4);
+        }
+
+        let images = scan_for_images().unwrap();
+        unsafe {
+            libc::munmap(page, len);
+        }
+
+        let image = images
+            .iter()
+            .find(|image| image.address() == page as usize)
+            .expect("should find the planted header");
+        assert_eq!(image.format(), ImageFormat::Elf);
+        assert_eq!(image.discovered_by(), Source::MemoryScan);
+
+        // The headers of loaded shared libraries are not reported.
+        let ranges = module_ranges();
+        assert!(images
+            .iter()
+            .all(|image| !ranges.iter().any(|range| range.contains(&image.address()))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mapped_modules_include_libc() {
+        let modules = mapped_modules().unwrap();
+        let libc = modules
+            .iter()
+            .find(|module| module.name().to_string_lossy().contains("libc.so"))
+            .expect("libc should be mapped");
+        assert_eq!(libc.source(), Source::MapsParse);
+        assert!(libc.id().is_none());
+        assert!(libc.segments().iter().any(|seg| seg.permissions().execute));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn merging_mapped_modules_keeps_loader_records() {
+        let mut map = ProcessMap::new();
+        let loaded = map.modules().len();
+        map.merge(mapped_modules().unwrap());
+
+        let libcs: Vec<_> = map
+            .modules()
+            .iter()
+            .filter(|module| module.name().to_string_lossy().contains("/libc.so"))
+            .collect();
+        assert_eq!(libcs.len(), 1);
+        assert_eq!(libcs[0].source(), Source::LoaderList);
+        assert!(map.modules()[..loaded]
+            .iter()
+            .all(|module| module.source() == Source::LoaderList));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn touched_pages_are_resident() {
+        use libc;
+
+        let len = 4 * 65536;
+        let mapping = unsafe {
+            libc::mmap(
+                ::std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mapping, libc::MAP_FAILED);
+        let start = mapping as usize;
+
+        let untouched = residency(start..start + len).unwrap();
+        unsafe {
+            *(mapping as *mut u8) = 1;
+        }
+        let touched = residency(start..start + len).unwrap();
+        unsafe {
+            libc::munmap(mapping, len);
+        }
+
+        assert_eq!(untouched.total_bytes, len);
+        assert_eq!(untouched.resident_bytes, 0);
+        assert!(touched.resident_bytes > 0);
+        assert!(touched.resident_bytes < len);
+        assert!(touched.fraction() > 0.0 && touched.fraction() < 1.0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn written_pages_are_private_dirty() {
+        use libc;
+
+        let len = 4 * 4096;
+        let mapping = unsafe {
+            libc::mmap(
+                ::std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mapping, libc::MAP_FAILED);
+        unsafe {
+            *(mapping as *mut u8) = 1;
+        }
+        let start = mapping as usize;
+        let usage = memory_usage(start..start + len).unwrap();
+        unsafe {
+            libc::munmap(mapping, len);
+        }
+
+        assert!(usage.private_dirty >= 4096);
+        assert!(usage.resident() >= usage.private_dirty);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[test]
+    fn unsupported() {
+        assert!(memory_regions().is_err());
+    }
+}
@@ -0,0 +1,504 @@
+//! Human-readable module map reports.
+//!
+//! [`render`](./fn.render.html) produces the kind of module list found at the
+//! bottom of a crash log, with one line per loaded shared library:
+//!
+//! ```text
+//! 0x00007f3a1c428000-0x00007f3a1c609f50 r-x 8ba59a4e2fc6c18fba4485d5e62dcd6c6b757a5d /lib/x86_64-linux-gnu/libc.so.6
+//! ```
+//!
+//! The columns are the range of actual virtual memory addresses that the
+//! shared library's segments span, the union of the permissions of its
+//! executable segments (so `rwx` stands out), its debug-id, and its name. A
+//! missing debug-id or name is shown as `-`.
+//!
+//! When the report has to fit into a fixed-size buffer, use
+//! [`Options`](./struct.Options.html) to shorten names, cap the size of each
+//! line and limit the number of modules, and
+//! [`render_checked`](./fn.render_checked.html) to find out what was cut off.
+//!
+//! [`render_security`](./fn.render_security.html) instead lists the findings
+//! a security review of the loaded modules starts with, one per line:
+//!
+//! ```text
+//! 0x00007f3a1c800000-0x00007f3a1c810000 wx-segment LOAD /opt/lib/libjit.so
+//! ```
+//!
+//! The columns are the range of actual virtual memory addresses the finding
+//! applies to, its kind, the name of the segment, and the name of the shared
+//! library. The only kind so far is `wx-segment`, for a segment mapped both
+//! writable and executable.
+
+use super::encoding::{display_name, NameDecoder};
+use super::path::PathPolicy;
+use super::snapshot::{loaded_modules, ModuleInfo};
+use super::{SegmentPermissions, Truncated, TruncationKind};
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::mem;
+use std::sync::Arc;
+
+/// Options controlling how a report is rendered.
+///
+/// All lengths are in bytes. Truncation never splits a UTF-8 character, so a
+/// truncated line may be a few bytes shorter than the limit, but the same
+/// input always produces the same output.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    basename: bool,
+    max_name_len: Option<usize>,
+    max_line_len: Option<usize>,
+    max_modules: Option<usize>,
+    path_policy: Option<Arc<dyn PathPolicy>>,
+    name_decoder: Option<Arc<dyn NameDecoder>>,
+}
+
+impl Options {
+    /// Get the default options, which render everything in full.
+    #[inline]
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Only show the last component of each shared library's path.
+    #[inline]
+    pub fn basename(mut self, basename: bool) -> Options {
+        self.basename = basename;
+        self
+    }
+
+    /// Apply `policy` to each shared library's name before showing it. This
+    /// comes before `basename` and `max_name_len`.
+    #[inline]
+    pub fn path_policy<P: PathPolicy + 'static>(mut self, policy: P) -> Options {
+        self.path_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Decode names that aren't valid UTF-8 with `decoder`, instead of
+    /// replacing their invalid sequences with U+FFFD. See
+    /// `encoding::display_name`.
+    #[inline]
+    pub fn name_decoder<D: NameDecoder + 'static>(mut self, decoder: D) -> Options {
+        self.name_decoder = Some(Arc::new(decoder));
+        self
+    }
+
+    /// Shorten names longer than `len` bytes by replacing their beginning with
+    /// `...`, keeping the end, which is usually the most specific part.
+    #[inline]
+    pub fn max_name_len(mut self, len: usize) -> Options {
+        self.max_name_len = Some(len);
+        self
+    }
+
+    /// Cut off each line after `len` bytes, not counting the newline.
+    #[inline]
+    pub fn max_line_len(mut self, len: usize) -> Options {
+        self.max_line_len = Some(len);
+        self
+    }
+
+    /// Only show the first `max` shared libraries.
+    #[inline]
+    pub fn max_modules(mut self, max: usize) -> Options {
+        self.max_modules = Some(max);
+        self
+    }
+}
+
+/// A rendered report, and what was cut off to respect the options' limits.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rendered {
+    /// The report itself.
+    pub text: String,
+    /// What was cut off, with one entry for each kind of truncation that
+    /// happened, counting what was lost across the whole report.
+    pub truncated: Vec<Truncated>,
+}
+
+impl Rendered {
+    /// Note that `lost` more was cut off.
+    fn lose(&mut self, kind: TruncationKind, lost: usize) {
+        if lost == 0 {
+            return;
+        }
+        match self.truncated.iter_mut().find(|truncated| truncated.kind == kind) {
+            Some(truncated) => truncated.lost += lost,
+            None => self.truncated.push(Truncated { kind, lost }),
+        }
+    }
+}
+
+/// Render a report of every shared library currently loaded in this process.
+///
+/// This is meant to be appended to panic messages and crash logs.
+pub fn render() -> String {
+    render_modules(&loaded_modules())
+}
+
+/// Render a report of every shared library currently loaded in this process,
+/// with the given options.
+pub fn render_with_options(options: &Options) -> String {
+    render_modules_with_options(&loaded_modules(), options)
+}
+
+/// Render a report of every shared library currently loaded in this process,
+/// with the given options, and tell what was cut off to respect their limits.
+pub fn render_checked(options: &Options) -> Rendered {
+    render_modules_checked(&loaded_modules(), options)
+}
+
+/// Render a report of the given shared libraries, in the given order.
+#[inline]
+pub fn render_modules(modules: &[ModuleInfo]) -> String {
+    render_modules_with_options(modules, &Options::default())
+}
+
+/// Render a report of the given shared libraries, in the given order, with
+/// the given options.
+#[inline]
+pub fn render_modules_with_options(modules: &[ModuleInfo], options: &Options) -> String {
+    render_modules_checked(modules, options).text
+}
+
+/// Render a report of the given shared libraries, in the given order, with
+/// the given options, and tell what was cut off to respect their limits.
+pub fn render_modules_checked(modules: &[ModuleInfo], options: &Options) -> Rendered {
+    let mut rendered = Rendered::default();
+    let mut line = String::new();
+    let shown = options.max_modules.unwrap_or(usize::MAX).min(modules.len());
+    rendered.lose(TruncationKind::Modules, modules.len() - shown);
+    for module in &modules[..shown] {
+        let (start, end) = avma_span(module);
+        let permissions = module
+            .segments()
+            .iter()
+            .map(|seg| seg.permissions())
+            .filter(|permissions| permissions.execute)
+            .fold(SegmentPermissions::default(), SegmentPermissions::union);
+        let id = module.id().map(|id| id.to_string());
+        let name = match options.path_policy {
+            Some(ref policy) => policy.apply(module.name()),
+            None => Cow::Borrowed(module.name()),
+        };
+        let name = display_name(&name, options.name_decoder.as_deref());
+        let name = if options.basename {
+            name.rsplit('/').next().unwrap_or("")
+        } else {
+            &name
+        };
+        let original_len = name.len();
+        let name = match options.max_name_len {
+            Some(len) => truncate_front(name, len),
+            None => Cow::Borrowed(name),
+        };
+        let kept = match name {
+            Cow::Borrowed(kept) => kept.len(),
+            Cow::Owned(ref kept) => kept.len() - ELLIPSIS.len(),
+        };
+        rendered.lose(TruncationKind::Names, original_len - kept);
+
+        let width = 2 + 2 * mem::size_of::<usize>();
+        line.clear();
+        let _ = write!(
+            line,
+            "{:#0width$x}-{:#0width$x} {} {} {}",
+            start,
+            end,
+            permissions,
+            id.as_ref().map_or("-", |id| &id[..]),
+            if name.is_empty() { "-" } else { &name },
+            width = width
+        );
+
+        if let Some(len) = options.max_line_len {
+            let len = floor_char_boundary(&line, len);
+            rendered.lose(TruncationKind::Lines, line.len() - len);
+            line.truncate(len);
+        }
+        rendered.text.push_str(&line);
+        rendered.text.push('\n');
+    }
+    rendered
+}
+
+/// Render a security report of every shared library currently loaded in this
+/// process.
+///
+/// This is empty if nothing was found.
+pub fn render_security() -> String {
+    render_security_modules(&loaded_modules())
+}
+
+/// Render a security report of the given shared libraries, in the given
+/// order.
+pub fn render_security_modules(modules: &[ModuleInfo]) -> String {
+    let mut out = String::new();
+    for module in modules {
+        let name = module.name().to_string_lossy();
+        for seg in module.segments().iter().filter(|seg| !seg.is_empty()) {
+            if !seg.permissions().is_wx() {
+                continue;
+            }
+            let start = module.actual_virtual_memory_address(seg).0 as usize;
+            let seg_name = seg.name().to_string_lossy();
+            let width = 2 + 2 * mem::size_of::<usize>();
+            let _ = writeln!(
+                out,
+                "{:#0width$x}-{:#0width$x} wx-segment {} {}",
+                start,
+                start.wrapping_add(seg.len()),
+                if seg_name.is_empty() { "-" } else { &seg_name },
+                if name.is_empty() { "-" } else { &name },
+                width = width
+            );
+        }
+    }
+    out
+}
+
+/// Get the largest index no greater than `index` that is on a character
+/// boundary in `s`.
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+    (0..=index).rev().find(|&i| s.is_char_boundary(i)).unwrap_or(0)
+}
+
+/// What replaces the beginning of names that are too long.
+const ELLIPSIS: &str = "...";
+
+/// Shorten `s` to at most `len` bytes by replacing its beginning with `...`.
+fn truncate_front(s: &str, len: usize) -> Cow<'_, str> {
+    if s.len() <= len {
+        return Cow::Borrowed(s);
+    }
+    if len <= ELLIPSIS.len() {
+        return Cow::Borrowed(&s[s.len() - floor_char_boundary_rev(s, len)..]);
+    }
+
+    let keep = floor_char_boundary_rev(s, len - ELLIPSIS.len());
+    Cow::Owned(format!("{}{}", ELLIPSIS, &s[s.len() - keep..]))
+}
+
+/// Get the length of the longest suffix of `s` no longer than `len` bytes that
+/// starts on a character boundary.
+fn floor_char_boundary_rev(s: &str, len: usize) -> usize {
+    let len = len.min(s.len());
+    (0..=len)
+        .rev()
+        .find(|&n| s.is_char_boundary(s.len() - n))
+        .unwrap_or(0)
+}
+
+/// Get the range of addresses covered by the module's non-empty, accessible
+/// segments.
+fn avma_span(module: &ModuleInfo) -> (usize, usize) {
+    let (start, end) = module
+        .segments()
+        .iter()
+        .filter(|seg| !seg.is_empty() && seg.permissions() != SegmentPermissions::default())
+        .map(|seg| {
+            let start = module.actual_virtual_memory_address(seg).0 as usize;
+            (start, start.wrapping_add(seg.len()))
+        })
+        .fold((usize::MAX, 0), |(lo, hi), (start, end)| {
+            (lo.min(start), hi.max(end))
+        });
+
+    if start <= end {
+        (start, end)
+    } else {
+        (0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::filter::NameMatching;
+    use super::super::path::Normalize;
+
+    #[test]
+    fn one_line_per_module() {
+        let modules = loaded_modules();
+        let report = render_modules(&modules);
+        assert_eq!(report.lines().count(), modules.len());
+
+        for (line, module) in report.lines().zip(&modules) {
+            let columns: Vec<_> = line.splitn(4, ' ').collect();
+            assert_eq!(columns.len(), 4, "malformed line: {}", line);
+            assert!(columns[0].contains('-'));
+            match module.id() {
+                Some(id) => assert_eq!(columns[2], id.to_string()),
+                None => assert_eq!(columns[2], "-"),
+            }
+        }
+    }
+
+    #[test]
+    fn truncation_keeps_char_boundaries() {
+        assert_eq!(truncate_front("/usr/lib/libfoo.so", 100), "/usr/lib/libfoo.so");
+        assert_eq!(truncate_front("/usr/lib/libfoo.so", 13), ".../libfoo.so");
+        assert_eq!(truncate_front("/usr/lib/libfoo.so", 2), "so");
+        assert_eq!(truncate_front("/é/libé.so", 9), "...bé.so");
+        assert_eq!(truncate_front("/é/libé.so", 8), "...é.so");
+        assert_eq!(truncate_front("/é/libé.so", 7), "....so");
+        assert_eq!(floor_char_boundary("aé", 2), 1);
+        assert_eq!(floor_char_boundary("aé", 3), 3);
+    }
+
+    #[test]
+    fn options_limit_names_and_lines() {
+        let modules = loaded_modules();
+
+        let report = render_modules_with_options(&modules, &Options::new().basename(true));
+        for line in report.lines() {
+            let name = line.splitn(4, ' ').nth(3).unwrap();
+            assert!(!name.contains('/'), "not a basename: {}", name);
+        }
+
+        let report = render_modules_with_options(&modules, &Options::new().max_name_len(10));
+        for line in report.lines() {
+            let name = line.splitn(4, ' ').nth(3).unwrap();
+            assert!(name.len() <= 10, "name too long: {}", name);
+        }
+
+        let lowercase = Normalize(NameMatching::new().case_insensitive(true));
+        let report = render_modules_with_options(&modules, &Options::new().path_policy(lowercase));
+        for line in report.lines() {
+            let name = line.splitn(4, ' ').nth(3).unwrap();
+            assert_eq!(name, name.to_lowercase());
+        }
+
+        let report = render_modules_with_options(&modules, &Options::new().max_line_len(40));
+        assert_eq!(report.lines().count(), modules.len());
+        for line in report.lines() {
+            assert!(line.len() <= 40, "line too long: {}", line);
+        }
+    }
+
+    #[test]
+    fn truncation_is_reported() {
+        use super::super::snapshot::Source;
+        use std::ffi::CString;
+
+        let module = |name: &str| {
+            ModuleInfo::from_parts(CString::new(name).unwrap(), Vec::new(), Source::LoaderList)
+        };
+        let modules = [module("/usr/lib/libfoo.so"), module("/lib/a.so"), module("/b.so")];
+
+        let rendered = render_modules_checked(&modules, &Options::new());
+        assert_eq!(rendered.text, render_modules(&modules));
+        assert!(rendered.truncated.is_empty());
+
+        let rendered = render_modules_checked(&modules, &Options::new().max_modules(1));
+        assert_eq!(rendered.text.lines().count(), 1);
+        assert_eq!(
+            rendered.truncated,
+            vec![Truncated {
+                kind: TruncationKind::Modules,
+                lost: 2,
+            }]
+        );
+
+        // Only `/usr/lib/libfoo.so` is shortened, to `.../libfoo.so`.
+        let rendered = render_modules_checked(&modules, &Options::new().max_name_len(13));
+        assert_eq!(
+            rendered.truncated,
+            vec![Truncated {
+                kind: TruncationKind::Names,
+                lost: 8,
+            }]
+        );
+
+        let full = render_modules(&modules);
+        let rendered = render_modules_checked(&modules, &Options::new().max_line_len(20));
+        let lost = full.len() - rendered.text.len();
+        assert_eq!(
+            rendered.truncated,
+            vec![Truncated {
+                kind: TruncationKind::Lines,
+                lost,
+            }]
+        );
+    }
+
+    #[test]
+    fn security_report_lists_wx_segments() {
+        let modules = loaded_modules();
+        let report = render_security_modules(&modules);
+        let expected: usize = modules
+            .iter()
+            .map(|module| {
+                module
+                    .segments()
+                    .iter()
+                    .filter(|seg| !seg.is_empty() && seg.permissions().is_wx())
+                    .count()
+            })
+            .sum();
+        assert_eq!(report.lines().count(), expected);
+        for line in report.lines() {
+            assert_eq!(line.split(' ').nth(1), Some("wx-segment"));
+        }
+        assert_eq!(render_security_modules(&[]), "");
+    }
+
+    #[test]
+    fn security_report_format() {
+        use super::super::snapshot::{SegmentInfo, Source};
+        use super::super::Svma;
+        use std::ffi::CString;
+
+        let segment = |name: &str, start: usize, write| {
+            let permissions = SegmentPermissions {
+                read: true,
+                write,
+                execute: true,
+            };
+            SegmentInfo::new(CString::new(name).unwrap(), Svma(start as *const u8), 0x1000, 0,
+                             0x1000, permissions)
+        };
+        let module = ModuleInfo::from_parts(
+            CString::new("/opt/lib/libjit.so").unwrap(),
+            vec![segment("text", 0x1000, false), segment("jit", 0x3000, true)],
+            Source::LoaderList,
+        );
+
+        let width = 2 + 2 * mem::size_of::<usize>();
+        assert_eq!(
+            render_security_modules(&[module]),
+            format!("{:#0w$x}-{:#0w$x} wx-segment jit /opt/lib/libjit.so\n", 0x3000, 0x4000, w = width)
+        );
+    }
+
+    #[test]
+    fn legacy_names_are_decoded() {
+        use super::super::encoding::Latin1;
+        use super::super::snapshot::Source;
+        use std::ffi::CString;
+
+        let module = ModuleInfo::from_parts(
+            CString::new(&b"/opt/m\xfcller/libfoo.so"[..]).unwrap(),
+            Vec::new(),
+            Source::LoaderList,
+        );
+        let modules = [module];
+        let name = |options: &Options| {
+            let report = render_modules_with_options(&modules, options);
+            report.trim_end().rsplit(' ').next().unwrap().to_owned()
+        };
+        assert_eq!(name(&Options::new()), "/opt/m\u{fffd}ller/libfoo.so");
+        assert_eq!(name(&Options::new().name_decoder(Latin1)), "/opt/m\u{fc}ller/libfoo.so");
+        assert_eq!(name(&Options::new().name_decoder(Latin1).basename(true)), "libfoo.so");
+    }
+
+    #[test]
+    fn render_is_empty_only_when_unsupported() {
+        assert_eq!(render().is_empty(), !::TARGET_SUPPORTED);
+    }
+}
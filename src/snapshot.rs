@@ -0,0 +1,1828 @@
+//! Owned snapshots of loaded shared libraries.
+//!
+//! The types handed out by `SharedLibrary::each` borrow the loader's own data
+//! structures and are only valid inside the callback. The types in this module
+//! copy out everything they need, so they can be kept around, sent to other
+//! threads, and compared after the fact.
+
+use super::filter::Filter;
+use super::path::PathPolicy;
+use super::regions::{self, HugePageUsage, MemoryUsage, Residency};
+use super::trace;
+use super::{
+    apply_bias, dladdr_symbol, exported_symbol, Avma, Bias, IterationControl, LockHazards,
+    ManagedImageKind, Segment, SegmentPermissions, SharedLibrary, SharedLibraryId, Svma,
+    TargetSharedLibrary, Truncated, TruncationKind, NATIVE_AOT_DEBUG_HEADER,
+};
+
+use std::cell::RefCell;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::time::SystemTime;
+
+/// The last load generation handed out, and the generation of each module
+/// seen loaded since, by name and bias.
+type LoadGenerations = (u64, HashMap<(CString, Bias), u64>);
+
+static LOAD_GENERATIONS: Mutex<Option<LoadGenerations>> = Mutex::new(None);
+static REGISTER_FORK_HANDLERS: Once = Once::new();
+
+thread_local! {
+    /// The `LOAD_GENERATIONS` guard held by the thread calling `fork`.
+    static FORK_GUARD: RefCell<Option<MutexGuard<'static, Option<LoadGenerations>>>> =
+        const { RefCell::new(None) };
+}
+
+/// Where the information about a module came from.
+///
+/// The loader's list is the most trustworthy source: it is what the dynamic
+/// linker itself uses to resolve symbols. The other sources are inferred
+/// from memory and can be incomplete or wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Source {
+    /// The platform loader's list of loaded shared libraries, as enumerated
+    /// by `SharedLibrary::each`.
+    LoaderList,
+    /// The process's memory map, such as `/proc/self/maps`. See
+    /// `regions::mapped_modules`.
+    MapsParse,
+    /// Scanning memory for image headers that the loader doesn't know about.
+    /// See `regions::scan_for_images`.
+    MemoryScan,
+    /// A core file. No source in this crate produces these yet.
+    CoreFile,
+    /// A minidump. No source in this crate produces these yet.
+    Minidump,
+}
+
+/// The order in which modules are enumerated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Order {
+    /// The order the loader keeps them in, which is load order. See
+    /// `SharedLibrary::each`.
+    Loader,
+    /// Ascending order of the lowest address of their non-empty segments.
+    /// Modules without any come last, in loader order.
+    BaseAddress,
+}
+
+impl Default for Order {
+    #[inline]
+    fn default() -> Self {
+        Order::Loader
+    }
+}
+
+/// Options controlling how much is copied out of each shared library.
+///
+/// Everything is copied by default. Samplers and other hot paths that only
+/// need names and addresses can turn off the parts they don't use.
+#[derive(Clone, Debug)]
+pub struct EnumerateOptions {
+    segments: bool,
+    debug_info: bool,
+    header_bytes: usize,
+    order: Order,
+    filter: Filter,
+    path_policy: Option<Arc<dyn PathPolicy>>,
+    lock_free: bool,
+    max_modules: Option<usize>,
+}
+
+impl Default for EnumerateOptions {
+    fn default() -> Self {
+        EnumerateOptions {
+            segments: true,
+            debug_info: true,
+            header_bytes: 0,
+            order: Order::Loader,
+            filter: Filter::default(),
+            path_policy: None,
+            lock_free: false,
+            max_modules: None,
+        }
+    }
+}
+
+impl EnumerateOptions {
+    /// Get the default options, which copy everything.
+    #[inline]
+    pub fn new() -> Self {
+        EnumerateOptions::default()
+    }
+
+    /// Copy each shared library's segments. If disabled,
+    /// `ModuleInfo::segments` is empty.
+    #[inline]
+    pub fn segments(mut self, segments: bool) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    /// Look up each shared library's debug-id. This requires parsing its
+    /// headers and notes, and allocates on some platforms. If disabled,
+    /// `ModuleInfo::id` is `None`.
+    #[inline]
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+
+    /// Copy up to `len` bytes from the start of each shared library's image,
+    /// where its ELF or Mach-O headers are mapped, so they can be inspected
+    /// after the fact. Nothing is copied by default.
+    ///
+    /// Fewer bytes are copied if the first segment is shorter than `len`.
+    #[inline]
+    pub fn header_bytes(mut self, len: usize) -> Self {
+        self.header_bytes = len;
+        self
+    }
+
+    /// Set the order modules are enumerated in. Loader order is the default.
+    ///
+    /// Any order other than `Order::Loader` has to copy every module before
+    /// handing out the first one. `ModuleInfo::load_index` always refers to
+    /// the loader order.
+    #[inline]
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Only copy the shared libraries that match `filter`. The others are
+    /// skipped before anything is copied out of them.
+    ///
+    /// `ModuleInfo::load_index` still counts the skipped shared libraries.
+    #[inline]
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Apply `policy` to each shared library's name before copying it, so
+    /// that `ModuleInfo::name`, and everything built from snapshots, uses the
+    /// policy's names. The filter uses the policy too, unless it has its own.
+    ///
+    /// Load generations are still tracked by the names the loader reports.
+    #[inline]
+    pub fn path_policy<P: PathPolicy + 'static>(mut self, policy: P) -> Self {
+        self.path_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Copy at most `max` modules, such as to bound the memory a snapshot
+    /// taken in a crash handler needs. The rest are still counted, so that
+    /// `each_with_options` can tell how many were left out.
+    ///
+    /// With `Order::BaseAddress`, the modules at the lowest addresses are
+    /// kept, and only copies of at most `max` modules are held at once while
+    /// they are being found.
+    #[inline]
+    pub fn max_modules(mut self, max: usize) -> Self {
+        self.max_modules = Some(max);
+        self
+    }
+
+    /// Take as few locks as possible.
+    ///
+    /// Shared libraries are enumerated with
+    /// `SharedLibrary::each_unsafe_signal_safe` instead of `each`, and none of
+    /// this crate's locks are taken, so modules get no load generation. The
+    /// loader's lock may still be taken; check `lock_hazards`.
+    ///
+    /// This still isn't async-signal-safe: modules are copied into allocated
+    /// memory, `Order::BaseAddress` keeps them in an allocated heap while
+    /// sorting, and the `trace` hooks still run. Signal handlers should use
+    /// `SharedLibrary::each_unsafe_signal_safe` directly.
+    ///
+    /// # Safety
+    ///
+    /// The same as for `each_unsafe_signal_safe`: if a callback panics, the
+    /// process aborts, and on macOS no shared library may be unloaded while
+    /// enumerating.
+    #[inline]
+    pub unsafe fn lock_free(mut self, lock_free: bool) -> Self {
+        self.lock_free = lock_free;
+        self
+    }
+
+    /// Get the locks that enumerating with these options may take.
+    ///
+    /// This always includes the allocator's locks, since modules are copied
+    /// into allocated memory.
+    pub fn lock_hazards(&self) -> LockHazards {
+        if self.lock_free {
+            LockHazards {
+                allocator_lock: true,
+                ..TargetSharedLibrary::signal_safe_lock_hazards()
+            }
+        } else {
+            LockHazards {
+                // Load generations are kept behind a lock.
+                crate_lock: true,
+                allocator_lock: true,
+                ..TargetSharedLibrary::lock_hazards()
+            }
+        }
+    }
+}
+
+/// An owned copy of one of a shared library's segments.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SegmentInfo {
+    name: CString,
+    svma: Svma,
+    len: usize,
+    file_offset: u64,
+    file_size: u64,
+    permissions: SegmentPermissions,
+}
+
+impl SegmentInfo {
+    #[inline]
+    pub(crate) fn new(
+        name: CString,
+        svma: Svma,
+        len: usize,
+        file_offset: u64,
+        file_size: u64,
+        permissions: SegmentPermissions,
+    ) -> Self {
+        SegmentInfo {
+            name,
+            svma,
+            len,
+            file_offset,
+            file_size,
+            permissions,
+        }
+    }
+
+    /// Get this segment's name.
+    #[inline]
+    pub fn name(&self) -> &CStr {
+        &self.name
+    }
+
+    /// Get this segment's stated virtual memory address.
+    #[inline]
+    pub fn stated_virtual_memory_address(&self) -> Svma {
+        self.svma
+    }
+
+    /// Get the length of this segment in memory (in bytes).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Is this segment empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the offset of this segment's contents within the file it was
+    /// loaded from (in bytes).
+    #[inline]
+    pub fn file_offset(&self) -> u64 {
+        self.file_offset
+    }
+
+    /// Get the number of bytes of this segment that are backed by the file it
+    /// was loaded from.
+    #[inline]
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Get the offset within the file this segment was loaded from of the
+    /// given stated virtual memory address, like
+    /// `Segment::svma_to_file_offset`.
+    #[inline]
+    pub fn svma_to_file_offset(&self, address: Svma) -> Option<u64> {
+        super::svma_to_file_offset(self.svma, self.len, self.file_offset, self.file_size, address)
+    }
+
+    /// Get the stated virtual memory address that the given offset within the
+    /// file this segment was loaded from is mapped at, like
+    /// `Segment::file_offset_to_svma`.
+    #[inline]
+    pub fn file_offset_to_svma(&self, offset: u64) -> Option<Svma> {
+        super::file_offset_to_svma(self.svma, self.len, self.file_offset, self.file_size, offset)
+    }
+
+    /// Get the memory protection this segment was initially mapped with.
+    #[inline]
+    pub fn permissions(&self) -> SegmentPermissions {
+        self.permissions
+    }
+}
+
+/// An owned copy of a symbol found by `ModuleInfo::symbol_at`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SymbolInfo {
+    name: CString,
+    address: Avma,
+    offset: usize,
+}
+
+// The raw pointer inside `Avma` is only ever used as a number.
+unsafe impl Send for SymbolInfo {}
+unsafe impl Sync for SymbolInfo {}
+
+impl SymbolInfo {
+    /// Get the symbol's name, which is not demangled.
+    #[inline]
+    pub fn name(&self) -> &CStr {
+        &self.name
+    }
+
+    /// Get the actual virtual memory address of the symbol.
+    #[inline]
+    pub fn address(&self) -> Avma {
+        self.address
+    }
+
+    /// Get the offset of the looked-up address from the symbol's address.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// An owned copy of the information about a loaded shared library.
+///
+/// Two `ModuleInfo`s are equal if they have the same debug-id and were loaded
+/// with the same bias, i.e. they are the same file loaded at the same address.
+/// Names and segments aren't compared, because the same file can be reported
+/// under different paths. Modules without a debug-id are compared by name and
+/// bias instead.
+#[derive(Clone, Debug)]
+pub struct ModuleInfo {
+    name: CString,
+    id: Option<SharedLibraryId>,
+    bias: Bias,
+    segments: Vec<SegmentInfo>,
+    header: Vec<u8>,
+    load_index: Option<usize>,
+    load_time: Option<SystemTime>,
+    load_generation: Option<u64>,
+    source: Source,
+    main_executable: bool,
+}
+
+// The raw pointers inside `Svma` and `Bias` are only ever used as numbers.
+unsafe impl Send for ModuleInfo {}
+unsafe impl Sync for ModuleInfo {}
+
+impl ModuleInfo {
+    /// Copy the information about the given shared library.
+    #[inline]
+    pub fn new<S: SharedLibrary>(shlib: &S) -> Self {
+        ModuleInfo::with_options(shlib, &EnumerateOptions::default())
+    }
+
+    /// Copy the parts of the information about the given shared library that
+    /// are enabled in `options`.
+    pub fn with_options<S: SharedLibrary>(shlib: &S, options: &EnumerateOptions) -> Self {
+        ModuleInfo {
+            name: match options.path_policy {
+                Some(ref policy) => policy.apply(shlib.name()).into_owned(),
+                None => shlib.name().to_owned(),
+            },
+            id: if options.debug_info { shlib.id() } else { None },
+            bias: shlib.virtual_memory_bias(),
+            segments: if options.segments {
+                shlib
+                    .segments()
+                    .map(|seg| SegmentInfo {
+                        name: seg.name().to_owned(),
+                        svma: seg.stated_virtual_memory_address(),
+                        len: seg.len(),
+                        file_offset: seg.file_offset(),
+                        file_size: seg.file_size(),
+                        permissions: seg.permissions(),
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            header: if options.header_bytes > 0 {
+                copy_header(shlib, options.header_bytes)
+            } else {
+                Vec::new()
+            },
+            load_index: None,
+            load_time: None,
+            load_generation: None,
+            source: Source::LoaderList,
+            main_executable: shlib.is_main_executable(),
+        }
+    }
+
+    /// Build a module from information that didn't come from the loader.
+    pub(crate) fn from_parts(name: CString, segments: Vec<SegmentInfo>, source: Source) -> Self {
+        ModuleInfo {
+            name,
+            id: None,
+            bias: Bias(0),
+            segments,
+            header: Vec::new(),
+            load_index: None,
+            load_time: None,
+            load_generation: None,
+            source,
+            main_executable: false,
+        }
+    }
+
+    /// Build a module with a segment at each of the given `(start, len)`
+    /// address ranges, for tests.
+    #[cfg(test)]
+    pub(crate) fn fake(name: &str, ranges: &[(usize, usize)], source: Source) -> Self {
+        let segments = ranges
+            .iter()
+            .map(|&(start, len)| {
+                SegmentInfo::new(
+                    CString::default(),
+                    Svma(start as *const u8),
+                    len,
+                    0,
+                    len as u64,
+                    SegmentPermissions::default(),
+                )
+            })
+            .collect();
+        ModuleInfo::from_parts(CString::new(name).unwrap(), segments, source)
+    }
+
+    /// Set the debug-id and bias of a module built with `from_parts`.
+    #[inline]
+    pub(crate) fn with_identity(mut self, id: Option<SharedLibraryId>, bias: Bias) -> Self {
+        self.id = id;
+        self.bias = bias;
+        self
+    }
+
+    /// Get the name of this shared library.
+    #[inline]
+    pub fn name(&self) -> &CStr {
+        &self.name
+    }
+
+    /// Get the debug-id of this shared library, if it had one.
+    #[inline]
+    pub fn id(&self) -> Option<&SharedLibraryId> {
+        self.id.as_ref()
+    }
+
+    /// Get the bias of this shared library.
+    #[inline]
+    pub fn virtual_memory_bias(&self) -> Bias {
+        self.bias
+    }
+
+    /// Get this shared library's segments.
+    #[inline]
+    pub fn segments(&self) -> &[SegmentInfo] {
+        &self.segments
+    }
+
+    /// Get the bytes copied from the start of this shared library's image.
+    ///
+    /// This is empty unless `EnumerateOptions::header_bytes` was set.
+    #[inline]
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Get this shared library's position in the loader's list when the
+    /// snapshot was taken, starting from zero for the main executable.
+    ///
+    /// Both the Linux and macOS loaders keep their lists in load order, so
+    /// the module with the highest index is the most recently loaded one.
+    /// Indices are not stable across snapshots: unloading a shared library
+    /// shifts the indices of everything loaded after it.
+    ///
+    /// This is `None` for modules created directly with `ModuleInfo::new`.
+    #[inline]
+    pub fn load_index(&self) -> Option<usize> {
+        self.load_index
+    }
+
+    /// Get when this shared library was loaded, if known.
+    ///
+    /// None of the supported loaders record load times, so this is only set
+    /// for modules reported by a [`Poller`](../poll/struct.Poller.html), as
+    /// the time of the poll that first saw the module. The actual load
+    /// happened at most one polling interval earlier.
+    #[inline]
+    pub fn load_time(&self) -> Option<SystemTime> {
+        self.load_time
+    }
+
+    /// Get the order in which this shared library was first seen loaded,
+    /// counting up from one across every snapshot taken in this process.
+    ///
+    /// A shared library keeps its load generation in every snapshot taken
+    /// for as long as it stays loaded, and any shared library seen loaded
+    /// later, even at the same address, gets a higher one. Unloads are
+    /// noticed by `ProcessMap::new` and `Poller`s, which see every loaded
+    /// shared library.
+    ///
+    /// This is `None` for modules created directly with `ModuleInfo::new`.
+    #[inline]
+    pub fn load_generation(&self) -> Option<u64> {
+        self.load_generation
+    }
+
+    /// Get this module's lowest address tagged with its load generation, to
+    /// tell it apart from other modules loaded at the same address before or
+    /// after it.
+    ///
+    /// This is `None` if the module has no load generation or no non-empty
+    /// segments.
+    pub fn tagged_base(&self) -> Option<TaggedBase> {
+        Some(TaggedBase {
+            base: Avma(self.span()?.start as *const u8),
+            generation: self.load_generation?,
+        })
+    }
+
+    /// Find out whether any of this module's non-empty segments is mapped both
+    /// writable and executable. See `SharedLibrary::has_wx_segments`.
+    #[inline]
+    pub fn has_wx_segments(&self) -> bool {
+        self.segments.iter().any(|seg| !seg.is_empty() && seg.permissions.is_wx())
+    }
+
+    /// Find out how much of this module's executable segments are currently
+    /// resident in physical memory.
+    ///
+    /// This queries the memory now, not when the snapshot was taken, so it
+    /// fails if the module has been unloaded since.
+    pub fn residency(&self) -> io::Result<Residency> {
+        let mut total = Residency::default();
+        for seg in self.segments.iter().filter(|seg| !seg.is_empty() && seg.permissions.execute) {
+            let start = self.actual_virtual_memory_address(seg).0 as usize;
+            let residency = regions::residency(start..start + seg.len())?;
+            total.resident_bytes += residency.resident_bytes;
+            total.total_bytes += residency.total_bytes;
+        }
+        Ok(total)
+    }
+
+    /// Find out how much of the memory backing this module's executable
+    /// segments is mapped with huge pages.
+    ///
+    /// Like `residency`, this queries the memory now, not when the snapshot
+    /// was taken.
+    pub fn huge_page_usage(&self) -> io::Result<HugePageUsage> {
+        match self.executable_span() {
+            Some(span) => regions::huge_page_usage(span),
+            None => Ok(HugePageUsage::default()),
+        }
+    }
+
+    /// Find out how the pages backing the given segment of this module are
+    /// shared, which shows how much of it was copied on write by relocations
+    /// or patching.
+    ///
+    /// Like `residency`, this queries the memory now, not when the snapshot
+    /// was taken. See `regions::memory_usage` for the caveats.
+    pub fn segment_memory_usage(&self, segment: &SegmentInfo) -> io::Result<MemoryUsage> {
+        if segment.is_empty() {
+            return Ok(MemoryUsage::default());
+        }
+        let start = self.actual_virtual_memory_address(segment).0 as usize;
+        regions::memory_usage(start..start + segment.len())
+    }
+
+    /// Find out how much memory this module's segments use, and how much of
+    /// it is shared with other processes.
+    ///
+    /// Every memory region overlapping one of the segments is counted once,
+    /// in full. Like `residency`, this queries the memory now, not when the
+    /// snapshot was taken.
+    pub fn memory_usage(&self) -> io::Result<MemoryUsage> {
+        let ranges: Vec<_> = self
+            .segments
+            .iter()
+            .filter(|seg| !seg.is_empty())
+            .map(|seg| {
+                let start = self.actual_virtual_memory_address(seg).0 as usize;
+                start..start + seg.len()
+            })
+            .collect();
+        if ranges.is_empty() {
+            return Ok(MemoryUsage::default());
+        }
+        regions::memory_usage_of(&ranges)
+    }
+
+    /// Get the range of actual virtual memory addresses spanned by this
+    /// module's non-empty executable segments.
+    fn executable_span(&self) -> Option<Range<usize>> {
+        self.segments
+            .iter()
+            .filter(|seg| !seg.is_empty() && seg.permissions.execute)
+            .map(|seg| {
+                let start = self.actual_virtual_memory_address(seg).0 as usize;
+                start..start + seg.len()
+            })
+            .fold(None, |span: Option<Range<usize>>, range| match span {
+                Some(span) => Some(span.start.min(range.start)..span.end.max(range.end)),
+                None => Some(range),
+            })
+    }
+
+    /// Get the range of actual virtual memory addresses spanned by this
+    /// module's non-empty segments.
+    fn span(&self) -> Option<Range<usize>> {
+        self.segments
+            .iter()
+            .filter(|seg| !seg.is_empty())
+            .map(|seg| {
+                let start = self.actual_virtual_memory_address(seg).0 as usize;
+                start..start + seg.len()
+            })
+            .fold(None, |span: Option<Range<usize>>, range| match span {
+                Some(span) => Some(span.start.min(range.start)..span.end.max(range.end)),
+                None => Some(range),
+            })
+    }
+
+    /// Get where the information about this module came from.
+    #[inline]
+    pub fn source(&self) -> Source {
+        self.source
+    }
+
+    #[inline]
+    pub(crate) fn set_load_time(&mut self, load_time: Option<SystemTime>) {
+        self.load_time = load_time;
+    }
+
+    /// Find out whether this is the main executable of the process. See
+    /// `SharedLibrary::is_main_executable`.
+    ///
+    /// This is always false for modules that didn't come from the loader.
+    #[inline]
+    pub fn is_main_executable(&self) -> bool {
+        self.main_executable
+    }
+
+    /// Find the nearest exported or dynamic symbol at or before the given
+    /// AVMA, if the address is within one of this module's segments. See
+    /// `SharedLibrary::symbol_at`.
+    ///
+    /// This is the way to look symbols up without risking a deadlock, since
+    /// `dladdr` isn't called while the loader's list is being walked. Like
+    /// `residency`, it asks the loader now, not when the snapshot was taken,
+    /// so it is only meaningful while the module is still loaded.
+    pub fn symbol_at(&self, address: Avma) -> Option<SymbolInfo> {
+        let addr = address.0 as usize;
+        let inside = self.segments.iter().any(|seg| {
+            let start = self.actual_virtual_memory_address(seg).0 as usize;
+            (start..start + seg.len()).contains(&addr)
+        });
+        if !inside {
+            return None;
+        }
+        dladdr_symbol(address).map(|symbol| SymbolInfo {
+            name: symbol.name().to_owned(),
+            address: symbol.address(),
+            offset: symbol.offset(),
+        })
+    }
+
+    /// Find out whether this module is a .NET image compiled ahead of time,
+    /// which has to be symbolicated differently from both native code and
+    /// JIT-compiled managed code.
+    ///
+    /// Only NativeAOT images go through the platform's loader; they are
+    /// recognized by the `DotNetRuntimeDebugHeader` symbol they export for
+    /// debuggers. ReadyToRun images are mapped by the .NET runtime itself and
+    /// can be found with `regions::scan_for_images`.
+    ///
+    /// This looks the symbol up with `dlopen` and `dlsym`, which take the
+    /// loader's lock, so it can't be done while `SharedLibrary::each` is
+    /// running. The symbol has to be inside one of the module's segments, so
+    /// this is `None` for snapshots taken without segments, and for modules
+    /// that have been unloaded since the snapshot was taken.
+    pub fn managed_image_kind(&self) -> Option<ManagedImageKind> {
+        // The main executable is opened by a null name, whatever path it was
+        // reported under.
+        let name = if self.main_executable { Default::default() } else { self.name.as_c_str() };
+        let header = exported_symbol(name, NATIVE_AOT_DEBUG_HEADER)?.0 as usize;
+        let inside = self.segments.iter().any(|seg| {
+            let start = self.actual_virtual_memory_address(seg).0 as usize;
+            (start..start + seg.len()).contains(&header)
+        });
+        if inside {
+            Some(ManagedImageKind::NativeAot)
+        } else {
+            None
+        }
+    }
+
+    /// Get the actual virtual memory address of the given segment.
+    #[inline]
+    pub fn actual_virtual_memory_address(&self, segment: &SegmentInfo) -> Avma {
+        apply_bias(segment.svma, self.bias)
+    }
+}
+
+/// A module's base address, tagged with its load generation.
+///
+/// Callers that cache which module an address belongs to by the module's
+/// base address mis-attribute addresses once the module is unloaded and
+/// another one is loaded at the same base. Caching the tagged base instead
+/// makes such stale attributions detectable with `ProcessMap::is_current`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TaggedBase {
+    /// The lowest actual virtual memory address of the module's non-empty
+    /// segments.
+    pub base: Avma,
+    /// The module's load generation. See `ModuleInfo::load_generation`.
+    pub generation: u64,
+}
+
+// The raw pointer inside `Avma` is only ever used as a number.
+unsafe impl Send for TaggedBase {}
+unsafe impl Sync for TaggedBase {}
+
+/// Get the load generation of the shared library with the given name and
+/// bias, handing out the next one if it hasn't been seen before.
+fn load_generation(name: &CStr, bias: Bias) -> u64 {
+    let mut generations = lock_load_generations();
+    let (last, seen) = generations.get_or_insert_with(Default::default);
+    if let Some(&generation) = seen.get(&(name.to_owned(), bias)) {
+        return generation;
+    }
+    *last += 1;
+    seen.insert((name.to_owned(), bias), *last);
+    *last
+}
+
+/// Lock the load generations.
+///
+/// A forked child only gets a copy of the thread that called `fork`, so if
+/// another thread held the lock at that moment, nothing would ever release it
+/// in the child. The first time the lock is taken, fork handlers are
+/// registered that hold it across every `fork`.
+fn lock_load_generations() -> MutexGuard<'static, Option<LoadGenerations>> {
+    #[cfg(unix)]
+    REGISTER_FORK_HANDLERS.call_once(|| unsafe {
+        libc::pthread_atfork(
+            Some(hold_load_generations),
+            Some(release_load_generations),
+            Some(release_load_generations),
+        );
+    });
+    LOAD_GENERATIONS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(unix)]
+unsafe extern "C" fn hold_load_generations() {
+    let guard = LOAD_GENERATIONS.lock().unwrap_or_else(|err| err.into_inner());
+    let _ = FORK_GUARD.try_with(|held| *held.borrow_mut() = Some(guard));
+}
+
+#[cfg(unix)]
+unsafe extern "C" fn release_load_generations() {
+    let _ = FORK_GUARD.try_with(|held| held.borrow_mut().take());
+}
+
+/// Get the last load generation handed out so far.
+fn last_load_generation() -> u64 {
+    let generations = lock_load_generations();
+    generations.as_ref().map_or(0, |&(last, _)| last)
+}
+
+/// Forget the load generations of shared libraries that aren't among the
+/// given complete list of loaded modules, so that they get a new one if they
+/// are loaded again.
+///
+/// Only generations up to `up_to` are forgotten, because shared libraries
+/// seen by concurrent enumerations may be missing from the list.
+fn forget_unloaded(loaded: &[ModuleInfo], up_to: u64) {
+    let loaded: HashSet<_> = loaded.iter().map(|module| (module.name(), module.bias)).collect();
+    let mut generations = lock_load_generations();
+    if let Some((_, ref mut seen)) = *generations {
+        seen.retain(|(name, bias), &mut generation| {
+            generation > up_to || loaded.contains(&(&name[..], *bias))
+        });
+    }
+}
+
+impl PartialEq for ModuleInfo {
+    fn eq(&self, other: &ModuleInfo) -> bool {
+        self.id == other.id
+            && self.bias == other.bias
+            && (self.id.is_some() || self.name == other.name)
+    }
+}
+
+impl Eq for ModuleInfo {}
+
+impl Hash for ModuleInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.bias.hash(state);
+        if self.id.is_none() {
+            self.name.hash(state);
+        }
+    }
+}
+
+/// Copy up to `len` bytes from the start of the lowest readable segment, which
+/// is where both ELF and Mach-O images map their headers.
+fn copy_header<S: SharedLibrary>(shlib: &S, len: usize) -> Vec<u8> {
+    let first = shlib
+        .segments()
+        .filter(|seg| seg.len() > 0 && seg.permissions().read)
+        .min_by_key(|seg| seg.actual_virtual_memory_address(shlib).0 as usize);
+    match first {
+        Some(seg) => {
+            let start = seg.actual_virtual_memory_address(shlib).0;
+            // The segment is mapped readable for as long as the shared library
+            // is loaded, which it is for the duration of `each`.
+            unsafe { slice::from_raw_parts(start, len.min(seg.len())) }.to_vec()
+        }
+        None => Vec::new(),
+    }
+}
+
+/// An owned snapshot of every shared library loaded in this process.
+///
+/// Two snapshots are equal if they contain equal modules in the same order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProcessMap {
+    modules: Vec<ModuleInfo>,
+}
+
+impl ProcessMap {
+    /// Take a snapshot of the shared libraries currently loaded in this
+    /// process.
+    #[inline]
+    pub fn new() -> Self {
+        ProcessMap {
+            modules: loaded_modules(),
+        }
+    }
+
+    /// Take a snapshot of the shared libraries currently loaded in this
+    /// process, copying only the parts enabled in `options`.
+    ///
+    /// Use `each_with_options` to find out whether modules were left out
+    /// because of `EnumerateOptions::max_modules`.
+    pub fn with_options(options: &EnumerateOptions) -> Self {
+        let mut modules = Vec::new();
+        each_with_options(options, |module| modules.push(module));
+        ProcessMap { modules }
+    }
+
+    /// Get the shared libraries in this snapshot, in the order they were
+    /// enumerated in (loader order, unless `EnumerateOptions::order` says
+    /// otherwise), followed by any that were merged in.
+    #[inline]
+    pub fn modules(&self) -> &[ModuleInfo] {
+        &self.modules
+    }
+
+    /// Merge modules from another source into this snapshot.
+    ///
+    /// Two modules are considered duplicates if the address ranges spanned by
+    /// their segments overlap. For each duplicate, the module from the more
+    /// trustworthy source is kept, in this order: `LoaderList`, `CoreFile`,
+    /// `Minidump`, `MapsParse`, `MemoryScan`. On a tie, the module already in
+    /// this snapshot is kept. If the kept module has an empty name, such as
+    /// the main executable on Linux, it takes the other module's name.
+    ///
+    /// Modules without duplicates are appended in their original order.
+    /// Modules without segments can't be matched, so they are always
+    /// appended.
+    ///
+    /// Duplicates that disagree about their file name (ignoring directories)
+    /// or debug-id are returned as conflicts. Duplicates that agree are
+    /// dropped silently.
+    pub fn merge<I>(&mut self, modules: I) -> Vec<MergeConflict>
+    where
+        I: IntoIterator<Item = ModuleInfo>,
+    {
+        let mut conflicts = Vec::new();
+        for module in modules {
+            let span = module.span();
+            let existing = span.as_ref().and_then(|span| {
+                self.modules.iter_mut().find(|existing| {
+                    existing
+                        .span()
+                        .is_some_and(|other| other.start < span.end && span.start < other.end)
+                })
+            });
+            let existing = match existing {
+                Some(existing) => existing,
+                None => {
+                    self.modules.push(module);
+                    continue;
+                }
+            };
+
+            let (mut kept, discarded) = if rank(module.source) < rank(existing.source) {
+                (module, existing.clone())
+            } else {
+                (existing.clone(), module)
+            };
+            if kept.name.as_bytes().is_empty() {
+                kept.name = discarded.name.clone();
+            }
+
+            let reason = if kept.id.is_some() && discarded.id.is_some() && kept.id != discarded.id
+            {
+                Some(ConflictReason::DifferentId)
+            } else if !discarded.name.as_bytes().is_empty()
+                && file_name(&kept.name) != file_name(&discarded.name)
+            {
+                Some(ConflictReason::DifferentName)
+            } else {
+                None
+            };
+
+            *existing = kept.clone();
+            if let Some(reason) = reason {
+                conflicts.push(MergeConflict {
+                    kept,
+                    discarded,
+                    reason,
+                });
+            }
+        }
+        conflicts
+    }
+
+    /// Find images that are mapped more than once, such as the same shared
+    /// library loaded into several `dlmopen` namespaces, or from two paths
+    /// that are hard links to the same file.
+    ///
+    /// Modules are considered the same image if they have the same debug-id,
+    /// regardless of their names and addresses; modules without one are
+    /// never reported. Each group lists the instances in snapshot order, and
+    /// the groups are ordered by their first instance.
+    pub fn duplicate_images(&self) -> Vec<Vec<&ModuleInfo>> {
+        let mut groups: Vec<Vec<&ModuleInfo>> = Vec::new();
+        let mut group_of_id = HashMap::new();
+        for module in &self.modules {
+            if let Some(id) = module.id() {
+                let index = *group_of_id.entry(id).or_insert_with(|| {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                });
+                groups[index].push(module);
+            }
+        }
+        groups.retain(|group| group.len() > 1);
+        groups
+    }
+
+    /// Find out whether the module with the given tagged base is in this
+    /// snapshot, i.e. whether addresses attributed to it are still
+    /// attributed correctly.
+    pub fn is_current(&self, tag: TaggedBase) -> bool {
+        self.modules.iter().any(|module| module.tagged_base() == Some(tag))
+    }
+
+    /// Bring a snapshot inherited from the parent process up to date in a
+    /// forked child.
+    ///
+    /// A child starts with a copy of its parent's address space, so the
+    /// modules are where the snapshot says, but shared libraries that another
+    /// thread of the parent loaded or unloaded since the snapshot was taken
+    /// aren't accounted for, and the child may have loaded or unloaded more
+    /// before calling this.
+    ///
+    /// The modules from the loader's list are enumerated again with the
+    /// default options. Those that were already in the snapshot keep their
+    /// load times and load generations, so tagged bases recorded in the parent
+    /// stay current in the child for as long as their modules stay loaded.
+    /// Modules from other sources are merged back in, and the conflicts are
+    /// returned.
+    pub fn after_fork(&mut self) -> Vec<MergeConflict> {
+        let load_times: HashMap<_, _> = self
+            .modules
+            .iter()
+            .filter(|module| module.source == Source::LoaderList)
+            .map(|module| ((module.name.clone(), module.bias), module.load_time))
+            .collect();
+        let mut modules = loaded_modules();
+        for module in &mut modules {
+            if let Some(&load_time) = load_times.get(&(module.name.clone(), module.bias)) {
+                module.load_time = load_time;
+            }
+        }
+        let others: Vec<_> = self
+            .modules
+            .drain(..)
+            .filter(|module| module.source != Source::LoaderList)
+            .collect();
+        self.modules = modules;
+        self.merge(others)
+    }
+
+    /// Get summary statistics about this snapshot.
+    ///
+    /// The total size is only counted for modules whose segments were copied.
+    pub fn stats(&self) -> ProcessMapStats {
+        let mut stats = ProcessMapStats::default();
+        for module in &self.modules {
+            stats.module_count += 1;
+            stats.total_size += module.segments().iter().map(SegmentInfo::len).sum::<usize>();
+            match module.id() {
+                Some(&SharedLibraryId::Uuid(_)) => stats.uuid_count += 1,
+                Some(&SharedLibraryId::GnuBuildId(_)) => stats.gnu_build_id_count += 1,
+                None => stats.no_id_count += 1,
+            }
+        }
+        stats
+    }
+}
+
+impl From<Vec<ModuleInfo>> for ProcessMap {
+    #[inline]
+    fn from(modules: Vec<ModuleInfo>) -> Self {
+        ProcessMap { modules }
+    }
+}
+
+/// Two duplicate modules that disagreed, found by
+/// [`ProcessMap::merge`](./struct.ProcessMap.html#method.merge).
+#[derive(Clone, Debug)]
+pub struct MergeConflict {
+    /// The module that was kept in the snapshot.
+    pub kept: ModuleInfo,
+    /// The module that was dropped.
+    pub discarded: ModuleInfo,
+    /// What the two modules disagreed about.
+    pub reason: ConflictReason,
+}
+
+/// What two duplicate modules disagreed about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConflictReason {
+    /// They have different debug-ids.
+    DifferentId,
+    /// They have different file names.
+    DifferentName,
+}
+
+/// How trustworthy each source is, lower being more trustworthy.
+fn rank(source: Source) -> u8 {
+    match source {
+        Source::LoaderList => 0,
+        Source::CoreFile => 1,
+        Source::Minidump => 2,
+        Source::MapsParse => 3,
+        Source::MemoryScan => 4,
+    }
+}
+
+/// Get the last component of a path.
+fn file_name(path: &CStr) -> &[u8] {
+    let path = path.to_bytes();
+    path.rsplit(|&b| b == b'/').next().unwrap_or(path)
+}
+
+/// Summary statistics about a [`ProcessMap`](./struct.ProcessMap.html).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessMapStats {
+    /// The number of loaded shared libraries.
+    pub module_count: usize,
+    /// The sum of the in-memory sizes of every shared library's segments, in
+    /// bytes.
+    pub total_size: usize,
+    /// The number of shared libraries identified by a UUID.
+    pub uuid_count: usize,
+    /// The number of shared libraries identified by a GNU build ID.
+    pub gnu_build_id_count: usize,
+    /// The number of shared libraries without a debug-id.
+    pub no_id_count: usize,
+}
+
+/// Copy the information about every shared library currently loaded in this
+/// process, in the order the loader reports them.
+pub(crate) fn loaded_modules() -> Vec<ModuleInfo> {
+    loaded_modules_matching(&Filter::new())
+}
+
+/// Like `loaded_modules`, but only return the modules that match `filter`.
+///
+/// Every module is still copied, so that the load generations of the ones
+/// that don't match are kept up to date too.
+pub(crate) fn loaded_modules_matching(filter: &Filter) -> Vec<ModuleInfo> {
+    let last_generation = last_load_generation();
+    let mut matched = Vec::new();
+    let mut modules = Vec::new();
+    each_selected(
+        &EnumerateOptions::default(),
+        |shlib| {
+            matched.push(filter.matches(shlib));
+            IterationControl::Continue
+        },
+        |module| modules.push(module),
+    );
+    forget_unloaded(&modules, last_generation);
+    modules
+        .into_iter()
+        .zip(matched)
+        .filter(|&(_, matched)| matched)
+        .map(|(module, _)| module)
+        .collect()
+}
+
+/// Copy the information about each shared library currently loaded in this
+/// process, as configured by `options`, and invoke `f` with each copy.
+///
+/// This is `SharedLibrary::each` followed by `ModuleInfo::with_options`, for
+/// callers that want owned values without paying for the parts they don't
+/// need.
+///
+/// Returns how many modules were left out if `EnumerateOptions::max_modules`
+/// was hit.
+#[inline]
+pub fn each_with_options<F, C>(options: &EnumerateOptions, f: F) -> Option<Truncated>
+where
+    F: FnMut(ModuleInfo) -> C,
+    C: Into<IterationControl>,
+{
+    each_selected(options, |_| IterationControl::Continue, f)
+}
+
+/// Like `each_with_options`, but first invoke `select` with each shared
+/// library that matches the filter in `options`, to decide whether to copy
+/// it.
+///
+/// If `select` returns `IterationControl::Skip`, nothing is copied out of the
+/// shared library and `f` isn't invoked for it. If it returns
+/// `IterationControl::Break`, enumeration stops; when sorting by address,
+/// the shared libraries selected so far are still handed to `f`.
+///
+/// Once `EnumerateOptions::max_modules` modules were copied, `select` isn't
+/// invoked anymore, and the modules that match the filter are counted as
+/// lost. When sorting by address, `select` is only invoked for modules lower
+/// than the highest of the `max` lowest so far, and the modules that drop out
+/// of those are counted as lost instead, so `select` may see more modules
+/// than are handed to `f`.
+pub fn each_selected<G, F, C>(
+    options: &EnumerateOptions,
+    mut select: G,
+    mut f: F,
+) -> Option<Truncated>
+where
+    G: FnMut(&TargetSharedLibrary) -> IterationControl,
+    F: FnMut(ModuleInfo) -> C,
+    C: Into<IterationControl>,
+{
+    let _span = trace::enumeration("snapshot");
+    let max = options.max_modules.unwrap_or(usize::MAX);
+    let mut load_index = 0;
+    let mut copied = 0;
+    let mut lost = 0;
+    match options.order {
+        Order::Loader => each_shlib(options, |shlib| {
+            load_index += 1;
+            if !options.filter.matches_with_policy(shlib, options.path_policy.as_deref()) {
+                trace::skipped(shlib.name(), "filtered out");
+                return IterationControl::Continue;
+            }
+            if copied == max {
+                trace::skipped(shlib.name(), "over the module limit");
+                lost += 1;
+                return IterationControl::Continue;
+            }
+            match select(shlib) {
+                IterationControl::Continue => {}
+                IterationControl::Skip => {
+                    trace::skipped(shlib.name(), "not selected");
+                    return IterationControl::Continue;
+                }
+                IterationControl::Break => return IterationControl::Break,
+            }
+            let mut module = ModuleInfo::with_options(shlib, options);
+            module.load_index = Some(load_index - 1);
+            if !options.lock_free {
+                module.load_generation = Some(load_generation(shlib.name(), module.bias));
+            }
+            copied += 1;
+            f(module).into()
+        }),
+        Order::BaseAddress => {
+            // Keep the `max` lowest modules seen so far, with the highest of
+            // them on top of the heap, so that only those are ever copied.
+            // Ties and modules without segments stay in loader order.
+            let mut kept = Vec::new();
+            let mut heap = BinaryHeap::new();
+            each_shlib(options, |shlib| {
+                load_index += 1;
+                if !options.filter.matches_with_policy(shlib, options.path_policy.as_deref()) {
+                    trace::skipped(shlib.name(), "filtered out");
+                    return IterationControl::Continue;
+                }
+                let base = base_address(shlib);
+                let key = (base.is_none(), base, load_index);
+                if heap.len() == max {
+                    match heap.peek() {
+                        Some(&(highest, _)) if key < highest => {}
+                        _ => {
+                            trace::skipped(shlib.name(), "over the module limit");
+                            lost += 1;
+                            return IterationControl::Continue;
+                        }
+                    }
+                }
+                match select(shlib) {
+                    IterationControl::Continue => {}
+                    IterationControl::Skip => {
+                        trace::skipped(shlib.name(), "not selected");
+                        return IterationControl::Continue;
+                    }
+                    IterationControl::Break => return IterationControl::Break,
+                }
+                let mut module = ModuleInfo::with_options(shlib, options);
+                module.load_index = Some(load_index - 1);
+                if !options.lock_free {
+                    module.load_generation = Some(load_generation(shlib.name(), module.bias));
+                }
+                if heap.len() == max {
+                    let (_, slot) = heap.pop().expect("the heap is full");
+                    kept[slot] = Some(module);
+                    heap.push((key, slot));
+                    lost += 1;
+                } else {
+                    kept.push(Some(module));
+                    heap.push((key, kept.len() - 1));
+                }
+                IterationControl::Continue
+            });
+            for (_, slot) in heap.into_sorted_vec() {
+                let module = kept[slot].take().expect("each slot is on the heap once");
+                if f(module).into() == IterationControl::Break {
+                    break;
+                }
+            }
+        }
+    }
+    if lost > 0 {
+        Some(Truncated {
+            kind: TruncationKind::Modules,
+            lost,
+        })
+    } else {
+        None
+    }
+}
+
+/// Invoke `f` with each shared library, through the enumeration that
+/// `options` asks for.
+fn each_shlib<F>(options: &EnumerateOptions, f: F)
+where
+    F: FnMut(&TargetSharedLibrary) -> IterationControl,
+{
+    if options.lock_free {
+        // Whoever set `lock_free` took on `each_unsafe_signal_safe`'s
+        // obligations.
+        unsafe { TargetSharedLibrary::each_unsafe_signal_safe(f) }
+    } else {
+        TargetSharedLibrary::each(f)
+    }
+}
+
+/// Get the lowest actual virtual memory address of the given shared
+/// library's non-empty segments.
+fn base_address<S: SharedLibrary>(shlib: &S) -> Option<usize> {
+    shlib
+        .segments()
+        .filter(|seg| seg.len() > 0)
+        .map(|seg| seg.actual_virtual_memory_address(shlib).0 as usize)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_skip_segments_and_ids() {
+        let options = EnumerateOptions::new().segments(false).debug_info(false);
+        let mut n = 0;
+        each_with_options(&options, |module| {
+            assert!(module.segments().is_empty());
+            assert!(module.id().is_none());
+            n += 1;
+        });
+        assert_eq!(n > 0, ::TARGET_SUPPORTED);
+    }
+
+    #[test]
+    fn lock_free_snapshots_skip_load_generations() {
+        let options = unsafe { EnumerateOptions::new().lock_free(true) };
+        assert!(!options.lock_hazards().crate_lock);
+        assert!(options.lock_hazards().allocator_lock);
+        assert!(EnumerateOptions::new().lock_hazards().crate_lock);
+
+        // Other tests load and unload libraries concurrently, so only compare
+        // the main executable.
+        let locked = ProcessMap::new();
+        let lock_free = ProcessMap::with_options(&options);
+        assert_eq!(locked.modules().first(), lock_free.modules().first());
+        assert!(lock_free.modules().iter().all(|module| module.load_generation().is_none()));
+    }
+
+    #[test]
+    fn base_address_order_is_sorted() {
+        let loader = ProcessMap::new();
+        let sorted = ProcessMap::with_options(&EnumerateOptions::new().order(Order::BaseAddress));
+        assert_eq!(loader.modules().len(), sorted.modules().len());
+
+        let bases: Vec<_> = sorted
+            .modules()
+            .iter()
+            .filter_map(|module| module.span())
+            .map(|span| span.start)
+            .collect();
+        assert!(bases.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        // Load indices still refer to the loader order.
+        let mut indices: Vec<_> =
+            sorted.modules().iter().filter_map(ModuleInfo::load_index).collect();
+        indices.sort();
+        assert_eq!(indices, (0..loader.modules().len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn base_address_order_can_break() {
+        let options = EnumerateOptions::new().order(Order::BaseAddress);
+        let mut n = 0;
+        each_with_options(&options, |_| {
+            n += 1;
+            IterationControl::Break
+        });
+        assert_eq!(n, ::TARGET_SUPPORTED as usize);
+    }
+
+    #[test]
+    fn filtered_modules_keep_their_load_indices() {
+        let all = ProcessMap::new();
+        let last = match all.modules().last() {
+            Some(last) => last.clone(),
+            None => return,
+        };
+        let span = last.span().unwrap();
+        let options = EnumerateOptions::new().filter(Filter::new().intersecting(span));
+        let filtered = ProcessMap::with_options(&options);
+        assert_eq!(filtered.modules().len(), 1);
+        assert_eq!(filtered.modules()[0].load_index(), last.load_index());
+    }
+
+    #[test]
+    fn path_policy_renames_modules() {
+        use std::borrow::Cow;
+
+        #[derive(Debug)]
+        struct Tagged;
+
+        impl PathPolicy for Tagged {
+            fn apply<'a>(&self, name: &'a CStr) -> Cow<'a, CStr> {
+                let mut tagged = b"tagged:".to_vec();
+                tagged.extend_from_slice(name.to_bytes());
+                Cow::Owned(CString::new(tagged).unwrap())
+            }
+        }
+
+        let options = EnumerateOptions::new()
+            .path_policy(Tagged)
+            .filter(Filter::new().name_contains("tagged:"));
+        let tagged = ProcessMap::with_options(&options);
+        let all = ProcessMap::new();
+        assert_eq!(tagged.modules().is_empty(), !::TARGET_SUPPORTED);
+        assert!(tagged
+            .modules()
+            .iter()
+            .all(|module| module.name().to_bytes().starts_with(b"tagged:")));
+        if let (Some(tagged), Some(first)) = (tagged.modules().first(), all.modules().first()) {
+            assert_eq!(&tagged.name().to_bytes()[7..], first.name().to_bytes());
+        }
+    }
+
+    #[test]
+    fn skipped_modules_are_not_copied() {
+        let mut selected = 0;
+        let mut copied = Vec::new();
+        each_selected(
+            &EnumerateOptions::new(),
+            |_| {
+                selected += 1;
+                if selected % 2 == 0 {
+                    IterationControl::Skip
+                } else {
+                    IterationControl::Continue
+                }
+            },
+            |module| copied.push(module.load_index().unwrap()),
+        );
+        assert_eq!(selected > 0, ::TARGET_SUPPORTED);
+        assert_eq!(copied, (0..selected).step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn module_limits_are_reported() {
+        for &order in &[Order::Loader, Order::BaseAddress] {
+            let options = EnumerateOptions::new().order(order).max_modules(2);
+            let mut copied = 0;
+            match each_with_options(&options, |_| copied += 1) {
+                Some(truncated) => {
+                    assert_eq!(truncated.kind, TruncationKind::Modules);
+                    assert!(truncated.lost > 0);
+                    assert_eq!(copied, 2);
+                }
+                None => assert!(copied <= 2),
+            }
+        }
+
+        let options = EnumerateOptions::new().max_modules(0);
+        let mut copied = 0;
+        let truncated = each_with_options(&options, |_| copied += 1);
+        assert_eq!(copied, 0);
+        assert_eq!(truncated.is_some(), ::TARGET_SUPPORTED);
+        assert_eq!(each_with_options(&EnumerateOptions::new(), |_| {}), None);
+    }
+
+    #[test]
+    fn module_limits_keep_the_lowest_addresses() {
+        // Other tests load and unload libraries concurrently, so enumerate
+        // again if anything was loaded or unloaded in between.
+        for _ in 0..100 {
+            let generation = TargetSharedLibrary::loaded_modules_generation();
+            let options = EnumerateOptions::new().order(Order::BaseAddress);
+            let sorted = ProcessMap::with_options(&options);
+            let limited = ProcessMap::with_options(&options.clone().max_modules(2));
+            if TargetSharedLibrary::loaded_modules_generation() == generation {
+                let expected = &sorted.modules()[..sorted.modules().len().min(2)];
+                assert_eq!(limited.modules(), expected);
+                return;
+            }
+        }
+        panic!("shared libraries kept being loaded or unloaded");
+    }
+
+    #[test]
+    fn select_can_break() {
+        let mut copied = 0;
+        each_selected(&EnumerateOptions::new(), |_| IterationControl::Break, |_| copied += 1);
+        assert_eq!(copied, 0);
+    }
+
+    #[test]
+    fn loader_order_matches_load_indices() {
+        let map = ProcessMap::new();
+        for (i, module) in map.modules().iter().enumerate() {
+            assert_eq!(module.load_index(), Some(i));
+        }
+    }
+
+    #[test]
+    fn process_map_stats() {
+        let map = ProcessMap::new();
+        let stats = map.stats();
+        assert_eq!(stats.module_count, map.modules().len());
+        assert_eq!(
+            stats.uuid_count + stats.gnu_build_id_count + stats.no_id_count,
+            stats.module_count
+        );
+        assert_eq!(stats.total_size > 0, ::TARGET_SUPPORTED);
+
+        let map = ProcessMap::with_options(&EnumerateOptions::new().segments(false));
+        assert_eq!(map.stats().total_size, 0);
+    }
+
+    #[test]
+    fn snapshots_compare_by_identity() {
+        let map = ProcessMap::new();
+        assert_eq!(map, map.clone());
+
+        let modules = map.modules();
+        for (i, a) in modules.iter().enumerate() {
+            for (j, b) in modules.iter().enumerate() {
+                assert_eq!(a == b, i == j, "{:?} vs {:?}", a, b);
+            }
+        }
+
+        // Only the identity matters, not how much was copied.
+        let sparse = ProcessMap::with_options(&EnumerateOptions::new().segments(false));
+        if let (Some(a), Some(b)) = (modules.first(), sparse.modules().first()) {
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn headers_are_only_copied_on_request() {
+        let map = ProcessMap::new();
+        assert!(map.modules().iter().all(|module| module.header().is_empty()));
+
+        let map = ProcessMap::with_options(&EnumerateOptions::new().header_bytes(64));
+        for module in map.modules() {
+            let header = module.header();
+            assert_eq!(header.len(), 64);
+            if cfg!(target_os = "linux") {
+                assert_eq!(&header[..4], b"\x7fELF");
+            } else if cfg!(target_os = "macos") {
+                let magic = [0xce, 0xfa, 0xed, 0xfe];
+                let magic_64 = [0xcf, 0xfa, 0xed, 0xfe];
+                assert!(header[..4] == magic || header[..4] == magic_64);
+            }
+        }
+    }
+
+    #[test]
+    fn load_indices_follow_loader_order() {
+        let map = ProcessMap::new();
+        for (index, module) in map.modules().iter().enumerate() {
+            assert_eq!(module.load_index(), Some(index));
+            assert_eq!(module.source(), Source::LoaderList);
+            assert!(module.load_time().is_none());
+        }
+
+        TargetSharedLibrary::each(|shlib| {
+            assert!(ModuleInfo::new(shlib).load_index().is_none());
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn wx_segments_are_detected() {
+        let mut module =
+            ModuleInfo::fake("/lib/libjit.so", &[(0x1000, 0x1000)], Source::LoaderList);
+        assert!(!module.has_wx_segments());
+
+        module.segments[0].permissions.write = true;
+        module.segments[0].permissions.execute = true;
+        assert!(module.has_wx_segments());
+
+        module.segments[0].len = 0;
+        assert!(!module.has_wx_segments());
+    }
+
+    #[test]
+    fn duplicate_images_are_grouped_by_id() {
+        let with_id = |name, start, id: u8| {
+            let mut module = ModuleInfo::fake(name, &[(start, 0x1000)], Source::LoaderList);
+            module.id = Some(SharedLibraryId::GnuBuildId(vec![id; 20]));
+            module
+        };
+        let map = ProcessMap::from(vec![
+            with_id("/lib/libfoo.so", 0x1000, 1),
+            with_id("/lib/libbar.so", 0x3000, 2),
+            ModuleInfo::fake("/lib/libnoid.so", &[(0x5000, 0x1000)], Source::LoaderList),
+            with_id("/opt/lib/libfoo.so", 0x7000, 1),
+            ModuleInfo::fake("/lib/libnoid.so", &[(0x9000, 0x1000)], Source::LoaderList),
+            with_id("/lib/libfoo.so", 0xb000, 1),
+        ]);
+
+        let duplicates = map.duplicate_images();
+        assert_eq!(duplicates.len(), 1);
+        let starts: Vec<_> = duplicates[0]
+            .iter()
+            .map(|module| module.span().unwrap().start)
+            .collect();
+        assert_eq!(starts, vec![0x1000, 0x7000, 0xb000]);
+    }
+
+    #[test]
+    fn running_process_has_no_duplicate_images() {
+        assert!(ProcessMap::new().duplicate_images().is_empty());
+    }
+
+    #[test]
+    fn load_generations_are_stable_while_loaded() {
+        let first = ProcessMap::new();
+        let second = ProcessMap::new();
+        let (first, second) = match (first.modules().first(), second.modules().first()) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return,
+        };
+        assert!(first.load_generation().is_some());
+        assert_eq!(first.load_generation(), second.load_generation());
+        assert_eq!(first.tagged_base(), second.tagged_base());
+        TargetSharedLibrary::each(|shlib| {
+            assert!(ModuleInfo::new(shlib).load_generation().is_none());
+            IterationControl::Break
+        });
+    }
+
+    #[test]
+    fn reused_bases_get_new_generations() {
+        let bias = Bias(0x7fff_1234_0000);
+        let first = load_generation(&CString::new("reused_bases_a").unwrap(), bias);
+        let second = load_generation(&CString::new("reused_bases_b").unwrap(), bias);
+        assert!(second > first);
+        assert_eq!(load_generation(&CString::new("reused_bases_a").unwrap(), bias), first);
+
+        let mut old =
+            ModuleInfo::fake("/lib/libold.so", &[(0x1000, 0x1000)], Source::LoaderList);
+        old.load_generation = Some(first);
+        let mut new =
+            ModuleInfo::fake("/lib/libnew.so", &[(0x1000, 0x1000)], Source::LoaderList);
+        new.load_generation = Some(second);
+        let tag = old.tagged_base().unwrap();
+        assert_eq!(tag.base, Avma(0x1000 as *const u8));
+        assert_ne!(Some(tag), new.tagged_base());
+
+        assert!(ProcessMap::from(vec![old]).is_current(tag));
+        assert!(!ProcessMap::from(vec![new]).is_current(tag));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn snapshots_survive_fork() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+
+        let mut map = ProcessMap::new();
+        let tag = match map.modules().first().and_then(ModuleInfo::tagged_base) {
+            Some(tag) => tag,
+            None => return,
+        };
+        let load_time = SystemTime::UNIX_EPOCH;
+        map.modules[0].load_time = Some(load_time);
+        let scanned = ModuleInfo::fake("/scanned/image", &[(0x10, 0x10)], Source::MemoryScan);
+        assert!(map.merge(vec![scanned]).is_empty());
+
+        // Keep another thread taking the lock, which a forked child would
+        // never see released without the fork handlers.
+        let stop = Arc::new(AtomicBool::new(false));
+        let contender = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    last_load_generation();
+                }
+            })
+        };
+
+        for _ in 0..8 {
+            match unsafe { libc::fork() } {
+                -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+                0 => {
+                    let mut child = map.clone();
+                    let conflicts = child.after_fork();
+                    let ok = conflicts.is_empty()
+                        && child.is_current(tag)
+                        && child.modules()[0].load_time() == Some(load_time)
+                        && child.modules().iter().any(|m| m.source() == Source::MemoryScan);
+                    unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+                }
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                    assert!(libc::WIFEXITED(status));
+                    assert_eq!(libc::WEXITSTATUS(status), 0);
+                }
+            }
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        contender.join().unwrap();
+    }
+
+    #[test]
+    fn merge_dedups_by_address() {
+        let mut map = ProcessMap::from(vec![
+            ModuleInfo::fake("", &[(0x1000, 0x1000)], Source::LoaderList),
+            ModuleInfo::fake("/lib/libfoo.so", &[(0x3000, 0x1000)], Source::LoaderList),
+        ]);
+        let conflicts = map.merge(vec![
+            ModuleInfo::fake("/usr/bin/main", &[(0x1800, 0x1000)], Source::MapsParse),
+            ModuleInfo::fake("/usr/lib/libfoo.so", &[(0x3000, 0x1000)], Source::MapsParse),
+            ModuleInfo::fake("/tmp/injected.so", &[(0x5000, 0x1000)], Source::MemoryScan),
+        ]);
+        assert!(conflicts.is_empty());
+
+        let modules = map.modules();
+        assert_eq!(modules.len(), 3);
+        assert_eq!(modules[0].name().to_bytes(), b"/usr/bin/main");
+        assert_eq!(modules[0].source(), Source::LoaderList);
+        assert_eq!(modules[1].name().to_bytes(), b"/lib/libfoo.so");
+        assert_eq!(modules[2].source(), Source::MemoryScan);
+    }
+
+    #[test]
+    fn merge_reports_conflicts() {
+        let mut map = ProcessMap::from(vec![ModuleInfo::fake(
+            "/lib/libfoo.so",
+            &[(0x1000, 0x1000)],
+            Source::MapsParse,
+        )]);
+        let conflicts = map.merge(vec![ModuleInfo::fake(
+            "/lib/libbar.so",
+            &[(0x1000, 0x1000)],
+            Source::LoaderList,
+        )]);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].reason, ConflictReason::DifferentName);
+        assert_eq!(conflicts[0].kept.source(), Source::LoaderList);
+        assert_eq!(conflicts[0].discarded.name().to_bytes(), b"/lib/libfoo.so");
+        assert_eq!(map.modules().len(), 1);
+        assert_eq!(map.modules()[0].name().to_bytes(), b"/lib/libbar.so");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn running_code_is_resident() {
+        let main = TargetSharedLibrary::main_executable().unwrap();
+        let residency = main.residency().unwrap();
+        assert!(residency.resident_bytes > 0);
+        assert!(residency.resident_bytes <= residency.total_bytes);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn only_the_main_executable_is_marked() {
+        let modules = ProcessMap::new();
+        let main: Vec<_> = modules
+            .modules()
+            .iter()
+            .map(ModuleInfo::is_main_executable)
+            .collect();
+        if let Some((&first, rest)) = main.split_first() {
+            assert!(first);
+            assert!(rest.iter().all(|&main| !main));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn huge_page_usage_of_main_executable() {
+        let main = TargetSharedLibrary::main_executable().unwrap();
+        let usage = main.huge_page_usage().unwrap();
+        assert!(usage.total_bytes > 0);
+        assert!(usage.huge_bytes <= usage.total_bytes);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn running_code_has_memory_usage() {
+        let main = TargetSharedLibrary::main_executable().unwrap();
+        let mut text = 0;
+        for seg in main.segments() {
+            let usage = main.segment_memory_usage(seg).unwrap();
+            if seg.permissions().execute {
+                text += usage.resident();
+            }
+        }
+        assert!(text > 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn module_memory_usage_counts_each_region_once() {
+        let main = TargetSharedLibrary::main_executable().unwrap();
+        let total = main.memory_usage().unwrap();
+        assert!(total.resident() > 0);
+
+        // Several segments, such as `PT_PHDR` and the first `PT_LOAD`, share
+        // a region, so summing them separately counts it more than once.
+        let summed: usize = main
+            .segments()
+            .iter()
+            .map(|seg| main.segment_memory_usage(seg).unwrap().resident())
+            .sum();
+        assert!(total.resident() <= summed);
+    }
+
+    #[test]
+    fn default_options_copy_everything() {
+        let mut first = None;
+        each_with_options(&EnumerateOptions::new(), |module| {
+            first = Some(module);
+            IterationControl::Break
+        });
+        let first = match first {
+            Some(first) => first,
+            None => return,
+        };
+
+        TargetSharedLibrary::each(|shlib| {
+            assert_eq!(first.name(), shlib.name());
+            assert_eq!(first.segments().len(), shlib.segments().len());
+            assert_eq!(first.id(), shlib.id().as_ref());
+            IterationControl::Break
+        });
+    }
+}
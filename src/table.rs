@@ -0,0 +1,392 @@
+//! Small, stable indices for referring to modules in traces.
+//!
+//! Trace and profile formats usually record many addresses per module, and
+//! repeating a module's path and debug-id with each of them wastes space. A
+//! [`ModuleTable`](./struct.ModuleTable.html) hands out a small integer index
+//! for each distinct module instead, and can be encoded once into the trace so
+//! that a reader can decode it and map the indices back to modules.
+//!
+//! ```
+//! use findshlibs::snapshot::ProcessMap;
+//! use findshlibs::table::ModuleTable;
+//!
+//! let mut table = ModuleTable::new();
+//! let indices: Vec<u32> = ProcessMap::new()
+//!     .modules()
+//!     .iter()
+//!     .map(|module| table.insert(module.clone()))
+//!     .collect();
+//!
+//! let decoded = ModuleTable::decode(&table.encode()).unwrap();
+//! for index in indices {
+//!     assert_eq!(decoded.get(index), table.get(index));
+//! }
+//! ```
+//!
+//! ## Encoding
+//!
+//! The encoding is meant to be embedded in other formats, so it is as plain
+//! as possible: the magic bytes `FSMT`, a version byte (currently 1), and the
+//! number of modules, followed by each module in index order. Integers are
+//! unsigned LEB128 unless noted otherwise, and byte strings are prefixed with
+//! their length.
+//!
+//! * The module's name, as a byte string.
+//! * Its debug-id: a `0` byte for none, `1` followed by 16 bytes for a UUID,
+//!   or `2` followed by a byte string for a GNU build ID.
+//! * Its bias, as a little-endian 8-byte two's complement integer.
+//! * Its source: 0 for the loader list, 1 for the memory map, 2 for a memory
+//!   scan, 3 for a core file and 4 for a minidump.
+//! * The number of segments, followed by each segment's name as a byte
+//!   string, stated virtual memory address, length, file offset and file
+//!   size, and a byte of permission bits: 1 for read, 2 for write and 4 for
+//!   execute.
+
+use super::snapshot::{ModuleInfo, ProcessMap, SegmentInfo, Source};
+use super::{Bias, SegmentPermissions, SharedLibraryId, Svma};
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+
+const MAGIC: &[u8] = b"FSMT";
+const VERSION: u8 = 1;
+
+/// A table assigning each distinct module a small integer index.
+///
+/// Indices count up from zero in insertion order and never change, so a
+/// table can be kept across snapshots, and modules that stay loaded keep
+/// their index. Modules are distinct if they aren't equal, i.e. if they have
+/// different debug-ids or biases.
+///
+/// Only the module's name, debug-id, bias, source and segments survive
+/// encoding.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleTable {
+    modules: Vec<ModuleInfo>,
+    indices: HashMap<ModuleInfo, u32>,
+}
+
+impl ModuleTable {
+    /// Create an empty table.
+    #[inline]
+    pub fn new() -> Self {
+        ModuleTable::default()
+    }
+
+    /// Get the index of the given module, adding it to the table if it isn't
+    /// in it yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the table already has `u32::MAX` modules.
+    pub fn insert(&mut self, module: ModuleInfo) -> u32 {
+        if let Some(&index) = self.indices.get(&module) {
+            return index;
+        }
+        assert!(self.modules.len() < u32::MAX as usize, "too many modules");
+        let index = self.modules.len() as u32;
+        self.indices.insert(module.clone(), index);
+        self.modules.push(module);
+        index
+    }
+
+    /// Get the index of the given module, if it is in the table.
+    #[inline]
+    pub fn index_of(&self, module: &ModuleInfo) -> Option<u32> {
+        self.indices.get(module).cloned()
+    }
+
+    /// Get the module with the given index.
+    #[inline]
+    pub fn get(&self, index: u32) -> Option<&ModuleInfo> {
+        self.modules.get(index as usize)
+    }
+
+    /// Get the modules in this table, in index order.
+    #[inline]
+    pub fn modules(&self) -> &[ModuleInfo] {
+        &self.modules
+    }
+
+    /// Get the number of modules in this table.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// Is this table empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    /// Encode this table in the format described in the [module
+    /// documentation](./index.html#encoding).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_uleb128(&mut out, self.modules.len() as u64);
+        for module in &self.modules {
+            write_bytes(&mut out, module.name().to_bytes());
+            match module.id() {
+                None => out.push(0),
+                Some(SharedLibraryId::Uuid(uuid)) => {
+                    out.push(1);
+                    out.extend_from_slice(uuid);
+                }
+                Some(SharedLibraryId::GnuBuildId(id)) => {
+                    out.push(2);
+                    write_bytes(&mut out, id);
+                }
+            }
+            out.extend_from_slice(&(module.virtual_memory_bias().0 as i64).to_le_bytes());
+            out.push(match module.source() {
+                Source::LoaderList => 0,
+                Source::MapsParse => 1,
+                Source::MemoryScan => 2,
+                Source::CoreFile => 3,
+                Source::Minidump => 4,
+            });
+
+            write_uleb128(&mut out, module.segments().len() as u64);
+            for seg in module.segments() {
+                write_bytes(&mut out, seg.name().to_bytes());
+                write_uleb128(&mut out, seg.stated_virtual_memory_address().0 as usize as u64);
+                write_uleb128(&mut out, seg.len() as u64);
+                write_uleb128(&mut out, seg.file_offset());
+                write_uleb128(&mut out, seg.file_size());
+                let permissions = seg.permissions();
+                out.push(permissions.read as u8
+                    | (permissions.write as u8) << 1
+                    | (permissions.execute as u8) << 2);
+            }
+        }
+        out
+    }
+
+    /// Decode a table encoded with `encode`.
+    ///
+    /// Returns an `InvalidData` error if `bytes` isn't a complete encoding
+    /// of a table in a version of the format this crate understands.
+    pub fn decode(bytes: &[u8]) -> io::Result<ModuleTable> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC || reader.u8()? != VERSION {
+            return Err(invalid("not an encoded module table"));
+        }
+
+        let mut table = ModuleTable::new();
+        for _ in 0..reader.uleb128()? {
+            let name = reader.cstring()?;
+            let id = match reader.u8()? {
+                0 => None,
+                1 => {
+                    let mut uuid = [0; 16];
+                    uuid.copy_from_slice(reader.take(16)?);
+                    Some(SharedLibraryId::Uuid(uuid))
+                }
+                2 => Some(SharedLibraryId::GnuBuildId(reader.byte_string()?.to_vec())),
+                _ => return Err(invalid("unknown debug-id kind")),
+            };
+            let mut bias = [0; 8];
+            bias.copy_from_slice(reader.take(8)?);
+            let bias = Bias(i64::from_le_bytes(bias) as isize);
+            let source = match reader.u8()? {
+                0 => Source::LoaderList,
+                1 => Source::MapsParse,
+                2 => Source::MemoryScan,
+                3 => Source::CoreFile,
+                4 => Source::Minidump,
+                _ => return Err(invalid("unknown module source")),
+            };
+
+            let mut segments = Vec::new();
+            for _ in 0..reader.uleb128()? {
+                let name = reader.cstring()?;
+                let svma = Svma(reader.uleb128()? as usize as *const u8);
+                let len = reader.uleb128()? as usize;
+                let (file_offset, file_size) = (reader.uleb128()?, reader.uleb128()?);
+                let bits = reader.u8()?;
+                let permissions = SegmentPermissions {
+                    read: bits & 1 != 0,
+                    write: bits & 2 != 0,
+                    execute: bits & 4 != 0,
+                };
+                segments.push(SegmentInfo::new(name, svma, len, file_offset, file_size, permissions));
+            }
+
+            let module = ModuleInfo::from_parts(name, segments, source).with_identity(id, bias);
+            if table.index_of(&module).is_some() {
+                return Err(invalid("duplicate module"));
+            }
+            table.insert(module);
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(invalid("trailing bytes after module table"));
+        }
+        Ok(table)
+    }
+}
+
+impl<'a> From<&'a ProcessMap> for ModuleTable {
+    /// Build a table of the modules in a snapshot, indexed in snapshot order
+    /// with duplicates removed.
+    fn from(map: &'a ProcessMap) -> Self {
+        let mut table = ModuleTable::new();
+        for module in map.modules() {
+            table.insert(module.clone());
+        }
+        table
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_uleb128(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(invalid("truncated module table"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn uleb128(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("integer too large"))
+    }
+
+    fn byte_string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.uleb128()?;
+        if len > self.bytes.len() as u64 {
+            return Err(invalid("truncated module table"));
+        }
+        self.take(len as usize)
+    }
+
+    fn cstring(&mut self) -> io::Result<CString> {
+        CString::new(self.byte_string()?).map_err(|_| invalid("name contains a NUL byte"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_module(name: &str, id: Option<SharedLibraryId>, bias: isize) -> ModuleInfo {
+        ModuleInfo::fake(name, &[(0x1000, 0x2000), (0x4000, 0x100)], Source::MemoryScan)
+            .with_identity(id, Bias(bias))
+    }
+
+    #[test]
+    fn indices_are_stable() {
+        let a = fake_module("/lib/liba.so", Some(SharedLibraryId::GnuBuildId(vec![1, 2, 3])), 0);
+        let b = fake_module("/lib/libb.so", None, 0x7000_0000);
+
+        let mut table = ModuleTable::new();
+        assert_eq!(table.insert(a.clone()), 0);
+        assert_eq!(table.insert(b.clone()), 1);
+        assert_eq!(table.insert(a.clone()), 0);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.index_of(&b), Some(1));
+        assert_eq!(table.get(1), Some(&b));
+        assert_eq!(table.get(2), None);
+
+        // The same file loaded somewhere else is a different module.
+        let moved = fake_module("/lib/liba.so", a.id().cloned(), 0x1000);
+        assert_eq!(table.insert(moved), 2);
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let mut table = ModuleTable::new();
+        table.insert(fake_module("", Some(SharedLibraryId::Uuid([7; 16])), -0x1000));
+        table.insert(fake_module("/lib/libb.so", None, 0x7fff_0000));
+        table.insert(fake_module("/lib/libc.so", Some(SharedLibraryId::GnuBuildId(vec![9; 20])), 0));
+
+        let decoded = ModuleTable::decode(&table.encode()).unwrap();
+        assert_eq!(decoded.len(), table.len());
+        for (decoded, original) in decoded.modules().iter().zip(table.modules()) {
+            assert_eq!(decoded, original);
+            assert_eq!(decoded.name(), original.name());
+            assert_eq!(decoded.source(), original.source());
+            assert_eq!(decoded.segments(), original.segments());
+        }
+
+        // The modules of this process have segments of every kind.
+        let table = ModuleTable::from(&ProcessMap::new());
+        let decoded = ModuleTable::decode(&table.encode()).unwrap();
+        for (decoded, original) in decoded.modules().iter().zip(table.modules()) {
+            assert_eq!(decoded.segments(), original.segments());
+        }
+
+        assert_eq!(ModuleTable::decode(&ModuleTable::new().encode()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn bad_encodings_are_rejected() {
+        let mut table = ModuleTable::new();
+        table.insert(fake_module("/lib/liba.so", None, 0));
+        let encoded = table.encode();
+
+        for len in 0..encoded.len() {
+            let err = ModuleTable::decode(&encoded[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(ModuleTable::decode(&trailing).is_err());
+
+        let mut future = encoded;
+        future[MAGIC.len()] = VERSION + 1;
+        assert!(ModuleTable::decode(&future).is_err());
+    }
+
+    #[test]
+    fn table_of_this_process() {
+        let map = ProcessMap::new();
+        let table = ModuleTable::from(&map);
+        assert!(table.len() <= map.modules().len());
+        for module in map.modules() {
+            let index = table.index_of(module).unwrap();
+            assert_eq!(table.get(index), Some(module));
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Optional instrumentation of enumeration with `tracing`.
+//!
+//! With the `tracing` feature enabled, these functions emit spans and events
+//! under the `findshlibs` target: a span around each enumeration, an event for
+//! each shared library visited, and events for shared libraries skipped
+//! because they couldn't be parsed and for failed OS calls. Without it, they
+//! do nothing and compile away.
+//!
+//! Subscribers are free to allocate and take locks, so none of this is used
+//! from `SharedLibrary::each_unsafe_signal_safe`.
+
+use super::SharedLibrary;
+
+use std::ffi::CStr;
+use std::io;
+
+#[cfg(feature = "tracing")]
+const TARGET: &str = "findshlibs";
+
+/// Entered when an enumeration starts, and exited when it is dropped.
+#[cfg(feature = "tracing")]
+pub type Span = ::tracing::span::EnteredSpan;
+
+/// Entered when an enumeration starts, and exited when it is dropped.
+#[cfg(not(feature = "tracing"))]
+pub struct Span;
+
+/// Enter a span covering one enumeration of the loaded shared libraries.
+#[cfg(feature = "tracing")]
+#[inline]
+pub fn enumeration(kind: &'static str) -> Span {
+    ::tracing::debug_span!(target: TARGET, "enumerate", kind).entered()
+}
+
+/// Enter a span covering one enumeration of the loaded shared libraries.
+#[cfg(not(feature = "tracing"))]
+#[inline]
+pub fn enumeration(_kind: &'static str) -> Span {
+    Span
+}
+
+/// Record that enumeration handed out the given shared library.
+#[inline]
+pub fn visited<S: SharedLibrary>(shlib: &S) {
+    #[cfg(feature = "tracing")]
+    ::tracing::trace!(
+        target: TARGET,
+        name = %shlib.name().to_string_lossy(),
+        bias = shlib.virtual_memory_bias().0,
+        segments = shlib.segments().count(),
+        "visited shared library"
+    );
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = shlib;
+}
+
+/// Record that some shared library wasn't handed out, and why.
+#[inline]
+pub fn skipped(name: &CStr, reason: &'static str) {
+    #[cfg(feature = "tracing")]
+    ::tracing::debug!(
+        target: TARGET,
+        name = %name.to_string_lossy(),
+        reason,
+        "skipped shared library"
+    );
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = (name, reason);
+}
+
+/// Record that part of the given shared library couldn't be parsed, so the
+/// answer to some query about it is incomplete.
+#[inline]
+pub fn unparsable(name: &CStr, what: &'static str) {
+    #[cfg(feature = "tracing")]
+    ::tracing::debug!(
+        target: TARGET,
+        name = %name.to_string_lossy(),
+        what,
+        "failed to parse shared library"
+    );
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = (name, what);
+}
+
+/// Record that an OS call failed.
+#[inline]
+pub fn os_error(call: &'static str, err: &io::Error) {
+    #[cfg(feature = "tracing")]
+    ::tracing::warn!(
+        target: TARGET,
+        call,
+        code = err.raw_os_error(),
+        kind = ?err.kind(),
+        error = %err,
+        "OS call failed"
+    );
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = (call, err);
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::super::TargetSharedLibrary;
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Counts the events and spans with our target.
+    #[derive(Default)]
+    struct Counter {
+        events: Arc<AtomicUsize>,
+        spans: Arc<AtomicUsize>,
+    }
+
+    impl Subscriber for Counter {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == TARGET
+        }
+
+        fn new_span(&self, _span: &Attributes) -> Id {
+            Id::from_u64(self.spans.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event) {
+            self.events.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn enumeration_is_traced() {
+        let counter = Counter::default();
+        let (events, spans) = (counter.events.clone(), counter.spans.clone());
+
+        let mut visited = 0;
+        ::tracing::subscriber::with_default(counter, || {
+            TargetSharedLibrary::each(|_| visited += 1);
+        });
+
+        assert_eq!(spans.load(Ordering::SeqCst), ::TARGET_SUPPORTED as usize);
+        assert!(events.load(Ordering::SeqCst) >= visited);
+    }
+
+    #[test]
+    fn os_errors_are_traced() {
+        let counter = Counter::default();
+        let events = counter.events.clone();
+        ::tracing::subscriber::with_default(counter, || {
+            os_error("open", &io::Error::from_raw_os_error(2));
+        });
+        assert_eq!(events.load(Ordering::SeqCst), 1);
+    }
+}
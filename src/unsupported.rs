@@ -3,11 +3,14 @@
 
 use super::Segment as SegmentTrait;
 use super::SharedLibrary as SharedLibraryTrait;
-use super::{Bias, IterationControl, SharedLibraryId, Svma};
+use super::{
+    Avma, Bias, DebugInfoAvailability, IterationControl, LockHazards, RelocationCounts,
+    SectionInfo, SegmentPermissions, SharedLibraryId, SharedLibraryVersion, Svma, Symbol, VolumeKind,
+};
 
 use std::ffi::CStr;
 use std::marker::PhantomData;
-use std::usize;
+use std::path::PathBuf;
 
 /// An unsupported segment
 #[derive(Debug)]
@@ -32,6 +35,21 @@ impl<'a> SegmentTrait for Segment<'a> {
     fn len(&self) -> usize {
         unreachable!()
     }
+
+    #[inline]
+    fn file_size(&self) -> u64 {
+        unreachable!()
+    }
+
+    #[inline]
+    fn file_offset(&self) -> u64 {
+        unreachable!()
+    }
+
+    #[inline]
+    fn permissions(&self) -> SegmentPermissions {
+        unreachable!()
+    }
 }
 
 /// An iterator over Mach-O segments.
@@ -46,11 +64,24 @@ impl<'a> Iterator for SegmentIter<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         None
     }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(0))
+    }
+}
+
+impl<'a> DoubleEndedIterator for SegmentIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        None
+    }
 }
 
+impl<'a> ExactSizeIterator for SegmentIter<'a> {}
+
 /// The fallback implementation of the [SharedLibrary
 /// trait](../trait.SharedLibrary.html).
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SharedLibrary<'a> {
     phantom: PhantomData<&'a SharedLibrary<'a>>,
 }
@@ -68,21 +99,100 @@ impl<'a> SharedLibraryTrait for SharedLibrary<'a> {
         unreachable!()
     }
 
+    fn version(&self) -> Option<SharedLibraryVersion> {
+        unreachable!()
+    }
+
+    fn debug_info_availability(&self) -> DebugInfoAvailability {
+        unreachable!()
+    }
+
+    fn compilation_directory(&self) -> Option<PathBuf> {
+        unreachable!()
+    }
+
+    fn volume_kind(&self) -> Option<VolumeKind> {
+        unreachable!()
+    }
+
+    fn section_map(&self) -> Option<Vec<Vec<SectionInfo>>> {
+        unreachable!()
+    }
+
+    fn is_main_executable(&self) -> bool {
+        unreachable!()
+    }
+
     fn segments(&self) -> Self::SegmentIter {
         SegmentIter {
             phantom: PhantomData,
         }
     }
 
+    fn virtual_memory_size(&self) -> usize {
+        unreachable!()
+    }
+
     #[inline]
     fn virtual_memory_bias(&self) -> Bias {
         unreachable!()
     }
 
+    #[inline]
+    fn load_slide(&self) -> isize {
+        unreachable!()
+    }
+
+    fn header_bytes(&self) -> Option<&[u8]> {
+        unreachable!()
+    }
+
+    fn relocation_counts(&self) -> Option<RelocationCounts> {
+        unreachable!()
+    }
+
+    fn init_routines(&self) -> Vec<Avma> {
+        unreachable!()
+    }
+
+    fn fini_routines(&self) -> Vec<Avma> {
+        unreachable!()
+    }
+
+    fn function_starts(&self) -> Vec<Avma> {
+        unreachable!()
+    }
+
+    fn symbol_at(&self, _address: Avma) -> Option<Symbol<'_>> {
+        unreachable!()
+    }
+
+    #[inline]
+    fn loaded_modules_generation() -> u64 {
+        0
+    }
+
+    #[inline]
+    fn lock_hazards() -> LockHazards {
+        LockHazards::default()
+    }
+
+    #[inline]
+    fn signal_safe_lock_hazards() -> LockHazards {
+        LockHazards::default()
+    }
+
     fn each<F, C>(_f: F)
     where
         F: FnMut(&Self) -> C,
         C: Into<IterationControl>,
     {
     }
+
+    unsafe fn each_unsafe_signal_safe<F, C>(_f: F)
+    where
+        F: FnMut(&Self) -> C,
+        C: Into<IterationControl>,
+    {
+    }
 }
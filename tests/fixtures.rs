@@ -0,0 +1,126 @@
+//! Check every accessor against shared libraries whose properties are known,
+//! from the `findshlibs-fixtures` crate.
+
+#![cfg(any(target_os = "linux", target_os = "macos"))]
+
+extern crate findshlibs;
+extern crate findshlibs_fixtures as fixtures;
+
+use findshlibs::filter::Filter;
+use findshlibs::lookup::ModuleMap;
+use findshlibs::snapshot::ProcessMap;
+use findshlibs::{Avma, DebugInfoAvailability, IterationControl, Segment, SharedLibrary,
+                 SharedLibraryId, SharedLibraryVersion, TargetSharedLibrary};
+
+use fixtures::Fixture;
+use std::path::Path;
+
+/// Load `fixture`, check everything about it, and check that it is gone
+/// once it is unloaded.
+///
+/// Each fixture is only loaded by one test, so that unloading it really
+/// unloads it.
+fn check(fixture: &Fixture) {
+    if !fixtures::BUILT {
+        return;
+    }
+    let loaded = fixture.load();
+    let function = Avma(loaded.exported_function());
+
+    let mut found = 0;
+    TargetSharedLibrary::each(|shlib| {
+        if shlib.name().to_bytes() != fixture.path.as_bytes() {
+            return IterationControl::Continue;
+        }
+        found += 1;
+        check_shared_library(fixture, shlib, function);
+        IterationControl::Break
+    });
+    assert_eq!(found, 1, "{} should be loaded once", fixture.path);
+
+    // These look the shared library up with `dlopen` or `dladdr`, or
+    // enumerate on their own, so they can't be called from inside `each`.
+    let map = ModuleMap::new();
+    let module = map.lookup(function).expect("should find the exported function");
+    assert_eq!(module.name().to_bytes(), fixture.path.as_bytes());
+
+    let symbol = map.symbol_at(function).expect("should find the exported function");
+    assert_eq!(symbol.name().to_str(), Ok(fixtures::EXPORTED_FUNCTION));
+    assert_eq!(symbol.address(), function);
+    assert_eq!(symbol.offset(), 0);
+    assert_eq!(module.symbol_at(function), Some(symbol));
+
+    let file_name = fixture.path().file_name().unwrap().to_str().unwrap();
+    let mut matched = Vec::new();
+    TargetSharedLibrary::each_matching(&Filter::new().name_glob(file_name), |shlib| {
+        matched.push(shlib.name().to_owned());
+    });
+    assert_eq!(matched.len(), 1);
+
+    drop(loaded);
+    let snapshot = ProcessMap::new();
+    assert!(snapshot
+        .modules()
+        .iter()
+        .all(|module| module.name().to_bytes() != fixture.path.as_bytes()));
+}
+
+fn check_shared_library(fixture: &Fixture, shlib: &TargetSharedLibrary, function: Avma) {
+    #[cfg(target_os = "linux")]
+    assert_eq!(shlib.id(), Some(SharedLibraryId::GnuBuildId(fixture.build_id.to_vec())));
+    #[cfg(target_os = "macos")]
+    assert!(matches!(shlib.id(), Some(SharedLibraryId::Uuid(_))));
+
+    if let Some((major, minor, patch)) = fixture.version {
+        let expected = SharedLibraryVersion { major, minor, patch };
+        assert_eq!(shlib.version(), Some(expected));
+    }
+
+    let bias = shlib.virtual_memory_bias();
+    assert!(shlib.segments().any(|seg| seg.contains_avma(shlib, function)));
+    assert!(shlib
+        .segments()
+        .filter(|seg| seg.permissions().execute)
+        .any(|seg| seg.avma_range(bias).contains(&(function.0 as usize))));
+    let svma = shlib.avma_to_svma(function);
+    assert!(shlib.segments().any(|seg| seg.contains_svma(svma)));
+
+    assert_ne!(shlib.debug_info_availability(), DebugInfoAvailability::None);
+    if cfg!(target_os = "linux") {
+        assert_eq!(shlib.debug_info_availability(), DebugInfoAvailability::Embedded);
+        assert_eq!(shlib.compilation_directory().as_deref(),
+                   Some(Path::new(fixture.compilation_directory)));
+    }
+    assert!(shlib.volume_kind().is_some());
+    assert!(!shlib.is_main_executable());
+    assert!(shlib.function_starts().contains(&function) || cfg!(target_os = "macos"));
+    assert!(!shlib.has_wx_segments());
+
+    let sections = shlib.section_map().expect("should read the sections");
+    assert_eq!(sections.len(), shlib.segments().len());
+    let names: Vec<&[u8]> = sections.iter().flatten().map(|section| &section.name[..]).collect();
+    assert!(names.contains(&&b".text"[..]) || names.contains(&&b"__text"[..]));
+    if let Some(odd) = fixture.odd_section {
+        assert!(names.contains(&odd.as_bytes()), "{} should have {}", fixture.path, odd);
+    }
+}
+
+#[test]
+fn basic() {
+    check(&fixtures::BASIC);
+}
+
+#[test]
+fn odd_sections() {
+    check(&fixtures::ODD_SECTIONS);
+}
+
+#[test]
+fn long_path() {
+    check(&fixtures::LONG_PATH);
+}
+
+#[test]
+fn non_ascii() {
+    check(&fixtures::NON_ASCII);
+}